@@ -0,0 +1,150 @@
+// Plays the live playlist through Media Source Extensions for browsers without native HLS, such as
+// Chrome and Firefox. It only handles what the server's hls muxer writes: a single media playlist
+// of fMP4 segments sharing one EXT-X-MAP init segment.
+
+// how many of the newest segments playback starts from, fewer means less delay but more stalls
+const LIVE_SYNC_SEGMENTS = 2;
+// seconds of already played video kept in the buffer
+const BACK_BUFFER_SECS = 30;
+
+export function parsePlaylist(text, playlistUrl) {
+    let mediaSequence = 0;
+    let targetDuration = 1;
+    let init = null;
+    const segments = [];
+    for (const rawLine of text.split("\n")) {
+        const line = rawLine.trim();
+        if (line.startsWith("#EXT-X-MEDIA-SEQUENCE:")) {
+            mediaSequence = parseInt(line.slice("#EXT-X-MEDIA-SEQUENCE:".length), 10);
+        } else if (line.startsWith("#EXT-X-TARGETDURATION:")) {
+            targetDuration = parseFloat(line.slice("#EXT-X-TARGETDURATION:".length));
+        } else if (line.startsWith("#EXT-X-MAP:")) {
+            const uri = line.match(/URI="([^"]+)"/);
+            if (uri) {
+                init = new URL(uri[1], playlistUrl).href;
+            }
+        } else if (line && !line.startsWith("#")) {
+            segments.push({
+                sequence: mediaSequence + segments.length,
+                url: new URL(line, playlistUrl).href,
+            });
+        }
+    }
+    return { targetDuration, init, segments };
+}
+
+function indexOfBox(bytes, type) {
+    const codes = [...type].map(char => char.charCodeAt(0));
+    for (let i = 0; i + codes.length <= bytes.length; i++) {
+        if (codes.every((code, offset) => bytes[i + offset] === code)) {
+            return i;
+        }
+    }
+    return -1;
+}
+
+function hexByte(byte) {
+    return byte.toString(16).padStart(2, "0").toUpperCase();
+}
+
+// MediaSource needs the codecs up front, the playlist doesn't list them so they are read from the
+// H264 decoder configuration and audio sample entry in the init segment
+export function mimeFromInitSegment(bytes) {
+    const avcC = indexOfBox(bytes, "avcC");
+    if (avcC < 0 || avcC + 8 > bytes.length) {
+        throw new Error("Live init segment has no H264 configuration");
+    }
+    // the box type is followed by the configuration version, profile, compatibility and level
+    const codecs = [`avc1.${hexByte(bytes[avcC + 5])}${hexByte(bytes[avcC + 6])}${hexByte(bytes[avcC + 7])}`];
+    if (indexOfBox(bytes, "Opus") >= 0) {
+        codecs.push("opus");
+    } else if (indexOfBox(bytes, "mp4a") >= 0) {
+        codecs.push("mp4a.40.2");
+    }
+    return `video/mp4; codecs="${codecs.join(", ")}"`;
+}
+
+async function fetchOk(url) {
+    const response = await fetch(url, { cache: "no-store" });
+    if (!response.ok) {
+        throw new Error(`${url} returned ${response.status}`);
+    }
+    return response;
+}
+
+function whenUpdated(sourceBuffer, update) {
+    return new Promise((resolve, reject) => {
+        const done = (event) => {
+            sourceBuffer.removeEventListener("updateend", done);
+            sourceBuffer.removeEventListener("error", done);
+            if (event.type === "error") {
+                reject(new Error("Appending live video failed"));
+            } else {
+                resolve();
+            }
+        };
+        sourceBuffer.addEventListener("updateend", done);
+        sourceBuffer.addEventListener("error", done);
+        update();
+    });
+}
+
+function sleep(ms) {
+    return new Promise(resolve => setTimeout(resolve, ms));
+}
+
+// Follows the playlist until the video's source is replaced, segments that roll off the playlist
+// before they are fetched are skipped and playback jumps ahead to the next one.
+export async function playLiveHls(video, playlistUrl) {
+    const mediaSource = new MediaSource();
+    video.src = URL.createObjectURL(mediaSource);
+    await new Promise(resolve => mediaSource.addEventListener("sourceopen", resolve, { once: true }));
+
+    let sourceBuffer = null;
+    let nextSequence = null;
+    while (mediaSource.readyState === "open") {
+        let playlist;
+        try {
+            playlist = parsePlaylist(await (await fetchOk(playlistUrl)).text(), playlistUrl);
+        } catch (error) {
+            // the playlist only appears once the first segment is written
+            console.log("Waiting for live playlist:", error);
+            await sleep(1000);
+            continue;
+        }
+        if (!sourceBuffer && playlist.init && playlist.segments.length > 0) {
+            const init = new Uint8Array(await (await fetchOk(playlist.init)).arrayBuffer());
+            sourceBuffer = mediaSource.addSourceBuffer(mimeFromInitSegment(init));
+            await whenUpdated(sourceBuffer, () => sourceBuffer.appendBuffer(init));
+            nextSequence = playlist.segments[Math.max(0, playlist.segments.length - LIVE_SYNC_SEGMENTS)].sequence;
+        }
+        for (const segment of playlist.segments) {
+            if (!sourceBuffer || segment.sequence < nextSequence) {
+                continue;
+            }
+            let data;
+            try {
+                data = await (await fetchOk(segment.url)).arrayBuffer();
+            } catch (error) {
+                console.log("Skipping live segment:", error);
+                continue;
+            }
+            const skipped = segment.sequence > nextSequence;
+            nextSequence = segment.sequence + 1;
+            if (mediaSource.readyState !== "open") {
+                return;
+            }
+            const played = video.currentTime - BACK_BUFFER_SECS;
+            if (video.buffered.length > 0 && video.buffered.start(0) < played) {
+                await whenUpdated(sourceBuffer, () => sourceBuffer.remove(0, played));
+            }
+            await whenUpdated(sourceBuffer, () => sourceBuffer.appendBuffer(data));
+            const buffered = video.buffered;
+            if (buffered.length > 0 && (skipped || video.currentTime < buffered.start(0))) {
+                // the first segment or one after a gap starts later than playback, so jump to it
+                video.currentTime = buffered.start(buffered.length - 1);
+            }
+        }
+        await sleep(playlist.targetDuration * 500);
+    }
+}
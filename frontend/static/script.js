@@ -10,6 +10,13 @@ export function generateLinks() {
         .then(response => response.json())
         .then(data => {
             console.info(data);
+            if (data.length > 0) {
+                const archive_link = document.createElement("a");
+                const file_names = data.map(item => item.file_name).join(",");
+                archive_link.href = `${SERVER_ADDR}/archive?files=${file_names}`;
+                archive_link.text = "Download all videos from this day";
+                linksContainer.appendChild(archive_link);
+            }
             data.forEach(item => {
                 console.info("Item to make link:", item);
                 const details_section = document.createElement("details");
@@ -18,15 +25,28 @@ export function generateLinks() {
                 let file_name = item.file_name;
                 const summary = document.createElement("summary");
                 summary.textContent = `Video datetime: ${created}, Video length: ${duration}`;
+                const preview = document.createElement("img");
+                preview.src = `${SERVER_ADDR}${item.preview_url}`;
+                preview.loading = "lazy";
+                preview.alt = `Preview of ${file_name}`;
+                summary.prepend(preview);
                 details_section.appendChild(summary);
                 details_section.style.display = "block";
                 const video_section = document.createElement("video");
                 video_section.controls = true;
+                video_section.preload = "none";
+                video_section.poster = `${SERVER_ADDR}${item.poster_url}`;
                 const source = document.createElement("source");
                 source.src = `${SERVER_ADDR}/file?filename=${file_name}`;
                 source.type = "video/mp4";
                 video_section.appendChild(source);
                 details_section.appendChild(video_section);
+                const delete_button = document.createElement("button");
+                delete_button.type = "button";
+                delete_button.textContent = "Delete";
+                delete_button.disabled = item.protected;
+                delete_button.addEventListener("click", () => delete_video(file_name, details_section));
+                details_section.appendChild(delete_button);
                 linksContainer.appendChild(details_section);
             });
         }
@@ -37,6 +57,17 @@ export function generateLinks() {
 }
 
 
+export async function delete_video(file_name, details_section) {
+    const endpoint = `${SERVER_ADDR}/file?filename=${file_name}`;
+    const response = await fetch(endpoint, { method: "DELETE" });
+    if (response.ok) {
+        details_section.remove();
+    } else {
+        console.error("Error occured trying to delete video, status:", response.status);
+    }
+}
+
+
 export function get_cam_status() {
     const endpoint = `${SERVER_ADDR}/cam_status`;
     const status_text = document.getElementById("current_status");
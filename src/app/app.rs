@@ -1,6 +1,16 @@
-use crate::app::{middleware, routes, task::ThreadPool, web_routes};
+use crate::app::{
+    jobs::{job_expiry_task, JobManager},
+    middleware, routes,
+    state::AppState,
+    web_routes,
+};
 use crate::camera::webrtc::ws_handler;
+use crate::media::{
+    hls::HlsCache, proxy::ProxyCache, sprites::SpriteCache, summary::summary_task,
+    thumbnails::ThumbnailCache,
+};
 use crate::motion_detect::gpio::MotionDetector;
+use crate::storage::{backup::Backup, catalog::Catalog, store::RecordingStore};
 use axum::{
     handler::HandlerWithoutStateExt,
     http::{uri::Authority, StatusCode, Uri},
@@ -13,20 +23,60 @@ use axum_extra::extract::Host;
 use std::{net::SocketAddr, sync::Arc};
 use tower_http::{services::ServeDir};
 
-pub async fn create_app(motion_detector: MotionDetector) -> Router {
-    let thread_pool = ThreadPool::new(20).await;
+pub async fn create_app(
+    motion_detector: Arc<MotionDetector>,
+    catalog: Arc<Catalog>,
+    store: Arc<RecordingStore>,
+    backup: Arc<Backup>,
+) -> Router {
+    let jobs = Arc::new(JobManager::new());
+    tokio::spawn(job_expiry_task(jobs.clone()));
+    tokio::spawn(summary_task(jobs.clone(), catalog.clone(), store.clone()));
     let session_store = middleware::build_session_layer().await;
+    let state = AppState {
+        motion_detector,
+        jobs,
+        catalog,
+        store,
+        proxies: Arc::new(ProxyCache::from_env()),
+        hls: Arc::new(HlsCache::from_env()),
+        thumbnails: Arc::new(ThumbnailCache::from_env()),
+        sprites: Arc::new(SpriteCache::from_env()),
+        backup,
+    };
 
     let app = Router::new()
         .route("/start_cam", post(routes::init_camera))
         .route("/shutdown", post(routes::shutdown_device))
         .route("/cam_status", get(routes::get_current_cam_status))
-        .with_state(Arc::new(motion_detector))
-        .route("/start_download", post(routes::start_download))
-        .route("/download", get(routes::download_from_task))
-        .with_state(Arc::new(thread_pool))
-        .route("/file", get(routes::stream))
+        .route("/events", get(routes::get_recent_events))
+        .route("/jobs", get(routes::list_jobs))
+        .route("/jobs/download", post(routes::start_download_job))
+        .route("/jobs/export", post(routes::start_export_job))
+        .route("/jobs/summary", post(routes::start_summary_job))
+        .route("/jobs/{id}", get(routes::get_job).delete(routes::cancel_job))
+        .route("/jobs/{id}/result", get(routes::get_job_result))
+        .route("/archive", get(routes::download_archive))
+        .route("/file", get(routes::stream).delete(routes::delete_video))
+        .route("/file/{sprite_file}", get(routes::stream_sprites))
+        .route("/proxy", get(routes::stream_proxy))
+        .route("/hls/{file_name}/{segment}", get(routes::stream_hls))
+        .route(
+            "/thumbnails/{file_name}/{kind}",
+            get(routes::stream_thumbnail),
+        )
+        .route("/live/{segment}", get(routes::stream_live_hls))
+        .route("/mjpeg", get(routes::mjpeg_stream))
+        .route("/snapshot.jpg", get(routes::snapshot))
+        .route("/delete_videos", post(routes::delete_videos))
+        .route("/trash", get(routes::get_trash))
+        .route("/trash/restore", post(routes::restore_video))
         .route("/video_data", get(routes::get_all_videos_data))
+        .route("/video_search", get(routes::search_videos))
+        .route("/video_annotations", post(routes::annotate_video))
+        .route("/catalog/rebuild", post(routes::rebuild_catalog))
+        .route("/stats/activity", get(routes::activity_stats))
+        .route("/backup/status", get(routes::backup_status))
         .route("/turn_config", get(routes::get_turn_config))
         .route("/dashboard", get(web_routes::index))
         .route("/play_videos", get(web_routes::play_videos))
@@ -43,7 +93,8 @@ pub async fn create_app(motion_detector: MotionDetector) -> Router {
         .nest_service(
             "/static",
             ServeDir::new("/home/jamie/coding/rust-raspi-motion-detector/frontend/static"),
-        );
+        )
+        .with_state(state);
     app
 }
 
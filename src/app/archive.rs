@@ -0,0 +1,334 @@
+use crate::timezone::local_now;
+use axum::{
+    body::Body,
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use chrono::Utc;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::{io, path::PathBuf, time::UNIX_EPOCH};
+use tokio::{
+    fs::File,
+    io::{duplex, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+};
+use tokio_util::io::ReaderStream;
+use tracing::error;
+
+const BLOCK_SIZE: u64 = 512;
+const MANIFEST_NAME: &str = "MANIFEST.json";
+/// Longest name that fits in the name field of a tar header.
+const MAX_NAME_LENGTH: usize = 100;
+
+/// A file to include in an archive, sizes are read up front so the response length is known.
+pub struct ArchiveEntry {
+    pub path: PathBuf,
+    pub name: String,
+    pub size: u64,
+    pub modified: u64,
+}
+
+impl ArchiveEntry {
+    pub async fn from_path(path: PathBuf) -> io::Result<Self> {
+        let metadata = tokio::fs::metadata(&path).await?;
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .filter(|name| name.len() <= MAX_NAME_LENGTH)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{} can't be stored in an archive", path.display()),
+                )
+            })?
+            .to_string();
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map(|modified| modified.as_secs())
+            .unwrap_or(0);
+        Ok(ArchiveEntry {
+            path,
+            name,
+            size: metadata.len(),
+            modified,
+        })
+    }
+}
+
+#[derive(Serialize)]
+struct ManifestFile<'a> {
+    name: &'a str,
+    size: u64,
+    sha256: String,
+}
+
+#[derive(Serialize)]
+struct Manifest<'a> {
+    created: String,
+    files: Vec<ManifestFile<'a>>,
+}
+
+/// A tar archive of recordings streamed straight from disk, ending with a manifest of checksums.
+///
+/// Checksums are worked out while the files are streamed, so the manifest is the last entry.
+pub struct TarArchive {
+    entries: Vec<ArchiveEntry>,
+    file_name: String,
+}
+
+impl TarArchive {
+    pub fn new(entries: Vec<ArchiveEntry>) -> Self {
+        let file_name = format!("recordings_{}.tar", local_now().format("%Y%m%d_%H%M%S"));
+        TarArchive { entries, file_name }
+    }
+
+    fn manifest(entries: &[ArchiveEntry], checksums: &[String], created: &str) -> Vec<u8> {
+        let manifest = Manifest {
+            created: created.to_string(),
+            files: entries
+                .iter()
+                .zip(checksums)
+                .map(|(entry, sha256)| ManifestFile {
+                    name: &entry.name,
+                    size: entry.size,
+                    sha256: sha256.clone(),
+                })
+                .collect(),
+        };
+        serde_json::to_vec_pretty(&manifest).unwrap()
+    }
+
+    /// Total bytes in the archive, the manifest length is fixed as every checksum has the same width.
+    fn content_length(&self, created: &str) -> u64 {
+        let placeholder = vec!["0".repeat(64); self.entries.len()];
+        let manifest_size = Self::manifest(&self.entries, &placeholder, created).len() as u64;
+        let files_size: u64 = self
+            .entries
+            .iter()
+            .map(|entry| BLOCK_SIZE + padded(entry.size))
+            .sum();
+        files_size + BLOCK_SIZE + padded(manifest_size) + 2 * BLOCK_SIZE
+    }
+
+    async fn write_to(
+        entries: Vec<ArchiveEntry>,
+        created: String,
+        mut writer: impl AsyncWrite + Unpin,
+    ) -> io::Result<()> {
+        let mut checksums = Vec::with_capacity(entries.len());
+        let mut buffer = vec![0u8; 64 * 1024];
+        for entry in &entries {
+            writer
+                .write_all(&tar_header(&entry.name, entry.size, entry.modified))
+                .await?;
+            let mut file = File::open(&entry.path).await?.take(entry.size);
+            let mut hasher = Sha256::new();
+            let mut written = 0;
+            loop {
+                let read = file.read(&mut buffer).await?;
+                if read == 0 {
+                    break;
+                }
+                hasher.update(&buffer[..read]);
+                writer.write_all(&buffer[..read]).await?;
+                written += read as u64;
+            }
+            if written != entry.size {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!("{} changed size while being archived", entry.name),
+                ));
+            }
+            write_padding(&mut writer, entry.size).await?;
+            checksums.push(format!("{:x}", hasher.finalize()));
+        }
+
+        let manifest = Self::manifest(&entries, &checksums, &created);
+        let modified = Utc::now().timestamp().max(0) as u64;
+        writer
+            .write_all(&tar_header(MANIFEST_NAME, manifest.len() as u64, modified))
+            .await?;
+        writer.write_all(&manifest).await?;
+        write_padding(&mut writer, manifest.len() as u64).await?;
+        // a tar archive ends with two empty blocks
+        writer.write_all(&[0u8; 2 * BLOCK_SIZE as usize]).await?;
+        writer.shutdown().await
+    }
+}
+
+impl IntoResponse for TarArchive {
+    fn into_response(self) -> Response {
+        let created = local_now().to_rfc3339();
+        let content_length = self.content_length(&created);
+        let (reader, writer) = duplex(64 * 1024);
+        let file_name = self.file_name;
+        let entries = self.entries;
+        tokio::spawn(async move {
+            if let Err(err) = Self::write_to(entries, created, writer).await {
+                error!("Error streaming archive, error: {err}");
+            }
+        });
+
+        Response::builder()
+            .header(header::CONTENT_TYPE, "application/x-tar")
+            .header(
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{file_name}\""),
+            )
+            .header(header::CONTENT_LENGTH, content_length)
+            .body(Body::from_stream(ReaderStream::new(reader)))
+            .unwrap_or_else(|e| {
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("build archive response error: {e}"),
+                )
+                    .into_response()
+            })
+    }
+}
+
+fn padded(size: u64) -> u64 {
+    size.div_ceil(BLOCK_SIZE) * BLOCK_SIZE
+}
+
+async fn write_padding(writer: &mut (impl AsyncWrite + Unpin), size: u64) -> io::Result<()> {
+    let padding = (padded(size) - size) as usize;
+    writer.write_all(&vec![0u8; padding]).await
+}
+
+/// Writes `value` as a nul terminated octal number filling `field`.
+fn write_octal(field: &mut [u8], value: u64) {
+    let end = field.len() - 1;
+    let digits = format!("{value:0end$o}");
+    field[..end].copy_from_slice(digits.as_bytes());
+    field[end] = 0;
+}
+
+/// Builds a ustar header block for a regular file.
+fn tar_header(name: &str, size: u64, modified: u64) -> [u8; BLOCK_SIZE as usize] {
+    let mut header = [0u8; BLOCK_SIZE as usize];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    write_octal(&mut header[100..108], 0o644);
+    write_octal(&mut header[108..116], 0);
+    write_octal(&mut header[116..124], 0);
+    write_octal(&mut header[124..136], size);
+    write_octal(&mut header[136..148], modified);
+    header[156] = b'0';
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+
+    // the checksum is calculated with its own field filled with spaces
+    header[148..156].copy_from_slice(b"        ");
+    let checksum: u64 = header.iter().map(|byte| *byte as u64).sum();
+    let digits = format!("{checksum:06o}");
+    header[148..154].copy_from_slice(digits.as_bytes());
+    header[154] = 0;
+    header[155] = b' ';
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::to_bytes;
+    use serde_json::Value;
+    use std::{fs, io::Read};
+    use tempfile::TempDir;
+
+    /// Contents that don't line up with tar blocks, one exactly a block and an empty file.
+    fn write_recordings(dir: &TempDir) -> Vec<(String, Vec<u8>)> {
+        let recordings = vec![
+            (
+                "motion_2025-06-01_14-03-00_UTC0100.mp4".to_string(),
+                (0..1000u32).map(|byte| byte as u8).collect(),
+            ),
+            ("block.mp4".to_string(), vec![7; BLOCK_SIZE as usize]),
+            ("empty.mp4".to_string(), Vec::new()),
+        ];
+        for (name, contents) in &recordings {
+            fs::write(dir.path().join(name), contents).unwrap();
+        }
+        recordings
+    }
+
+    #[tokio::test]
+    async fn streamed_archive_matches_its_length_and_manifest() {
+        let dir = TempDir::new().unwrap();
+        let recordings = write_recordings(&dir);
+        let mut entries = Vec::new();
+        for (name, _) in &recordings {
+            entries.push(
+                ArchiveEntry::from_path(dir.path().join(name))
+                    .await
+                    .unwrap(),
+            );
+        }
+
+        let response = TarArchive::new(entries).into_response();
+        let content_length: usize = response.headers()[header::CONTENT_LENGTH]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body.len(), content_length);
+        assert_eq!(body.len() % BLOCK_SIZE as usize, 0);
+
+        let mut archive = tar::Archive::new(&body[..]);
+        let mut read = Vec::new();
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let name = entry.path().unwrap().to_string_lossy().to_string();
+            let mut contents = Vec::new();
+            entry.read_to_end(&mut contents).unwrap();
+            read.push((name, contents));
+        }
+        let (manifest_name, manifest) = read.pop().unwrap();
+        assert_eq!(manifest_name, MANIFEST_NAME);
+        assert_eq!(read, recordings);
+
+        let manifest: Value = serde_json::from_slice(&manifest).unwrap();
+        let files = manifest["files"].as_array().unwrap();
+        assert_eq!(files.len(), recordings.len());
+        for (file, (name, contents)) in files.iter().zip(&recordings) {
+            assert_eq!(file["name"], name.as_str());
+            assert_eq!(file["size"], contents.len());
+            assert_eq!(
+                file["sha256"],
+                format!("{:x}", Sha256::digest(contents)).as_str()
+            );
+        }
+        assert_eq!(
+            files[2]["sha256"],
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+    }
+
+    #[tokio::test]
+    async fn file_changing_size_fails_the_stream() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("motion.mp4");
+        fs::write(&path, [1; 100]).unwrap();
+        let entry = ArchiveEntry::from_path(path.clone()).await.unwrap();
+        fs::write(&path, [1; 50]).unwrap();
+
+        let mut written = Vec::new();
+        let err = TarArchive::write_to(vec![entry], String::new(), &mut written)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn names_too_long_for_a_header_are_refused() {
+        let dir = TempDir::new().unwrap();
+        let path = dir
+            .path()
+            .join(format!("{}.mp4", "a".repeat(MAX_NAME_LENGTH)));
+        fs::write(&path, [1; 10]).unwrap();
+        let err = ArchiveEntry::from_path(path).await.err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -1,18 +1,29 @@
+use crate::storage::backend::{ByteStream, StorageBackend};
 use axum::{
-    body,
-    http::{header, StatusCode},
+    body::{self, Body},
+    http::{header, HeaderMap, Method, StatusCode},
     response::{IntoResponse, Response},
     BoxError,
 };
 use bytes::Bytes;
-use futures_util::TryStream;
-use std::{io, path::Path};
+use chrono::{DateTime, Utc};
+use futures_util::{stream, StreamExt, TryStream};
+use http_range_header::{
+    parse_range_header, EndPosition, StartPosition, SyntacticallyCorrectRange,
+};
+use std::{
+    io::{self, SeekFrom},
+    ops::{Range, RangeInclusive},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::UNIX_EPOCH,
+};
 use tokio::{
     fs::File,
-    io::{AsyncReadExt, AsyncSeekExt},
+    io::{duplex, AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
 };
 use tokio_util::io::ReaderStream;
-use tracing::debug;
+use tracing::{debug, error};
 
 #[derive(Debug)]
 pub struct FileStream<S> {
@@ -69,65 +80,6 @@ where
         self.content_size = Some(len);
         self
     }
-
-    pub fn into_range_response(self, start: u64, end: u64, total_size: u64) -> Response {
-        let mut resp = Response::builder().header(header::CONTENT_TYPE, "video/mp4");
-        resp = resp.status(StatusCode::PARTIAL_CONTENT);
-
-        debug!("bytes {start}-{end}/{total_size}");
-        resp = resp.header(
-            header::CONTENT_RANGE,
-            format!("bytes {start}-{end}/{total_size}"),
-        );
-
-        let content_length = total_size - start;
-        resp = resp.header(header::CONTENT_LENGTH, content_length);
-
-        resp.body(body::Body::from_stream(self.stream))
-            .unwrap_or_else(|e| {
-                (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    format!("build FileStream responsec error: {e}"),
-                )
-                    .into_response()
-            })
-    }
-
-    pub async fn try_range_response(
-        file_path: impl AsRef<Path>,
-        start: u64,
-        mut end: u64,
-    ) -> io::Result<Response> {
-        // open file
-        let mut file = File::open(file_path).await?;
-
-        // get file metadata
-        let metadata = file.metadata().await?;
-        let total_size = metadata.len();
-        debug!("total size: {total_size}");
-
-        if end == 0 {
-            end = total_size - 1;
-        }
-
-        // range check
-        if start > total_size {
-            return Ok((StatusCode::RANGE_NOT_SATISFIABLE, "Range Not Satisfiable").into_response());
-        }
-        if start > end {
-            return Ok((StatusCode::RANGE_NOT_SATISFIABLE, "Range Not Satisfiable").into_response());
-        }
-        if end >= total_size {
-            return Ok((StatusCode::RANGE_NOT_SATISFIABLE, "Range Not Satisfiable").into_response());
-        }
-
-        // get file stream and seek to start to return range response
-        file.seek(std::io::SeekFrom::Start(start)).await?;
-
-        let stream = ReaderStream::new(file.take(end - start + 1));
-
-        Ok(FileStream::new(stream).into_range_response(start, end, total_size))
-    }
 }
 
 impl<S> IntoResponse for FileStream<S>
@@ -145,7 +97,7 @@ where
                 format!("attachment; filename=\"{file_name}\""),
             );
             let file_split: Vec<&str> = file_name.split(".").collect();
-            if let Some(file_stem) = file_split.get(0) {
+            if let Some(file_stem) = file_split.first() {
                 resp = resp.header(
                     header::HeaderName::from_static("file_stem"),
                     header::HeaderValue::from_str(file_stem).unwrap(),
@@ -167,3 +119,604 @@ where
             })
     }
 }
+
+/// A file served with HTTP range, conditional request and `HEAD` support.
+///
+/// Without a usable `Range` header the whole file is sent with `200 OK`. A single range is sent as
+/// `206 Partial Content`, several ranges as `multipart/byteranges`. `If-Range` falls back to the
+/// whole file when the file has changed since the client's copy, and `If-None-Match` or
+/// `If-Modified-Since` get `304 Not Modified` when it hasn't.
+///
+/// The file can be local or held by a [`StorageBackend`], ranges are read from either the same way.
+pub struct RangedFile {
+    source: Source,
+    content_type: String,
+    total_size: u64,
+    etag: String,
+    last_modified: DateTime<Utc>,
+}
+
+impl RangedFile {
+    pub async fn open(path: impl AsRef<Path>, content_type: &str) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let metadata = tokio::fs::metadata(&path).await?;
+        if !metadata.is_file() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} is not a file", path.display()),
+            ));
+        }
+        let modified = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        // HTTP dates only have second precision, so validators are compared at that precision
+        let last_modified =
+            DateTime::from_timestamp(modified.as_secs() as i64, 0).unwrap_or_default();
+        let etag = format!(
+            "\"{:x}-{:x}-{:x}\"",
+            metadata.len(),
+            modified.as_secs(),
+            modified.subsec_nanos()
+        );
+        Ok(RangedFile {
+            source: Source::Local(path),
+            content_type: content_type.to_string(),
+            total_size: metadata.len(),
+            etag,
+            last_modified,
+        })
+    }
+
+    /// A recording held by `backend`, `None` if the backend doesn't have it.
+    pub async fn remote(
+        backend: Arc<dyn StorageBackend>,
+        file_name: &str,
+        content_type: &str,
+    ) -> anyhow::Result<Option<Self>> {
+        let Some(info) = backend.info(file_name).await? else {
+            return Ok(None);
+        };
+        let modified = info.last_modified.timestamp();
+        let last_modified = DateTime::from_timestamp(modified, 0).unwrap_or_default();
+        let etag = format!("\"{:x}-{:x}\"", info.size, modified);
+        Ok(Some(RangedFile {
+            source: Source::Remote {
+                backend,
+                file_name: file_name.to_string(),
+            },
+            content_type: content_type.to_string(),
+            total_size: info.size,
+            etag,
+            last_modified,
+        }))
+    }
+
+    /// Builds the response to a `GET` or `HEAD` request with the given headers, `Range` is only
+    /// honoured on `GET` as RFC 9110 defines it for no other method.
+    pub async fn response(self, method: &Method, headers: &HeaderMap) -> Response {
+        let head = method == Method::HEAD;
+        if self.not_modified(headers) {
+            return self
+                .builder(StatusCode::NOT_MODIFIED)
+                .body(Body::empty())
+                .unwrap_or_else(build_error);
+        }
+
+        let requested = if head {
+            Ok(None)
+        } else {
+            self.requested_ranges(headers)
+        };
+        let ranges = match requested {
+            Ok(ranges) => ranges,
+            Err(RangeNotSatisfiable) => {
+                return self
+                    .builder(StatusCode::RANGE_NOT_SATISFIABLE)
+                    .header(
+                        header::CONTENT_RANGE,
+                        format!("bytes */{}", self.total_size),
+                    )
+                    .body(Body::empty())
+                    .unwrap_or_else(build_error);
+            }
+        };
+
+        let result = match ranges.as_deref() {
+            None | Some([]) => self.full_response(head).await,
+            Some([range]) => self.single_range_response(range.clone()).await,
+            Some(ranges) => self.multipart_response(ranges.to_vec()),
+        };
+        result.unwrap_or_else(|err| {
+            (StatusCode::NOT_FOUND, format!("File not found: {err}")).into_response()
+        })
+    }
+
+    fn last_modified_header(&self) -> String {
+        self.last_modified
+            .format("%a, %d %b %Y %H:%M:%S GMT")
+            .to_string()
+    }
+
+    fn builder(&self, status: StatusCode) -> http::response::Builder {
+        Response::builder()
+            .status(status)
+            .header(header::ACCEPT_RANGES, "bytes")
+            .header(header::ETAG, &self.etag)
+            .header(header::LAST_MODIFIED, self.last_modified_header())
+    }
+
+    /// Whether the client's cached copy is still current.
+    fn not_modified(&self, headers: &HeaderMap) -> bool {
+        if let Some(if_none_match) = header_str(headers, header::IF_NONE_MATCH) {
+            return if_none_match.trim() == "*"
+                || if_none_match
+                    .split(',')
+                    .any(|tag| weak_tag(tag.trim()) == weak_tag(&self.etag));
+        }
+        header_str(headers, header::IF_MODIFIED_SINCE)
+            .and_then(parse_http_date)
+            .is_some_and(|since| self.last_modified <= since)
+    }
+
+    /// The ranges to send, `Ok(None)` when the whole file should be sent instead.
+    ///
+    /// A malformed `Range` header is ignored as RFC 9110 requires, only a well formed one that
+    /// doesn't overlap the file is unsatisfiable.
+    fn requested_ranges(
+        &self,
+        headers: &HeaderMap,
+    ) -> Result<Option<Vec<RangeInclusive<u64>>>, RangeNotSatisfiable> {
+        let Some(range_header) = header_str(headers, header::RANGE) else {
+            return Ok(None);
+        };
+        if let Some(if_range) = header_str(headers, header::IF_RANGE) {
+            if !self.if_range_matches(if_range) {
+                return Ok(None);
+            }
+        }
+        let ranges = match parse_range_header(range_header) {
+            Ok(parsed) => parsed.ranges,
+            Err(err) => {
+                debug!("Ignoring malformed range {range_header}, error: {err}");
+                return Ok(None);
+            }
+        };
+        // a range ending before it starts makes the whole header invalid rather than unsatisfiable
+        if ranges.iter().any(|range| match (range.start, range.end) {
+            (StartPosition::Index(start), EndPosition::Index(end)) => end < start,
+            _ => false,
+        }) {
+            debug!("Ignoring reversed range {range_header}");
+            return Ok(None);
+        }
+        let satisfiable = satisfiable_ranges(&ranges, self.total_size);
+        if satisfiable.is_empty() {
+            return Err(RangeNotSatisfiable);
+        }
+        Ok(Some(satisfiable))
+    }
+
+    /// `If-Range` needs a strong match, weak entity tags never match.
+    fn if_range_matches(&self, if_range: &str) -> bool {
+        let if_range = if_range.trim();
+        if if_range.starts_with('"') {
+            return if_range == self.etag;
+        }
+        if if_range.starts_with("W/") {
+            return false;
+        }
+        parse_http_date(if_range).is_some_and(|date| date == self.last_modified)
+    }
+
+    async fn full_response(self, head: bool) -> io::Result<Response> {
+        let builder = self
+            .builder(StatusCode::OK)
+            .header(header::CONTENT_TYPE, &self.content_type)
+            .header(header::CONTENT_LENGTH, self.total_size);
+        let body = if head {
+            Body::empty()
+        } else {
+            Body::from_stream(self.source.read(0..self.total_size).await?)
+        };
+        Ok(builder.body(body).unwrap_or_else(build_error))
+    }
+
+    async fn single_range_response(self, range: RangeInclusive<u64>) -> io::Result<Response> {
+        let (start, end) = (*range.start(), *range.end());
+        let builder = self
+            .builder(StatusCode::PARTIAL_CONTENT)
+            .header(header::CONTENT_TYPE, &self.content_type)
+            .header(
+                header::CONTENT_RANGE,
+                format!("bytes {start}-{end}/{}", self.total_size),
+            )
+            .header(header::CONTENT_LENGTH, end - start + 1);
+        let body = Body::from_stream(self.source.read(start..end + 1).await?);
+        Ok(builder.body(body).unwrap_or_else(build_error))
+    }
+
+    fn multipart_response(self, ranges: Vec<RangeInclusive<u64>>) -> io::Result<Response> {
+        let boundary = format!(
+            "{:016x}",
+            self.total_size ^ self.last_modified.timestamp() as u64
+        );
+        let part_headers: Vec<String> = ranges
+            .iter()
+            .map(|range| {
+                format!(
+                    "\r\n--{boundary}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+                    self.content_type,
+                    range.start(),
+                    range.end(),
+                    self.total_size
+                )
+            })
+            .collect();
+        let closing = format!("\r\n--{boundary}--\r\n");
+        let content_length: u64 = part_headers
+            .iter()
+            .zip(&ranges)
+            .map(|(part_header, range)| part_header.len() as u64 + range.end() - range.start() + 1)
+            .sum::<u64>()
+            + closing.len() as u64;
+
+        let builder = self
+            .builder(StatusCode::PARTIAL_CONTENT)
+            .header(
+                header::CONTENT_TYPE,
+                format!("multipart/byteranges; boundary={boundary}"),
+            )
+            .header(header::CONTENT_LENGTH, content_length);
+
+        let (reader, mut writer) = duplex(64 * 1024);
+        let source = self.source;
+        tokio::spawn(async move {
+            let written: io::Result<()> = async {
+                for (part_header, range) in part_headers.iter().zip(&ranges) {
+                    writer.write_all(part_header.as_bytes()).await?;
+                    let mut section = source.read(*range.start()..range.end() + 1).await?;
+                    while let Some(chunk) = section.next().await {
+                        writer.write_all(&chunk?).await?;
+                    }
+                }
+                writer.write_all(closing.as_bytes()).await?;
+                writer.shutdown().await
+            }
+            .await;
+            if let Err(err) = written {
+                error!("Error streaming byte ranges of {source}, error: {err}");
+            }
+        });
+        Ok(builder
+            .body(Body::from_stream(ReaderStream::new(reader)))
+            .unwrap_or_else(build_error))
+    }
+}
+
+/// A well formed `Range` header none of whose ranges overlap the file.
+struct RangeNotSatisfiable;
+
+/// Resolves ranges against a file of `size` bytes, dropping those starting past its end, clamping
+/// suffixes longer than the file to its start and merging ranges that overlap or touch.
+fn satisfiable_ranges(ranges: &[SyntacticallyCorrectRange], size: u64) -> Vec<RangeInclusive<u64>> {
+    // nothing in an empty file can be satisfied
+    let Some(last) = size.checked_sub(1) else {
+        return Vec::new();
+    };
+    let mut resolved: Vec<RangeInclusive<u64>> = ranges
+        .iter()
+        .filter_map(|range| {
+            let start = match range.start {
+                StartPosition::Index(start) => start,
+                StartPosition::FromLast(suffix) => size.saturating_sub(suffix),
+            };
+            let end = match range.end {
+                EndPosition::Index(end) => end.min(last),
+                EndPosition::LastByte => last,
+            };
+            (start <= end).then_some(start..=end)
+        })
+        .collect();
+    resolved.sort_by_key(|range| *range.start());
+
+    let mut merged: Vec<RangeInclusive<u64>> = Vec::with_capacity(resolved.len());
+    for range in resolved {
+        match merged.last_mut() {
+            Some(previous) if *range.start() <= previous.end().saturating_add(1) => {
+                let end = *previous.end().max(range.end());
+                *previous = *previous.start()..=end;
+            }
+            _ => merged.push(range),
+        }
+    }
+    merged
+}
+
+/// Where a [`RangedFile`] reads its bytes from.
+enum Source {
+    Local(PathBuf),
+    Remote {
+        backend: Arc<dyn StorageBackend>,
+        file_name: String,
+    },
+}
+
+impl Source {
+    async fn read(&self, range: Range<u64>) -> io::Result<ByteStream> {
+        if range.is_empty() {
+            return Ok(stream::empty().boxed());
+        }
+        match self {
+            Source::Local(path) => {
+                let mut file = File::open(path).await?;
+                file.seek(SeekFrom::Start(range.start)).await?;
+                Ok(ReaderStream::new(file.take(range.end - range.start)).boxed())
+            }
+            Source::Remote { backend, file_name } => backend
+                .read_range(file_name, range)
+                .await
+                .map_err(io::Error::other),
+        }
+    }
+}
+
+impl std::fmt::Display for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Source::Local(path) => write!(f, "{}", path.display()),
+            Source::Remote { backend, file_name } => write!(f, "{}:{file_name}", backend.name()),
+        }
+    }
+}
+
+fn header_str(headers: &HeaderMap, name: header::HeaderName) -> Option<&str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+/// Entity tag without its weak prefix, `If-None-Match` uses weak comparison.
+fn weak_tag(tag: &str) -> &str {
+    tag.strip_prefix("W/").unwrap_or(tag)
+}
+
+fn parse_http_date(date: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc2822(date.trim())
+        .ok()
+        .map(|date| date.with_timezone(&Utc))
+}
+
+fn build_error(err: http::Error) -> Response {
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        format!("build file response error: {err}"),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::to_bytes;
+    use http::HeaderValue;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    const CONTENTS: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyz";
+
+    fn recording() -> NamedTempFile {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(CONTENTS).unwrap();
+        file
+    }
+
+    async fn respond(file: &NamedTempFile, method: Method, headers: &[(&str, &str)]) -> Response {
+        let mut header_map = HeaderMap::new();
+        for (name, value) in headers {
+            header_map.insert(
+                header::HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                HeaderValue::from_str(value).unwrap(),
+            );
+        }
+        RangedFile::open(file.path(), "video/mp4")
+            .await
+            .unwrap()
+            .response(&method, &header_map)
+            .await
+    }
+
+    fn header_of(response: &Response, name: header::HeaderName) -> &str {
+        response.headers()[name].to_str().unwrap()
+    }
+
+    async fn body_of(response: Response) -> Bytes {
+        to_bytes(response.into_body(), usize::MAX).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn no_range_sends_whole_file() {
+        let file = recording();
+        let response = respond(&file, Method::GET, &[]).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(header_of(&response, header::CONTENT_LENGTH), "36");
+        assert_eq!(header_of(&response, header::ACCEPT_RANGES), "bytes");
+        assert_eq!(body_of(response).await, CONTENTS);
+    }
+
+    #[tokio::test]
+    async fn single_range_is_partial() {
+        let file = recording();
+        let response = respond(&file, Method::GET, &[("range", "bytes=2-5")]).await;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(header_of(&response, header::CONTENT_RANGE), "bytes 2-5/36");
+        assert_eq!(header_of(&response, header::CONTENT_LENGTH), "4");
+        assert_eq!(body_of(response).await, &CONTENTS[2..=5]);
+    }
+
+    #[tokio::test]
+    async fn open_ended_range_runs_to_end() {
+        let file = recording();
+        let response = respond(&file, Method::GET, &[("range", "bytes=30-")]).await;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(
+            header_of(&response, header::CONTENT_RANGE),
+            "bytes 30-35/36"
+        );
+        assert_eq!(body_of(response).await, &CONTENTS[30..]);
+    }
+
+    #[tokio::test]
+    async fn suffix_range_sends_last_bytes() {
+        let file = recording();
+        let response = respond(&file, Method::GET, &[("range", "bytes=-4")]).await;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(
+            header_of(&response, header::CONTENT_RANGE),
+            "bytes 32-35/36"
+        );
+        assert_eq!(body_of(response).await, &CONTENTS[32..]);
+    }
+
+    #[tokio::test]
+    async fn several_ranges_are_multipart() {
+        let file = recording();
+        let response = respond(&file, Method::GET, &[("range", "bytes=0-1,10-11")]).await;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        let content_type = header_of(&response, header::CONTENT_TYPE).to_string();
+        let boundary = content_type
+            .strip_prefix("multipart/byteranges; boundary=")
+            .expect("multipart content type");
+        let content_length: usize = header_of(&response, header::CONTENT_LENGTH)
+            .parse()
+            .unwrap();
+        let body = body_of(response).await;
+        assert_eq!(body.len(), content_length);
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains("Content-Range: bytes 0-1/36\r\n\r\n01"));
+        assert!(body.contains("Content-Range: bytes 10-11/36\r\n\r\nab"));
+        assert!(body.ends_with(&format!("\r\n--{boundary}--\r\n")));
+    }
+
+    #[tokio::test]
+    async fn range_past_end_is_unsatisfiable() {
+        let file = recording();
+        let response = respond(&file, Method::GET, &[("range", "bytes=100-200")]).await;
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(header_of(&response, header::CONTENT_RANGE), "bytes */36");
+    }
+
+    #[tokio::test]
+    async fn suffix_longer_than_file_sends_whole_file() {
+        let file = recording();
+        let response = respond(&file, Method::GET, &[("range", "bytes=-500")]).await;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(header_of(&response, header::CONTENT_RANGE), "bytes 0-35/36");
+        assert_eq!(header_of(&response, header::CONTENT_LENGTH), "36");
+        assert_eq!(body_of(response).await, CONTENTS);
+    }
+
+    #[tokio::test]
+    async fn overlapping_ranges_are_merged() {
+        let file = recording();
+        let response = respond(&file, Method::GET, &[("range", "bytes=0-5,3-8")]).await;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(header_of(&response, header::CONTENT_RANGE), "bytes 0-8/36");
+        assert_eq!(body_of(response).await, &CONTENTS[..=8]);
+
+        // out of order and touching ranges merge too, leaving the separate one as its own part
+        let response = respond(&file, Method::GET, &[("range", "bytes=20-25,4-7,0-3,100-")]).await;
+        let body = String::from_utf8(body_of(response).await.to_vec()).unwrap();
+        assert!(body.contains("Content-Range: bytes 0-7/36\r\n\r\n01234567"));
+        assert!(body.contains("Content-Range: bytes 20-25/36\r\n\r\nklmnop"));
+        assert_eq!(body.matches("Content-Range").count(), 2);
+    }
+
+    #[tokio::test]
+    async fn any_range_of_empty_file_is_unsatisfiable() {
+        let file = NamedTempFile::new().unwrap();
+        for range in ["bytes=0-", "bytes=-5"] {
+            let response = respond(&file, Method::GET, &[("range", range)]).await;
+            assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+            assert_eq!(header_of(&response, header::CONTENT_RANGE), "bytes */0");
+        }
+        let response = respond(&file, Method::GET, &[]).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(header_of(&response, header::CONTENT_LENGTH), "0");
+    }
+
+    #[tokio::test]
+    async fn reversed_range_is_ignored() {
+        let file = recording();
+        let response = respond(&file, Method::GET, &[("range", "bytes=5-2")]).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body_of(response).await, CONTENTS);
+    }
+
+    #[tokio::test]
+    async fn if_range_mismatch_sends_whole_file() {
+        let file = recording();
+        let response = respond(
+            &file,
+            Method::GET,
+            &[("range", "bytes=2-5"), ("if-range", "\"stale\"")],
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body_of(response).await, CONTENTS);
+    }
+
+    #[tokio::test]
+    async fn if_range_match_sends_range() {
+        let file = recording();
+        let etag = respond(&file, Method::HEAD, &[]).await.headers()[header::ETAG]
+            .to_str()
+            .unwrap()
+            .to_string();
+        let response = respond(
+            &file,
+            Method::GET,
+            &[("range", "bytes=2-5"), ("if-range", &etag)],
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+    }
+
+    #[tokio::test]
+    async fn if_none_match_is_not_modified() {
+        let file = recording();
+        let etag = respond(&file, Method::HEAD, &[]).await.headers()[header::ETAG]
+            .to_str()
+            .unwrap()
+            .to_string();
+        let response = respond(&file, Method::GET, &[("if-none-match", &etag)]).await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert!(body_of(response).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn if_modified_since_is_not_modified() {
+        let file = recording();
+        let last_modified = respond(&file, Method::HEAD, &[]).await.headers()
+            [header::LAST_MODIFIED]
+            .to_str()
+            .unwrap()
+            .to_string();
+        let response = respond(&file, Method::GET, &[("if-modified-since", &last_modified)]).await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+    }
+
+    #[tokio::test]
+    async fn head_has_headers_without_body() {
+        let file = recording();
+        // Range is only defined for GET, so HEAD describes the whole file
+        let response = respond(&file, Method::HEAD, &[("range", "bytes=2-5")]).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(header_of(&response, header::CONTENT_LENGTH), "36");
+        assert!(!response.headers().contains_key(header::CONTENT_RANGE));
+        assert!(body_of(response).await.is_empty());
+
+        let response = respond(&file, Method::HEAD, &[]).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(header_of(&response, header::CONTENT_LENGTH), "36");
+        assert!(body_of(response).await.is_empty());
+    }
+}
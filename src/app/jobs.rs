@@ -0,0 +1,396 @@
+use crate::timezone::{serialize_local, serialize_local_option};
+use anyhow::Result;
+use chrono::{DateTime, TimeDelta, Utc};
+use glob::glob;
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    env::var,
+    fs,
+    future::Future,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::{sync::Semaphore, time::interval};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info};
+
+pub type JobId = u64;
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+impl JobStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobStatus::Queued => "queued",
+            JobStatus::Running => "running",
+            JobStatus::Completed => "completed",
+            JobStatus::Failed => "failed",
+            JobStatus::Cancelled => "cancelled",
+        }
+    }
+
+    fn is_finished(&self) -> bool {
+        matches!(
+            self,
+            JobStatus::Completed | JobStatus::Failed | JobStatus::Cancelled
+        )
+    }
+}
+
+/// What a finished job produced, fetched later through `/jobs/{id}/result`.
+#[derive(Clone, Debug)]
+pub enum JobOutput {
+    /// A single file, anything under the job output directory is removed when the job expires.
+    File(PathBuf),
+    /// Several existing files, served together as an archive.
+    Files(Vec<PathBuf>),
+}
+
+struct Job {
+    owner: String,
+    /// Other sessions that may follow the job and fetch its result, see [`JobManager::share`].
+    shared_with: Vec<String>,
+    kind: String,
+    status: JobStatus,
+    progress: f32,
+    error: Option<String>,
+    created: DateTime<Utc>,
+    finished: Option<DateTime<Utc>>,
+    output: Option<JobOutput>,
+    cancel: CancellationToken,
+}
+
+impl Job {
+    fn is_visible_to(&self, owner: &str) -> bool {
+        self.owner == owner || self.shared_with.iter().any(|shared| shared == owner)
+    }
+}
+
+/// Public view of a job returned by the job endpoints.
+#[derive(Serialize, Debug)]
+pub struct JobInfo {
+    pub id: JobId,
+    pub kind: String,
+    pub status: JobStatus,
+    /// Fraction of the work done, from 0 to 1.
+    pub progress: f32,
+    pub error: Option<String>,
+    #[serde(serialize_with = "serialize_local")]
+    pub created: DateTime<Utc>,
+    #[serde(serialize_with = "serialize_local_option")]
+    pub finished: Option<DateTime<Utc>>,
+}
+
+impl JobInfo {
+    fn new(id: JobId, job: &Job) -> Self {
+        JobInfo {
+            id,
+            kind: job.kind.clone(),
+            status: job.status,
+            progress: job.progress,
+            error: job.error.clone(),
+            created: job.created,
+            finished: job.finished,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum JobLookupError {
+    NotFound,
+    NotFinished(JobStatus),
+    NoOutput(JobStatus),
+}
+
+type JobTable = Arc<Mutex<HashMap<JobId, Job>>>;
+
+/// Passed to a job's work so it can report progress and notice cancellation.
+#[derive(Clone)]
+pub struct JobHandle {
+    pub id: JobId,
+    jobs: JobTable,
+    cancel: CancellationToken,
+}
+
+impl JobHandle {
+    pub fn set_progress(&self, progress: f32) {
+        if let Some(job) = self.jobs.lock().unwrap().get_mut(&self.id) {
+            job.progress = progress.clamp(0.0, 1.0);
+        }
+    }
+
+    /// Blocking work should check this regularly and stop early once it returns true.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.is_cancelled()
+    }
+}
+
+/// Runs background work such as downloads, exports and transcodes on behalf of a session.
+///
+/// Read from the environment:
+/// - `JOB_CONCURRENCY`: how many jobs run at once, defaults to 2.
+/// - `JOB_RESULT_TTL_SECS`: how long finished jobs and their results are kept, defaults to an hour.
+/// - `JOB_OUTPUT_PATH`: directory jobs write temporary results to.
+pub struct JobManager {
+    jobs: JobTable,
+    next_id: AtomicU64,
+    slots: Arc<Semaphore>,
+    result_ttl: TimeDelta,
+    output_dir: PathBuf,
+}
+
+impl JobManager {
+    pub fn new() -> Self {
+        let concurrency = var("JOB_CONCURRENCY")
+            .ok()
+            .and_then(|count| count.parse().ok())
+            .unwrap_or(2);
+        let result_ttl = var("JOB_RESULT_TTL_SECS")
+            .ok()
+            .and_then(|secs| secs.parse().ok())
+            .unwrap_or(60 * 60);
+        let output_dir = match var("JOB_OUTPUT_PATH") {
+            Ok(path) => PathBuf::from(path),
+            Err(_) => std::env::temp_dir().join("motion_detector_jobs"),
+        };
+        JobManager {
+            jobs: Arc::new(Mutex::new(HashMap::new())),
+            next_id: AtomicU64::new(1),
+            slots: Arc::new(Semaphore::new(concurrency)),
+            result_ttl: TimeDelta::seconds(result_ttl),
+            output_dir,
+        }
+    }
+
+    /// Path a job can write a temporary result to, the directory is created if needed.
+    pub fn output_path(&self, id: JobId, extension: &str) -> Result<PathBuf> {
+        fs::create_dir_all(&self.output_dir)?;
+        Ok(self.output_dir.join(format!("job_{id}.{extension}")))
+    }
+
+    /// Queues `work` to run once a slot is free and returns the new job's ID.
+    pub fn spawn<F, Fut>(&self, owner: &str, kind: &str, work: F) -> JobId
+    where
+        F: FnOnce(JobHandle) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<JobOutput>> + Send + 'static,
+    {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let cancel = CancellationToken::new();
+        self.jobs.lock().unwrap().insert(
+            id,
+            Job {
+                owner: owner.to_string(),
+                shared_with: Vec::new(),
+                kind: kind.to_string(),
+                status: JobStatus::Queued,
+                progress: 0.0,
+                error: None,
+                created: Utc::now(),
+                finished: None,
+                output: None,
+                cancel: cancel.clone(),
+            },
+        );
+
+        let handle = JobHandle {
+            id,
+            jobs: self.jobs.clone(),
+            cancel: cancel.clone(),
+        };
+        let jobs = self.jobs.clone();
+        let slots = self.slots.clone();
+        tokio::spawn(async move {
+            let _permit = tokio::select! {
+                permit = slots.acquire_owned() => permit.expect("job semaphore is never closed"),
+                _ = cancel.cancelled() => {
+                    finish(&jobs, id, JobStatus::Cancelled, None, None);
+                    return;
+                }
+            };
+            if let Some(job) = jobs.lock().unwrap().get_mut(&id) {
+                job.status = JobStatus::Running;
+            }
+
+            let result = tokio::select! {
+                result = work(handle) => result,
+                _ = cancel.cancelled() => {
+                    finish(&jobs, id, JobStatus::Cancelled, None, None);
+                    return;
+                }
+            };
+            match result {
+                Ok(output) => finish(&jobs, id, JobStatus::Completed, Some(output), None),
+                Err(err) => {
+                    error!("Job {id} failed, error: {err}");
+                    finish(&jobs, id, JobStatus::Failed, None, Some(err.to_string()));
+                }
+            }
+        });
+        info!("Queued {kind} job {id}");
+        id
+    }
+
+    /// Lets `owner` follow a job started by another session and fetch its result, for work such as
+    /// proxies that is shared rather than repeated. Only the session that started the job can cancel
+    /// it. Returns false if the job is unknown.
+    pub fn share(&self, id: JobId, owner: &str) -> bool {
+        let mut jobs = self.jobs.lock().unwrap();
+        match jobs.get_mut(&id) {
+            Some(job) => {
+                if !job.is_visible_to(owner) {
+                    job.shared_with.push(owner.to_string());
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn get(&self, id: JobId, owner: &str) -> Option<JobInfo> {
+        let jobs = self.jobs.lock().unwrap();
+        jobs.get(&id)
+            .filter(|job| job.is_visible_to(owner))
+            .map(|job| JobInfo::new(id, job))
+    }
+
+    pub fn list(&self, owner: &str) -> Vec<JobInfo> {
+        let jobs = self.jobs.lock().unwrap();
+        let mut infos: Vec<JobInfo> = jobs
+            .iter()
+            .filter(|(_, job)| job.is_visible_to(owner))
+            .map(|(id, job)| JobInfo::new(*id, job))
+            .collect();
+        infos.sort_by_key(|info| info.id);
+        infos
+    }
+
+    /// Requests cancellation of a queued or running job, returns false if the job is unknown.
+    pub fn cancel(&self, id: JobId, owner: &str) -> bool {
+        let jobs = self.jobs.lock().unwrap();
+        match jobs.get(&id).filter(|job| job.owner == owner) {
+            Some(job) => {
+                job.cancel.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn output(&self, id: JobId, owner: &str) -> Result<JobOutput, JobLookupError> {
+        let jobs = self.jobs.lock().unwrap();
+        let job = jobs
+            .get(&id)
+            .filter(|job| job.is_visible_to(owner))
+            .ok_or(JobLookupError::NotFound)?;
+        if !job.status.is_finished() {
+            return Err(JobLookupError::NotFinished(job.status));
+        }
+        job.output
+            .clone()
+            .ok_or(JobLookupError::NoOutput(job.status))
+    }
+
+    /// Drops finished jobs older than the result TTL along with any temporary output.
+    pub fn expire(&self) {
+        let cutoff = Utc::now() - self.result_ttl;
+        let mut jobs = self.jobs.lock().unwrap();
+        jobs.retain(|id, job| {
+            let expired = job.finished.is_some_and(|finished| finished < cutoff);
+            if expired {
+                self.remove_output_files(*id);
+            }
+            !expired
+        });
+    }
+
+    /// Removes what a job wrote to the output directory, including partial output of cancelled jobs.
+    fn remove_output_files(&self, id: JobId) {
+        let pattern = format!("{}/job_{id}.*", self.output_dir.display());
+        let Ok(paths) = glob(&pattern) else {
+            return;
+        };
+        for path in paths.filter_map(|path| path.ok()) {
+            let removed = if path.is_dir() {
+                fs::remove_dir_all(&path)
+            } else {
+                fs::remove_file(&path)
+            };
+            if let Err(err) = removed {
+                error!("Unable to remove output of job {id}, error: {err}");
+            }
+        }
+    }
+}
+
+impl Default for JobManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn finish(
+    jobs: &JobTable,
+    id: JobId,
+    status: JobStatus,
+    output: Option<JobOutput>,
+    error: Option<String>,
+) {
+    if let Some(job) = jobs.lock().unwrap().get_mut(&id) {
+        job.status = status;
+        job.finished = Some(Utc::now());
+        if status == JobStatus::Completed {
+            job.progress = 1.0;
+        }
+        job.output = output;
+        job.error = error;
+    }
+}
+
+/// Periodically removes expired jobs and their results.
+pub async fn job_expiry_task(jobs: Arc<JobManager>) {
+    let mut ticker = interval(Duration::from_secs(60));
+    loop {
+        ticker.tick().await;
+        jobs.expire();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn shared_job_is_visible_but_only_cancellable_by_its_owner() {
+        let jobs = JobManager::new();
+        let id = jobs.spawn("first", "transcode", |_| async {
+            Ok(JobOutput::Files(Vec::new()))
+        });
+        assert!(jobs.get(id, "second").is_none());
+        assert!(jobs.share(id, "second"));
+        assert!(jobs.get(id, "second").is_some());
+        assert_eq!(jobs.list("second").len(), 1);
+        assert!(!jobs.cancel(id, "second"));
+        assert!(!jobs.share(id + 1, "second"));
+
+        for _ in 0..100 {
+            if jobs.output(id, "second").is_ok() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("shared job output was not available");
+    }
+}
@@ -1,6 +1,8 @@
 pub mod app;
+pub mod archive;
 pub mod file_stream;
+pub mod jobs;
 pub mod middleware;
 pub mod routes;
-pub mod task;
+pub mod state;
 pub mod web_routes;
@@ -1,32 +1,65 @@
-use super::file_stream::FileStream;
-use super::task::ThreadPool;
+use super::archive::{ArchiveEntry, TarArchive};
+use super::file_stream::{FileStream, RangedFile};
+use super::jobs::{JobId, JobLookupError, JobManager, JobOutput};
+use crate::camera::{frames::FrameHub, live_hls};
+use crate::media::{
+    clip::{spawn_export, TrimMode},
+    hls::{self, HlsCache},
+    proxy::{ProxyCache, ProxyQuality},
+    sprites::{self, SpriteCache},
+    summary::{spawn_summary, SummaryOptions, MAX_SPEED},
+    thumbnails::{ThumbnailCache, ThumbnailKind},
+};
 use crate::motion_detect::gpio::{
     monitor_loop_record, monitor_loop_stream, CameraType, MotionDetector,
 };
+use crate::storage::{
+    audit,
+    backend::StorageBackend,
+    backup::Backup,
+    catalog::{
+        Annotation, Catalog, CatalogEntry, SearchCursor, SearchFilter, SortField, SortOrder,
+    },
+    metadata::RecordingMetadata,
+    stats::ActivityStats,
+    store::{RecordingStore, StoreError},
+    trash::{restore_recording, trash_recording, TrashOutcome},
+    video_save_path,
+};
+use crate::timezone::{day_bounds, local_now, local_rfc3339};
 use axum::{
-    extract::{Query, State},
+    body::Body,
+    extract::{self, Query, State},
     http::StatusCode,
     response::{IntoResponse, Response},
+    Json,
 };
-use chrono::{offset::Utc, DateTime};
-use ffmpeg_next::{ffi::AV_TIME_BASE, format::input};
-use glob::glob;
-use http::{header, HeaderMap};
-use http_range_header::{self, EndPosition, StartPosition};
+use bytes::Bytes;
+use chrono::{offset::Utc, DateTime, NaiveDate, TimeDelta};
+use http::{header, HeaderMap, Method};
 use serde::{Deserialize, Serialize};
 use serde_json::to_string;
 use std::{
     env::var,
     fmt::Display,
-    path::Path,
+    path::{Path, PathBuf},
     result::Result,
     sync::Arc,
     thread::spawn,
-    time::{Duration, UNIX_EPOCH},
+    time::Duration,
 };
-use tokio::fs::File;
+use tokio::{fs::File, task::spawn_blocking, time::timeout};
 use tokio_util::io::ReaderStream;
-use tracing::{debug, error};
+use tower_sessions::Session;
+use tracing::error;
+
+const MJPEG_BOUNDARY: &str = "frame";
+const DEFAULT_SEARCH_LIMIT: usize = 50;
+const MAX_SEARCH_LIMIT: usize = 500;
+const DEFAULT_STATS_DAYS: i64 = 30;
+const MAX_STATS_DAYS: i64 = 366;
+/// How long to wait for a camera frame before giving up, covers the camera starting up.
+const FRAME_TIMEOUT: Duration = Duration::from_secs(5);
 
 impl Display for CameraType {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -55,33 +88,104 @@ pub struct FileName {
     filename: String,
 }
 
+#[derive(Deserialize)]
+pub struct FileNames {
+    file_names: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct DeleteResult {
+    file_name: String,
+    status: &'static str,
+}
+
+#[derive(Deserialize)]
+pub struct ArchiveSelection {
+    /// Comma separated recording names.
+    files: Option<String>,
+    /// Unix timestamp, in seconds, every recording started since and still kept locally is included.
+    since: Option<i64>,
+}
+
 #[derive(Deserialize)]
 pub struct VideosSince {
     timestamp: i64,
 }
 
+#[derive(Deserialize)]
+pub struct VideoSearch {
+    /// RFC 3339 times, recordings must start at or after `from` and before `to`.
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    /// Duration bounds in seconds, both inclusive.
+    min_duration: Option<f64>,
+    max_duration: Option<f64>,
+    trigger: Option<String>,
+    /// Comma separated tags, recordings must have all of them.
+    tags: Option<String>,
+    sort: Option<SortField>,
+    order: Option<SortOrder>,
+    /// `next_cursor` from the previous page.
+    cursor: Option<String>,
+    limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct SearchResults {
+    videos: Vec<VideoData>,
+    total: u64,
+    next_cursor: Option<String>,
+}
+
 #[derive(Deserialize, Serialize)]
 pub struct VideoData {
     file_name: String,
+    /// When the recording started, RFC 3339 in the configured timezone.
     video_created: String,
     video_duration: f64,
+    width: u32,
+    height: u32,
+    size_bytes: u64,
+    codec: String,
+    trigger: Option<String>,
+    metadata: Option<RecordingMetadata>,
+    tags: Vec<String>,
+    starred: bool,
+    notes: Option<String>,
+    protected: bool,
+    poster_url: String,
+    preview_url: String,
+    contact_sheet_url: String,
+    sprites_vtt_url: String,
 }
 
 impl VideoData {
-    pub fn new(created: DateTime<Utc>, video_path: &Path) -> Self {
-        let duration = input(video_path).unwrap().duration() as f64 / AV_TIME_BASE as f64;
-        let formated_date = created.format("%d/%m/%Y %T").to_string();
-        let file_name = video_path
-            .file_name()
-            .unwrap()
-            .to_str()
-            .unwrap()
-            .to_string();
+    pub fn new(entry: CatalogEntry) -> Self {
+        let thumbnail_url =
+            |kind: ThumbnailKind| format!("/thumbnails/{}/{}", entry.file_name, kind.as_str());
 
         return VideoData {
-            file_name: file_name,
-            video_created: formated_date,
-            video_duration: duration,
+            poster_url: thumbnail_url(ThumbnailKind::Poster),
+            preview_url: thumbnail_url(ThumbnailKind::Preview),
+            contact_sheet_url: thumbnail_url(ThumbnailKind::ContactSheet),
+            sprites_vtt_url: format!(
+                "/file/{}?filename={}",
+                sprites::SPRITE_VTT_NAME,
+                entry.file_name
+            ),
+            file_name: entry.file_name,
+            video_created: local_rfc3339(entry.recorded),
+            video_duration: entry.duration,
+            width: entry.width,
+            height: entry.height,
+            size_bytes: entry.size_bytes,
+            codec: entry.codec,
+            trigger: entry.trigger,
+            metadata: entry.metadata,
+            tags: entry.tags,
+            starred: entry.starred,
+            notes: entry.notes,
+            protected: entry.protected,
         };
     }
 }
@@ -157,11 +261,18 @@ pub async fn shutdown_device(motion_detector: State<Arc<MotionDetector>>) -> Res
 }
 
 pub async fn get_current_cam_status(motion_detector: State<Arc<MotionDetector>>) -> Response {
+    let degraded = motion_detector
+        .degraded
+        .read()
+        .unwrap()
+        .as_ref()
+        .map(|reason| format!(" (Degraded: {reason})"))
+        .unwrap_or_default();
     match &*motion_detector.cam_type.read().unwrap() {
         Some(cam_type) => {
             return CameraResponse {
                 status: StatusCode::OK,
-                message: cam_type.to_string(),
+                message: format!("{cam_type}{degraded}"),
             }
             .into_response();
         }
@@ -176,68 +287,447 @@ pub async fn get_current_cam_status(motion_detector: State<Arc<MotionDetector>>)
     };
     return CameraResponse {
         status: StatusCode::OK,
-        message: "Inactive".to_string(),
+        message: format!("Inactive{degraded}"),
     }
     .into_response();
 }
 
-pub async fn download(file_name: Query<FileName>) -> Response {
-    let file_name = &file_name.filename;
-    let file_dir = var("VIDEO_SAVE_PATH").unwrap_or("/home".to_string());
-    let formated_path = format!("{file_dir}/{file_name}");
-    FileStream::<ReaderStream<File>>::from_path(formated_path)
+pub async fn get_recent_events(motion_detector: State<Arc<MotionDetector>>) -> Response {
+    let events = motion_detector.events.recent();
+    return (StatusCode::OK, to_string(&events).unwrap()).into_response();
+}
+
+/// Resolves a recording name from a request through the store, or the error response to send.
+fn resolve_recording(store: &RecordingStore, file_name: &str) -> Result<PathBuf, Response> {
+    store
+        .resolve(file_name)
+        .map_err(|err| store_error_response(file_name, err))
+}
+
+fn store_error_response(file_name: &str, err: StoreError) -> Response {
+    let status = match &err {
+        StoreError::InvalidName => StatusCode::BAD_REQUEST,
+        StoreError::NotFound | StoreError::NotARecording => StatusCode::NOT_FOUND,
+        StoreError::Io(io_err) => {
+            error!("Error resolving recording {file_name}, error: {io_err}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    };
+    (status, format!("{file_name}: {err}")).into_response()
+}
+
+pub async fn download(store: State<Arc<RecordingStore>>, file_name: Query<FileName>) -> Response {
+    let path = match resolve_recording(&store, &file_name.filename) {
+        Ok(path) => path,
+        Err(response) => return response,
+    };
+    FileStream::<ReaderStream<File>>::from_path(path)
         .await
         .map_err(|e| (StatusCode::NOT_FOUND, format!("File not found: {e}")))
         .into_response()
 }
 
-pub async fn stream(file_name: Query<FileName>, headers: HeaderMap) -> Response {
-    let range_header = headers
-        .get(header::RANGE)
-        .and_then(|value| value.to_str().ok());
+/// Serves a file with range and conditional request support, as players seek by requesting ranges.
+async fn ranged_file_response(path: &Path, method: &Method, headers: &HeaderMap) -> Response {
+    match RangedFile::open(path, "video/mp4").await {
+        Ok(file) => file.response(method, headers).await,
+        Err(err) => (StatusCode::NOT_FOUND, format!("File not found: {err}")).into_response(),
+    }
+}
+
+pub async fn stream(
+    store: State<Arc<RecordingStore>>,
+    file_name: Query<FileName>,
+    method: Method,
+    headers: HeaderMap,
+) -> Response {
+    let file_name = &file_name.filename;
+    match store.resolve(file_name) {
+        Ok(path) => ranged_file_response(&path, &method, &headers).await,
+        // recordings offloaded with their local copy deleted are streamed from the backend
+        Err(StoreError::NotFound) => match store.remote() {
+            Some(backend) => {
+                remote_file_response(backend.clone(), file_name, &method, &headers).await
+            }
+            None => store_error_response(file_name, StoreError::NotFound),
+        },
+        Err(err) => store_error_response(file_name, err),
+    }
+}
 
-    let ranges = http_range_header::parse_range_header(range_header.unwrap());
-    let valid_ranges = match ranges {
-        Ok(range) => range,
+async fn remote_file_response(
+    backend: Arc<dyn StorageBackend>,
+    file_name: &str,
+    method: &Method,
+    headers: &HeaderMap,
+) -> Response {
+    match RangedFile::remote(backend, file_name, "video/mp4").await {
+        Ok(Some(file)) => file.response(method, headers).await,
+        Ok(None) => store_error_response(file_name, StoreError::NotFound),
         Err(err) => {
-            error!("Error parsing range, error: {err}");
-            return (StatusCode::RANGE_NOT_SATISFIABLE, "Invalid Range").into_response();
+            error!("Error reading {file_name} from storage backend, error: {err}");
+            (
+                StatusCode::BAD_GATEWAY,
+                format!("{file_name}: storage backend unavailable"),
+            )
+                .into_response()
         }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ProxyRequest {
+    filename: String,
+    quality: Option<ProxyQuality>,
+}
+
+/// Serves a lower bitrate copy of a recording, queuing a transcode job if it hasn't been made yet.
+pub async fn stream_proxy(
+    store: State<Arc<RecordingStore>>,
+    proxies: State<Arc<ProxyCache>>,
+    jobs: State<Arc<JobManager>>,
+    session: Session,
+    request: Query<ProxyRequest>,
+    method: Method,
+    headers: HeaderMap,
+) -> Response {
+    let file_name = &request.filename;
+    let quality = request.quality.unwrap_or(ProxyQuality::Low);
+    let source = match resolve_recording(&store, file_name) {
+        Ok(path) => path,
+        Err(response) => return response,
     };
-    let first_range = valid_ranges.ranges.get(0).unwrap();
-    let start = match first_range.start {
-        StartPosition::FromLast(val) => val,
-        StartPosition::Index(val) => val,
+    if let Some(path) = proxies.cached(file_name, quality) {
+        return ranged_file_response(&path, &method, &headers).await;
+    }
+
+    let job_id = proxies.generate(&jobs, &session_actor(&session), source, quality);
+    return (
+        StatusCode::ACCEPTED,
+        [(header::RETRY_AFTER, "5")],
+        to_string(&JobCreated { job_id }).unwrap(),
+    )
+        .into_response();
+}
+
+/// Serves the playlist or a segment of a recording's HLS package, packaging the recording if needed.
+pub async fn stream_hls(
+    store: State<Arc<RecordingStore>>,
+    hls: State<Arc<HlsCache>>,
+    extract::Path((file_name, segment)): extract::Path<(String, String)>,
+) -> Response {
+    let source = match resolve_recording(&store, &file_name) {
+        Ok(path) => path,
+        Err(response) => return response,
     };
-    let end = match first_range.end {
-        EndPosition::Index(val) => val,
-        EndPosition::LastByte => 0,
+    let Some(content_type) = hls::content_type(&segment) else {
+        return (StatusCode::NOT_FOUND, "Unknown HLS file").into_response();
     };
 
+    let dir = match hls.packaged(&source).await {
+        Ok(dir) => dir,
+        Err(err) => {
+            error!("Error packaging {file_name} for HLS, error: {err}");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Error packaging recording, {err}"),
+            )
+                .into_response();
+        }
+    };
+    match tokio::fs::read(dir.join(&segment)).await {
+        Ok(contents) => (
+            StatusCode::OK,
+            [(header::CONTENT_TYPE, content_type)],
+            contents,
+        )
+            .into_response(),
+        Err(err) => (StatusCode::NOT_FOUND, format!("HLS file not found: {err}")).into_response(),
+    }
+}
+
+/// Serves the thumbnail sprite sheet of a recording or the WebVTT file indexing it, making them
+/// if needed. Both sit next to `/file` so the sheet URLs in the WebVTT file resolve relative to it.
+pub async fn stream_sprites(
+    store: State<Arc<RecordingStore>>,
+    sprites: State<Arc<SpriteCache>>,
+    extract::Path(sprite_file): extract::Path<String>,
+    file_name: Query<FileName>,
+    method: Method,
+    headers: HeaderMap,
+) -> Response {
     let file_name = &file_name.filename;
-    let file_dir = var("VIDEO_SAVE_PATH").unwrap_or("/home".to_string());
-    let formated_path = format!("{file_dir}/{file_name}");
-    FileStream::<ReaderStream<File>>::try_range_response(formated_path, start, end)
-        .await
-        .map_err(|e| (StatusCode::NOT_FOUND, format!("File not found: {e}")))
+    let source = match resolve_recording(&store, file_name) {
+        Ok(path) => path,
+        Err(response) => return response,
+    };
+    let Some(content_type) = sprites::content_type(&sprite_file) else {
+        return (StatusCode::NOT_FOUND, "Unknown sprite file").into_response();
+    };
+
+    let dir = match sprites.sprites(&source, file_name).await {
+        Ok(dir) => dir,
+        Err(err) => {
+            error!("Error making sprite sheet of {file_name}, error: {err}");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Error making sprite sheet, {err}"),
+            )
+                .into_response();
+        }
+    };
+    match RangedFile::open(dir.join(&sprite_file), content_type).await {
+        Ok(file) => file.response(&method, &headers).await,
+        Err(err) => (StatusCode::NOT_FOUND, format!("File not found: {err}")).into_response(),
+    }
+}
+
+/// Serves a poster, animated preview or contact sheet of a recording, making it first if needed.
+pub async fn stream_thumbnail(
+    store: State<Arc<RecordingStore>>,
+    thumbnails: State<Arc<ThumbnailCache>>,
+    extract::Path((file_name, kind)): extract::Path<(String, String)>,
+    method: Method,
+    headers: HeaderMap,
+) -> Response {
+    let source = match resolve_recording(&store, &file_name) {
+        Ok(path) => path,
+        Err(response) => return response,
+    };
+    let kind = match kind.parse::<ThumbnailKind>() {
+        Ok(kind) => kind,
+        Err(err) => return (StatusCode::NOT_FOUND, err).into_response(),
+    };
+
+    let path = match thumbnails.image(&source, kind).await {
+        Ok(path) => path,
+        Err(err) => {
+            error!(
+                "Error making {} of {file_name}, error: {err}",
+                kind.as_str()
+            );
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Error making thumbnail, {err}"),
+            )
+                .into_response();
+        }
+    };
+    match RangedFile::open(&path, thumbnails.content_type(kind)).await {
+        Ok(file) => file.response(&method, &headers).await,
+        Err(err) => (StatusCode::NOT_FOUND, format!("File not found: {err}")).into_response(),
+    }
+}
+
+/// Serves the rolling live playlist and its segments, the fallback for clients that can't use WebRTC.
+pub async fn stream_live_hls(
+    motion_detector: State<Arc<MotionDetector>>,
+    extract::Path(segment): extract::Path<String>,
+) -> Response {
+    let Some(content_type) = live_hls::content_type(&segment) else {
+        return (StatusCode::NOT_FOUND, "Unknown HLS file").into_response();
+    };
+    if !matches!(
+        *motion_detector.cam_type.read().unwrap(),
+        Some(CameraType::Stream)
+    ) {
+        return (StatusCode::SERVICE_UNAVAILABLE, "Camera is not streaming").into_response();
+    }
+    match tokio::fs::read(live_hls::live_hls_path().join(&segment)).await {
+        Ok(contents) => (
+            StatusCode::OK,
+            [
+                (header::CONTENT_TYPE, content_type),
+                // the playlist changes with every segment
+                (header::CACHE_CONTROL, "no-cache"),
+            ],
+            contents,
+        )
+            .into_response(),
+        Err(err) => (StatusCode::NOT_FOUND, format!("HLS file not found: {err}")).into_response(),
+    }
+}
+
+/// Waits for the camera's next frame, starting an on demand capture if the camera is idle.
+async fn next_frame(frames: &FrameHub) -> Option<Bytes> {
+    let mut receiver = frames.subscribe();
+    if let Err(err) = frames.ensure_frames() {
+        error!("Unable to start frame capture, error: {err}");
+        return None;
+    }
+    match timeout(FRAME_TIMEOUT, receiver.changed()).await {
+        Ok(Ok(_)) => receiver.borrow().clone(),
+        _ => None,
+    }
+}
+
+pub async fn snapshot(motion_detector: State<Arc<MotionDetector>>) -> Response {
+    let frames = &motion_detector.frames;
+    let frame = match frames.latest() {
+        Some(frame) => Some(frame),
+        None => next_frame(frames).await,
+    };
+    match frame {
+        Some(frame) => (
+            StatusCode::OK,
+            [
+                (header::CONTENT_TYPE, "image/jpeg"),
+                (header::CACHE_CONTROL, "no-store"),
+            ],
+            frame,
+        )
+            .into_response(),
+        None => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "No frame available from the camera",
+        )
+            .into_response(),
+    }
+}
+
+fn mjpeg_part(frame: &Bytes) -> Bytes {
+    let mut part = format!(
+        "--{MJPEG_BOUNDARY}\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n",
+        frame.len()
+    )
+    .into_bytes();
+    part.extend_from_slice(frame);
+    part.extend_from_slice(b"\r\n");
+    Bytes::from(part)
+}
+
+/// Streams camera frames as `multipart/x-mixed-replace`, the format `<img>` tags play as video.
+pub async fn mjpeg_stream(motion_detector: State<Arc<MotionDetector>>) -> Response {
+    let frames = motion_detector.frames.clone();
+    let receiver = frames.subscribe();
+    if let Err(err) = frames.ensure_frames() {
+        error!("Unable to start frame capture, error: {err}");
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Unable to start the camera",
+        )
+            .into_response();
+    }
+
+    let parts =
+        futures_util::stream::unfold((frames, receiver), |(frames, mut receiver)| async move {
+            loop {
+                match timeout(FRAME_TIMEOUT, receiver.changed()).await {
+                    Ok(Ok(_)) => {
+                        let Some(frame) = receiver.borrow_and_update().clone() else {
+                            continue;
+                        };
+                        return Some((
+                            Ok::<_, std::io::Error>(mjpeg_part(&frame)),
+                            (frames, receiver),
+                        ));
+                    }
+                    Ok(Err(_)) => return None,
+                    // the pipeline feeding frames stopped, between recordings for example
+                    Err(_) => {
+                        if let Err(err) = frames.ensure_frames() {
+                            error!("Unable to restart frame capture, error: {err}");
+                            return None;
+                        }
+                    }
+                }
+            }
+        });
+
+    (
+        StatusCode::OK,
+        [
+            (
+                header::CONTENT_TYPE,
+                format!("multipart/x-mixed-replace; boundary={MJPEG_BOUNDARY}"),
+            ),
+            (header::CACHE_CONTROL, "no-store".to_string()),
+        ],
+        Body::from_stream(parts),
+    )
         .into_response()
 }
 
-pub async fn get_all_videos_data(videos_since: Query<VideosSince>) -> Response {
-    let file_dir = var("VIDEO_SAVE_PATH").unwrap_or("/home".to_string());
-    let pattern = format!("{file_dir}/*.mp4");
-    let videos_date = DateTime::from_timestamp_millis(videos_since.timestamp).unwrap();
+pub async fn get_all_videos_data(
+    catalog: State<Arc<Catalog>>,
+    videos_since: Query<VideosSince>,
+) -> Response {
+    // date inputs give midnight UTC of the picked day, which is then bucketed in local time
+    let Some(videos_date) = DateTime::from_timestamp_millis(videos_since.timestamp) else {
+        return (StatusCode::BAD_REQUEST, "Invalid timestamp").into_response();
+    };
+    let (day_start, day_end) = day_bounds(videos_date.date_naive());
 
-    match ffmpeg_next::init() {
-        Ok(()) => {}
+    let catalog = catalog.0.clone();
+    let entries = spawn_blocking(move || catalog.recorded_between(day_start, day_end))
+        .await
+        .unwrap_or_else(|err| Err(err.into()));
+    let entries = match entries {
+        Ok(entries) => entries,
         Err(err) => {
-            error!("Encountered error: {err} trying to init ffmpeg");
-            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Error encountered trying to find videos, {err}"),
+            )
+                .into_response();
         }
     };
 
-    let paths = match glob(&pattern) {
-        Ok(paths) => paths,
+    let video_names: Vec<VideoData> = entries.into_iter().map(VideoData::new).collect();
+    return (StatusCode::OK, to_string(&video_names).unwrap()).into_response();
+}
+
+/// Finds recordings matching the query's filters, a page at a time.
+pub async fn search_videos(catalog: State<Arc<Catalog>>, search: Query<VideoSearch>) -> Response {
+    if let (Some(from), Some(to)) = (search.from, search.to) {
+        if from >= to {
+            return (StatusCode::BAD_REQUEST, "from must be before to").into_response();
+        }
+    }
+    if let (Some(min), Some(max)) = (search.min_duration, search.max_duration) {
+        if min > max {
+            return (
+                StatusCode::BAD_REQUEST,
+                "min_duration must not be more than max_duration",
+            )
+                .into_response();
+        }
+    }
+    let tags: Vec<String> = search
+        .tags
+        .as_deref()
+        .map(|tags| tags.split(',').map(String::from).collect())
+        .unwrap_or_default();
+    let tags = match validate_tags(&tags) {
+        Ok(tags) => tags,
+        Err(message) => return (StatusCode::BAD_REQUEST, message).into_response(),
+    };
+    let cursor = match search.cursor.as_deref().map(SearchCursor::decode) {
+        Some(Ok(cursor)) => Some(cursor),
+        Some(Err(err)) => return (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+        None => None,
+    };
+    let limit = search
+        .limit
+        .unwrap_or(DEFAULT_SEARCH_LIMIT)
+        .clamp(1, MAX_SEARCH_LIMIT);
+
+    let filter = SearchFilter {
+        from: search.from.map(|from| from.timestamp()),
+        to: search.to.map(|to| to.timestamp()),
+        min_duration: search.min_duration,
+        max_duration: search.max_duration,
+        trigger: search.trigger.clone(),
+        tags,
+        sort: search.sort.unwrap_or_default(),
+        order: search.order.unwrap_or_default(),
+    };
+    let catalog = catalog.0.clone();
+    let page = spawn_blocking(move || catalog.search(&filter, cursor.as_ref(), limit))
+        .await
+        .unwrap_or_else(|err| Err(err.into()));
+    let page = match page {
+        Ok(page) => page,
         Err(err) => {
             return (
                 StatusCode::INTERNAL_SERVER_ERROR,
@@ -247,56 +737,555 @@ pub async fn get_all_videos_data(videos_since: Query<VideosSince>) -> Response {
         }
     };
 
-    let mut video_names = Vec::new();
-    for file in paths.filter_map(Result::ok) {
-        let file_created: DateTime<Utc> = file.metadata().unwrap().created().unwrap().into();
-        if file_created.date_naive() == videos_date.date_naive() {
-            let video_data = VideoData::new(file_created, file.as_path());
-            video_names.push(video_data);
+    let results = SearchResults {
+        videos: page.entries.into_iter().map(VideoData::new).collect(),
+        total: page.total,
+        next_cursor: page.next_cursor.map(|cursor| cursor.encode()),
+    };
+    (StatusCode::OK, to_string(&results).unwrap()).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct ActivityRange {
+    /// Local dates, both inclusive, defaulting to the last 30 days.
+    from: Option<NaiveDate>,
+    to: Option<NaiveDate>,
+}
+
+/// Counts of motion and recordings by hour, weekday and day, for the dashboard's charts.
+pub async fn activity_stats(catalog: State<Arc<Catalog>>, range: Query<ActivityRange>) -> Response {
+    let to = range.to.unwrap_or_else(|| local_now().date_naive());
+    let from = range
+        .from
+        .unwrap_or(to - TimeDelta::days(DEFAULT_STATS_DAYS - 1));
+    if from > to {
+        return (StatusCode::BAD_REQUEST, "from must not be after to").into_response();
+    }
+    if (to - from).num_days() >= MAX_STATS_DAYS {
+        return (
+            StatusCode::BAD_REQUEST,
+            format!("Statistics cover at most {MAX_STATS_DAYS} days"),
+        )
+            .into_response();
+    }
+
+    let catalog = catalog.0.clone();
+    match spawn_blocking(move || ActivityStats::collect(&catalog, from, to)).await {
+        Ok(Ok(stats)) => (StatusCode::OK, to_string(&stats).unwrap()).into_response(),
+        Ok(Err(err)) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Error gathering activity statistics, {err}"),
+        )
+            .into_response(),
+        Err(err) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Gathering activity statistics did not finish, {err}"),
+        )
+            .into_response(),
+    }
+}
+
+pub async fn rebuild_catalog(
+    catalog: State<Arc<Catalog>>,
+    store: State<Arc<RecordingStore>>,
+) -> Response {
+    let catalog = catalog.0.clone();
+    let root = store.root().to_path_buf();
+    match spawn_blocking(move || catalog.rebuild(&root, true)).await {
+        Ok(Ok(indexed)) => {
+            (StatusCode::OK, format!("Indexed {indexed} recordings")).into_response()
         }
+        Ok(Err(err)) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Error rebuilding catalog, {err}"),
+        )
+            .into_response(),
+        Err(err) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Catalog rebuild did not finish, {err}"),
+        )
+            .into_response(),
     }
-    return (StatusCode::OK, to_string(&video_names).unwrap()).into_response();
 }
 
-pub async fn start_download(
-    thread_pool: State<Arc<ThreadPool>>,
-    last_download: Query<u64>,
+const MAX_TAG_LENGTH: usize = 64;
+
+fn validate_tags(tags: &[String]) -> Result<Vec<String>, String> {
+    let mut cleaned = Vec::new();
+    for tag in tags {
+        let tag = tag.trim();
+        if tag.is_empty() {
+            continue;
+        }
+        if tag.chars().count() > MAX_TAG_LENGTH {
+            return Err(format!(
+                "Tag is longer than {MAX_TAG_LENGTH} characters: {tag}"
+            ));
+        }
+        if tag.chars().any(char::is_control) {
+            return Err(format!("Tag contains control characters: {tag:?}"));
+        }
+        cleaned.push(tag.to_string());
+    }
+    Ok(cleaned)
+}
+
+pub async fn annotate_video(
+    catalog: State<Arc<Catalog>>,
+    session: Session,
+    file_name: Query<FileName>,
+    Json(mut annotation): Json<Annotation>,
 ) -> Response {
-    let file_dir = var("VIDEO_SAVE_PATH").unwrap_or("/home".to_string());
-    let pattern = format!("{file_dir}/*.mp4");
-    let time_delta = UNIX_EPOCH + Duration::from_secs(last_download.0);
-    // handle possible errors here return bad resp
-    let paths = match glob(&pattern) {
-        Ok(paths) => paths,
+    if let Some(tags) = &annotation.tags {
+        match validate_tags(tags) {
+            Ok(tags) => annotation.tags = Some(tags),
+            Err(message) => return (StatusCode::BAD_REQUEST, message).into_response(),
+        }
+    }
+
+    let protected = annotation.protected;
+    let catalog = catalog.0.clone();
+    let name = file_name.filename.clone();
+    let entry = spawn_blocking(move || catalog.annotate(&name, &annotation))
+        .await
+        .unwrap_or_else(|err| Err(err.into()));
+    let entry = match entry {
+        Ok(Some(entry)) => entry,
+        Ok(None) => {
+            return (
+                StatusCode::NOT_FOUND,
+                format!("No recording named {}", file_name.filename),
+            )
+                .into_response();
+        }
         Err(err) => {
+            error!("Error annotating {}, error: {err}", file_name.filename);
             return (
                 StatusCode::INTERNAL_SERVER_ERROR,
-                format!("Error encountered trying to find videos, {err}"),
+                "Error saving recording details",
             )
                 .into_response();
         }
     };
-    for file in paths.filter_map(Result::ok) {
-        let file_created = file.metadata().unwrap().created().unwrap();
-        debug!("found file {}", &file.display());
-        if file_created >= time_delta {
-            debug!("adding file to queue");
-            thread_pool.queue_file(file).await;
+
+    if let Some(protected) = protected {
+        let actor = session_actor(&session);
+        let action = if protected { "protect" } else { "unprotect" };
+        audit::record(
+            &actor,
+            action,
+            &video_save_path().join(&file_name.filename),
+            "set through the API",
+        );
+    }
+    return (StatusCode::OK, to_string(&VideoData::new(entry)).unwrap()).into_response();
+}
+
+fn session_actor(session: &Session) -> String {
+    session
+        .id()
+        .map(|id| format!("session:{id}"))
+        .unwrap_or("session:unknown".to_string())
+}
+
+fn trash_status(outcome: &anyhow::Result<TrashOutcome>) -> (StatusCode, &'static str) {
+    match outcome {
+        Ok(TrashOutcome::Trashed) => (StatusCode::OK, "trashed"),
+        Ok(TrashOutcome::Restored) => (StatusCode::OK, "restored"),
+        Ok(TrashOutcome::Protected) => (StatusCode::CONFLICT, "protected"),
+        Ok(TrashOutcome::NameTaken) => (StatusCode::CONFLICT, "name_taken"),
+        Ok(TrashOutcome::NotFound) => (StatusCode::NOT_FOUND, "not_found"),
+        Ok(TrashOutcome::InvalidName) => (StatusCode::BAD_REQUEST, "invalid_name"),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "error"),
+    }
+}
+
+pub async fn delete_video(
+    catalog: State<Arc<Catalog>>,
+    session: Session,
+    file_name: Query<FileName>,
+) -> Response {
+    let catalog = catalog.0.clone();
+    let actor = session_actor(&session);
+    let name = file_name.filename.clone();
+    let outcome = spawn_blocking(move || trash_recording(&catalog, &name, &actor))
+        .await
+        .unwrap_or_else(|err| Err(err.into()));
+    if let Err(err) = &outcome {
+        error!("Error deleting {}, error: {err}", file_name.filename);
+    }
+    let (status, message) = trash_status(&outcome);
+    return (status, message).into_response();
+}
+
+pub async fn delete_videos(
+    catalog: State<Arc<Catalog>>,
+    session: Session,
+    Json(file_names): Json<FileNames>,
+) -> Response {
+    let catalog = catalog.0.clone();
+    let actor = session_actor(&session);
+    let results = spawn_blocking(move || {
+        file_names
+            .file_names
+            .into_iter()
+            .map(|file_name| {
+                let outcome = trash_recording(&catalog, &file_name, &actor);
+                if let Err(err) = &outcome {
+                    error!("Error deleting {file_name}, error: {err}");
+                }
+                DeleteResult {
+                    status: trash_status(&outcome).1,
+                    file_name,
+                }
+            })
+            .collect::<Vec<_>>()
+    })
+    .await;
+    match results {
+        Ok(results) => (StatusCode::OK, to_string(&results).unwrap()).into_response(),
+        Err(err) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Delete did not finish, {err}"),
+        )
+            .into_response(),
+    }
+}
+
+pub async fn get_trash(catalog: State<Arc<Catalog>>) -> Response {
+    let catalog = catalog.0.clone();
+    let trashed = spawn_blocking(move || catalog.trashed())
+        .await
+        .unwrap_or_else(|err| Err(err.into()));
+    match trashed {
+        Ok(entries) => {
+            let videos: Vec<VideoData> = entries.into_iter().map(VideoData::new).collect();
+            (StatusCode::OK, to_string(&videos).unwrap()).into_response()
+        }
+        Err(err) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Error listing trash, {err}"),
+        )
+            .into_response(),
+    }
+}
+
+pub async fn backup_status(backup: State<Arc<Backup>>) -> Response {
+    match backup.status() {
+        Ok(status) => (StatusCode::OK, to_string(&status).unwrap()).into_response(),
+        Err(err) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Error reading backup queue, {err}"),
+        )
+            .into_response(),
+    }
+}
+
+pub async fn restore_video(
+    catalog: State<Arc<Catalog>>,
+    session: Session,
+    file_name: Query<FileName>,
+) -> Response {
+    let catalog = catalog.0.clone();
+    let actor = session_actor(&session);
+    let name = file_name.filename.clone();
+    let outcome = spawn_blocking(move || restore_recording(&catalog, &name, &actor))
+        .await
+        .unwrap_or_else(|err| Err(err.into()));
+    if let Err(err) = &outcome {
+        error!("Error restoring {}, error: {err}", file_name.filename);
+    }
+    let (status, message) = trash_status(&outcome);
+    return (status, message).into_response();
+}
+
+/// Works out which recordings an archive selection refers to, resolving each through the store.
+fn selected_recordings(
+    catalog: &Catalog,
+    store: &RecordingStore,
+    selection: &ArchiveSelection,
+) -> Result<Vec<PathBuf>, Response> {
+    let mut file_names: Vec<String> = match &selection.files {
+        Some(files) => files
+            .split(',')
+            .map(|name| name.trim().to_string())
+            .filter(|name| !name.is_empty())
+            .collect(),
+        None => Vec::new(),
+    };
+    if let Some(since) = selection.since {
+        match catalog.recorded_between(since, i64::MAX) {
+            // recordings offloaded without a local copy only live in the remote backend, so they
+            // are left out rather than failing the whole archive
+            Ok(entries) => file_names.extend(
+                entries
+                    .into_iter()
+                    .filter(|entry| {
+                        entry.offloaded_at.is_none() || store.resolve(&entry.file_name).is_ok()
+                    })
+                    .map(|entry| entry.file_name),
+            ),
+            Err(err) => {
+                return Err((
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("Error encountered trying to find videos, {err}"),
+                )
+                    .into_response());
+            }
         }
     }
-    StatusCode::OK.into_response()
+    file_names.sort();
+    file_names.dedup();
+    if file_names.is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "No recordings selected").into_response());
+    }
+    store
+        .resolve_all(&file_names)
+        .map_err(|(file_name, err)| store_error_response(&file_name, err))
 }
 
-pub async fn download_from_task(thread_pool: State<Arc<ThreadPool>>) -> Response {
-    let (task_running, stream) = thread_pool.get_result().await;
-    if !task_running {
+async fn archive_response(paths: Vec<PathBuf>) -> Response {
+    let mut entries = Vec::with_capacity(paths.len());
+    for path in paths {
+        match ArchiveEntry::from_path(path.clone()).await {
+            Ok(entry) => entries.push(entry),
+            Err(err) => {
+                return (
+                    StatusCode::NOT_FOUND,
+                    format!("Recording {} not found: {err}", path.display()),
+                )
+                    .into_response();
+            }
+        }
+    }
+    TarArchive::new(entries).into_response()
+}
+
+pub async fn download_archive(
+    catalog: State<Arc<Catalog>>,
+    store: State<Arc<RecordingStore>>,
+    selection: Query<ArchiveSelection>,
+) -> Response {
+    match selected_recordings(&catalog, &store, &selection) {
+        Ok(paths) => archive_response(paths).await,
+        Err(response) => response,
+    }
+}
+
+#[derive(Serialize)]
+struct JobCreated {
+    job_id: JobId,
+}
+
+/// Queues a download of the selected recordings, fetched later from `/jobs/{id}/result`.
+pub async fn start_download_job(
+    jobs: State<Arc<JobManager>>,
+    catalog: State<Arc<Catalog>>,
+    store: State<Arc<RecordingStore>>,
+    session: Session,
+    selection: Query<ArchiveSelection>,
+) -> Response {
+    let paths = match selected_recordings(&catalog, &store, &selection) {
+        Ok(paths) => paths,
+        Err(response) => return response,
+    };
+    let owner = session_actor(&session);
+    let job_id = jobs.spawn(&owner, "download", move |job| async move {
+        let total = paths.len();
+        for (done, path) in paths.iter().enumerate() {
+            if job.is_cancelled() {
+                anyhow::bail!("cancelled");
+            }
+            if !tokio::fs::try_exists(path).await? {
+                anyhow::bail!("recording {} not found", path.display());
+            }
+            job.set_progress((done + 1) as f32 / total as f32);
+        }
+        Ok(JobOutput::Files(paths))
+    });
+    return (
+        StatusCode::ACCEPTED,
+        to_string(&JobCreated { job_id }).unwrap(),
+    )
+        .into_response();
+}
+
+#[derive(Deserialize)]
+pub struct ExportRequest {
+    filename: String,
+    /// Offsets into the recording, in seconds.
+    start: f64,
+    end: f64,
+    /// Re-encode so the clip starts exactly at `start` rather than at the keyframe before it.
+    #[serde(default)]
+    accurate: bool,
+    /// Keep the clip as a new protected recording instead of only offering it for download.
+    #[serde(default)]
+    save: bool,
+}
+
+/// Starts exporting part of a recording, the clip is fetched from the job's result once done.
+///
+/// Accurate exports use the encoder set with `EXPORT_ENCODER`, defaulting to `libx264`.
+pub async fn start_export_job(
+    jobs: State<Arc<JobManager>>,
+    catalog: State<Arc<Catalog>>,
+    store: State<Arc<RecordingStore>>,
+    session: Session,
+    Json(request): Json<ExportRequest>,
+) -> Response {
+    let source = match resolve_recording(&store, &request.filename) {
+        Ok(source) => source,
+        Err(response) => return response,
+    };
+    if !(request.start >= 0.0 && request.end > request.start) {
         return (
             StatusCode::BAD_REQUEST,
-            "No task is currently running and no results are still pending download.",
+            "start must be at least 0 and before end",
         )
             .into_response();
     }
-    stream.unwrap().into_response()
+    let known = {
+        let catalog = catalog.0.clone();
+        let name = request.filename.clone();
+        spawn_blocking(move || catalog.get(&name)).await
+    };
+    if let Ok(Ok(Some(entry))) = known {
+        if request.start >= entry.duration {
+            return (
+                StatusCode::BAD_REQUEST,
+                format!(
+                    "start is past the end of the {:.1}s recording",
+                    entry.duration
+                ),
+            )
+                .into_response();
+        }
+    }
+
+    let mode = if request.accurate {
+        TrimMode::Accurate {
+            encoder: var("EXPORT_ENCODER").unwrap_or("libx264".to_string()),
+        }
+    } else {
+        TrimMode::KeyframeCopy
+    };
+    let job_id = spawn_export(
+        &jobs,
+        catalog.0.clone(),
+        &session_actor(&session),
+        source,
+        (request.start, request.end),
+        mode,
+        request.save,
+    );
+    (
+        StatusCode::ACCEPTED,
+        to_string(&JobCreated { job_id }).unwrap(),
+    )
+        .into_response()
+}
+
+#[derive(Deserialize)]
+pub struct SummaryRequest {
+    date: NaiveDate,
+    /// How many times faster than real time clips play, defaults to `SUMMARY_SPEED`.
+    speed: Option<f64>,
+    /// Whether each clip is preceded by a caption card, defaults to `SUMMARY_CAPTIONS`.
+    captions: Option<bool>,
+}
+
+/// Starts making the summary video of a day, it is listed with the recordings once done.
+pub async fn start_summary_job(
+    jobs: State<Arc<JobManager>>,
+    catalog: State<Arc<Catalog>>,
+    store: State<Arc<RecordingStore>>,
+    session: Session,
+    Json(request): Json<SummaryRequest>,
+) -> Response {
+    let mut options = SummaryOptions::from_env();
+    if let Some(speed) = request.speed {
+        if !(1.0..=MAX_SPEED).contains(&speed) {
+            return (
+                StatusCode::BAD_REQUEST,
+                format!("speed must be between 1 and {MAX_SPEED}"),
+            )
+                .into_response();
+        }
+        options.speed = speed;
+    }
+    if let Some(captions) = request.captions {
+        options.captions = captions;
+    }
+    let job_id = spawn_summary(
+        &jobs,
+        catalog.0.clone(),
+        store.0.clone(),
+        &session_actor(&session),
+        request.date,
+        options,
+    );
+    (
+        StatusCode::ACCEPTED,
+        to_string(&JobCreated { job_id }).unwrap(),
+    )
+        .into_response()
+}
+
+pub async fn list_jobs(jobs: State<Arc<JobManager>>, session: Session) -> Response {
+    let infos = jobs.list(&session_actor(&session));
+    return (StatusCode::OK, to_string(&infos).unwrap()).into_response();
+}
+
+pub async fn get_job(
+    jobs: State<Arc<JobManager>>,
+    session: Session,
+    extract::Path(job_id): extract::Path<JobId>,
+) -> Response {
+    match jobs.get(job_id, &session_actor(&session)) {
+        Some(info) => (StatusCode::OK, to_string(&info).unwrap()).into_response(),
+        None => (StatusCode::NOT_FOUND, "Job not found").into_response(),
+    }
+}
+
+pub async fn cancel_job(
+    jobs: State<Arc<JobManager>>,
+    session: Session,
+    extract::Path(job_id): extract::Path<JobId>,
+) -> Response {
+    if jobs.cancel(job_id, &session_actor(&session)) {
+        (StatusCode::ACCEPTED, "Cancellation requested").into_response()
+    } else {
+        (StatusCode::NOT_FOUND, "Job not found").into_response()
+    }
+}
+
+pub async fn get_job_result(
+    jobs: State<Arc<JobManager>>,
+    session: Session,
+    extract::Path(job_id): extract::Path<JobId>,
+) -> Response {
+    match jobs.output(job_id, &session_actor(&session)) {
+        Ok(JobOutput::File(path)) => match FileStream::<ReaderStream<File>>::from_path(&path).await
+        {
+            Ok(stream) => stream.into_response(),
+            Err(err) => (
+                StatusCode::NOT_FOUND,
+                format!("Job result is no longer available: {err}"),
+            )
+                .into_response(),
+        },
+        Ok(JobOutput::Files(paths)) => archive_response(paths).await,
+        Err(JobLookupError::NotFound) => (StatusCode::NOT_FOUND, "Job not found").into_response(),
+        Err(JobLookupError::NotFinished(status)) => {
+            (StatusCode::CONFLICT, format!("Job is {}", status.as_str())).into_response()
+        }
+        Err(JobLookupError::NoOutput(status)) => (
+            StatusCode::GONE,
+            format!("Job {} without a result", status.as_str()),
+        )
+            .into_response(),
+    }
 }
 
 pub async fn get_turn_config() -> Response {
@@ -0,0 +1,76 @@
+use super::jobs::JobManager;
+use crate::{
+    media::{hls::HlsCache, proxy::ProxyCache, sprites::SpriteCache, thumbnails::ThumbnailCache},
+    motion_detect::gpio::MotionDetector,
+    storage::{backup::Backup, catalog::Catalog, store::RecordingStore},
+};
+use axum::extract::FromRef;
+use std::sync::Arc;
+
+/// Shared state handed to every route, handlers extract just the parts they need.
+#[derive(Clone)]
+pub struct AppState {
+    pub motion_detector: Arc<MotionDetector>,
+    pub jobs: Arc<JobManager>,
+    pub catalog: Arc<Catalog>,
+    pub store: Arc<RecordingStore>,
+    pub proxies: Arc<ProxyCache>,
+    pub hls: Arc<HlsCache>,
+    pub thumbnails: Arc<ThumbnailCache>,
+    pub sprites: Arc<SpriteCache>,
+    pub backup: Arc<Backup>,
+}
+
+impl FromRef<AppState> for Arc<MotionDetector> {
+    fn from_ref(state: &AppState) -> Self {
+        state.motion_detector.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<JobManager> {
+    fn from_ref(state: &AppState) -> Self {
+        state.jobs.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<Catalog> {
+    fn from_ref(state: &AppState) -> Self {
+        state.catalog.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<RecordingStore> {
+    fn from_ref(state: &AppState) -> Self {
+        state.store.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<ProxyCache> {
+    fn from_ref(state: &AppState) -> Self {
+        state.proxies.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<HlsCache> {
+    fn from_ref(state: &AppState) -> Self {
+        state.hls.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<ThumbnailCache> {
+    fn from_ref(state: &AppState) -> Self {
+        state.thumbnails.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<SpriteCache> {
+    fn from_ref(state: &AppState) -> Self {
+        state.sprites.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<Backup> {
+    fn from_ref(state: &AppState) -> Self {
+        state.backup.clone()
+    }
+}
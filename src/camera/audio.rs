@@ -0,0 +1,172 @@
+use std::{env::var, fmt::Display};
+
+/// Local port ffmpeg sends the live Opus RTP stream to for the WebRTC audio track.
+pub const AUDIO_RTP_PORT: u16 = 5006;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AudioSource {
+    /// Capture from an ALSA device such as `hw:1,0`.
+    Alsa(String),
+    /// Loop an audio file, used when testing without a microphone.
+    File(String),
+    /// Generate silence, used when testing without a microphone.
+    Null,
+}
+
+impl Display for AudioSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AudioSource::Alsa(device) => write!(f, "alsa:{device}"),
+            AudioSource::File(path) => write!(f, "file:{path}"),
+            AudioSource::Null => write!(f, "null"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AudioCodec {
+    Aac,
+    Opus,
+}
+
+impl Display for AudioCodec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AudioCodec::Aac => write!(f, "aac"),
+            AudioCodec::Opus => write!(f, "opus"),
+        }
+    }
+}
+
+impl AudioCodec {
+    fn encoder(&self) -> &'static str {
+        match self {
+            AudioCodec::Aac => "aac",
+            AudioCodec::Opus => "libopus",
+        }
+    }
+}
+
+/// Optional audio capture muxed into recordings and the live stream.
+///
+/// Read from the environment:
+/// - `AUDIO_SOURCE`: `alsa`, `file` or `null`, audio is disabled when unset.
+/// - `AUDIO_DEVICE`: ALSA device name used with `alsa`, defaults to `default`.
+/// - `AUDIO_FILE`: path of the file looped with `file`.
+/// - `AUDIO_CODEC`: `aac` or `opus` for recordings, defaults to `aac`.
+#[derive(Debug, Clone)]
+pub struct AudioConfig {
+    pub source: AudioSource,
+    pub record_codec: AudioCodec,
+}
+
+impl AudioConfig {
+    pub fn from_env() -> Option<Self> {
+        let source = match var("AUDIO_SOURCE").ok()?.to_lowercase().as_str() {
+            "alsa" => AudioSource::Alsa(var("AUDIO_DEVICE").unwrap_or("default".to_string())),
+            "file" => AudioSource::File(var("AUDIO_FILE").ok()?),
+            "null" => AudioSource::Null,
+            _ => return None,
+        };
+        let record_codec = match var("AUDIO_CODEC").map(|codec| codec.to_lowercase()) {
+            Ok(codec) if codec == "opus" => AudioCodec::Opus,
+            _ => AudioCodec::Aac,
+        };
+        Some(AudioConfig {
+            source,
+            record_codec,
+        })
+    }
+
+    /// ffmpeg arguments adding the audio source as an extra input.
+    pub fn input_args(&self) -> Vec<String> {
+        let args: Vec<&str> = match &self.source {
+            AudioSource::Alsa(device) => vec![
+                "-f",
+                "alsa",
+                "-thread_queue_size",
+                "1024",
+                "-i",
+                device.as_str(),
+            ],
+            AudioSource::File(path) => vec!["-stream_loop", "-1", "-re", "-i", path.as_str()],
+            AudioSource::Null => vec![
+                "-f",
+                "lavfi",
+                "-i",
+                "anullsrc=channel_layout=mono:sample_rate=48000",
+            ],
+        };
+        args.into_iter().map(String::from).collect()
+    }
+
+    /// ffmpeg output arguments encoding the audio input for an mp4 recording.
+    pub fn record_output_args(&self) -> Vec<String> {
+        [
+            "-c:a",
+            self.record_codec.encoder(),
+            "-b:a",
+            "128k",
+            // the audio inputs never end on their own, so stop with the camera
+            "-shortest",
+        ]
+        .into_iter()
+        .map(String::from)
+        .collect()
+    }
+
+    /// ffmpeg output arguments encoding the audio input as Opus for the WebRTC track and live playlist.
+    pub fn stream_codec_args(&self) -> Vec<String> {
+        ["-c:a", "libopus", "-b:a", "64k", "-ar", "48000"]
+            .into_iter()
+            .map(String::from)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    fn config(source: AudioSource, record_codec: AudioCodec) -> AudioConfig {
+        AudioConfig {
+            source,
+            record_codec,
+        }
+    }
+
+    #[test]
+    fn file_and_null_sources_need_no_microphone() {
+        let file = config(AudioSource::File("tone.wav".to_string()), AudioCodec::Aac);
+        assert_eq!(
+            file.input_args(),
+            ["-stream_loop", "-1", "-re", "-i", "tone.wav"]
+        );
+        let null = config(AudioSource::Null, AudioCodec::Opus);
+        assert!(!null.input_args().iter().any(|arg| arg == "alsa"));
+        assert!(null.record_output_args().contains(&"libopus".to_string()));
+    }
+
+    #[test]
+    #[ignore = "needs the ffmpeg binary"]
+    fn null_source_is_muxed_into_recording() {
+        let dir = tempfile::tempdir().unwrap();
+        for codec in [AudioCodec::Aac, AudioCodec::Opus] {
+            let audio = config(AudioSource::Null, codec);
+            let output = dir.path().join(format!("{codec}.mp4"));
+            // the same layout as `start_recording`, with a generated picture instead of the camera
+            let status = Command::new("ffmpeg")
+                .args(["-y", "-f", "lavfi", "-i", "testsrc=duration=1:rate=10"])
+                .args(audio.input_args())
+                .args(["-map", "0:v", "-map", "1:a"])
+                .args(audio.record_output_args())
+                .args(["-movflags", "faststart", "-f", "mp4"])
+                .arg(&output)
+                .status()
+                .unwrap();
+            assert!(status.success());
+            assert!(output.metadata().unwrap().len() > 0);
+        }
+    }
+}
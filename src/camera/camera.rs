@@ -1,18 +1,66 @@
+use super::{
+    audio::{AudioConfig, AUDIO_RTP_PORT},
+    frames::{frame_output_args, FrameHub},
+    live_hls,
+    overlay::OverlayConfig,
+};
+use crate::storage::{
+    mark_in_progress, metadata::CameraSettings, recording_name, video_save_path, InProgress,
+};
+use crate::timezone::local_timezone;
 use std::{
-    env::var,
-    process::{Command, Stdio},
-    time::{SystemTime, UNIX_EPOCH},
+    io,
+    path::PathBuf,
+    process::{Child, Command, ExitStatus, Stdio},
+    time::SystemTime,
 };
+use tracing::error;
+
+/// A recording in progress, `rpicam-vid` piping into an `ffmpeg` process writing `output`.
+pub struct ActiveRecording {
+    pub camera_process_id: u32,
+    pub output: PathBuf,
+    pub started: SystemTime,
+    /// When the motion that started this recording was seen, usually just before `started`.
+    pub first_motion: SystemTime,
+    /// When the sensor last reported motion during this recording.
+    pub last_motion: SystemTime,
+    pub settings: CameraSettings,
+    ffmpeg_process: Child,
+    /// Keeps retention away from `output` until the recording is stopped or dropped.
+    _in_progress: InProgress,
+}
+
+impl ActiveRecording {
+    /// Returns the exit status if `ffmpeg` has stopped even though the camera was not shut down.
+    pub fn ffmpeg_exited(&mut self) -> Option<ExitStatus> {
+        self.ffmpeg_process.try_wait().ok().flatten()
+    }
+
+    pub fn file_name(&self) -> String {
+        self.output
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default()
+    }
+
+    /// Stops the camera and waits for `ffmpeg` to finish writing the file.
+    pub fn stop(mut self) -> io::Result<ExitStatus> {
+        shutdown_cam_process(self.camera_process_id);
+        self.ffmpeg_process.wait()
+    }
+}
 
 pub fn test_initialise_camera() -> Result<std::process::Output, std::io::Error> {
     Command::new("rpicam-hello").arg("-t 100").output()
 }
 
-pub fn start_recording() -> u32 {
+pub fn start_recording(frames: &FrameHub) -> io::Result<ActiveRecording> {
+    // released when this returns early, before ffmpeg is reading frames
+    let claim = frames.claim_camera();
     let start = SystemTime::now();
-    let time = start.duration_since(UNIX_EPOCH).unwrap().as_secs();
-    let save_path = var("VIDEO_SAVE_PATH").unwrap_or("/home".to_string());
-    let output = format!("{save_path}/motion_{time:?}.mp4");
+    let output = video_save_path().join(recording_name(start));
+    let in_progress = mark_in_progress(&output);
 
     let rpicam_args = [
         "-t",
@@ -29,32 +77,65 @@ pub fn start_recording() -> u32 {
         .args(rpicam_args)
         .stdout(Stdio::piped())
         .stderr(Stdio::null())
-        .spawn()
-        .expect("Expected Camera command to succeed without error.");
+        .spawn()?;
     let camera_process_id = camera_process.id();
-    let ffmpeg_args = [
-        "-f",
-        "mpegts",
-        "-i",
-        "-",
-        "-movflags",
-        "faststart",
-        "-f",
-        "mp4",
-        output.as_str(),
-    ];
-    Command::new("ffmpeg")
+    let audio = AudioConfig::from_env();
+    let overlay_filter = OverlayConfig::from_env().and_then(|overlay| overlay.drawtext_filter());
+    let mut ffmpeg_args = to_args(&["-f", "mpegts", "-i", "-"]);
+    if let Some(audio) = &audio {
+        ffmpeg_args.extend(audio.input_args());
+        ffmpeg_args.extend(to_args(&["-map", "0:v", "-map", "1:a"]));
+    }
+    if let Some(filter) = &overlay_filter {
+        ffmpeg_args.extend(["-vf".to_string(), filter.clone()]);
+    }
+    if let Some(audio) = &audio {
+        ffmpeg_args.extend(audio.record_output_args());
+    }
+    ffmpeg_args.extend(to_args(&["-movflags", "faststart", "-f", "mp4"]));
+    let ffmpeg_process = Command::new("ffmpeg")
+        // the overlay's timestamp is drawn in ffmpeg's local time
+        .env("TZ", local_timezone().name())
         .args(ffmpeg_args)
+        .arg(&output)
+        .args(frame_output_args(overlay_filter.as_deref()))
         .stdin(Stdio::from(camera_process.stdout.unwrap()))
-        .stdout(Stdio::null())
+        .stdout(Stdio::piped())
         .stderr(Stdio::null())
-        .spawn()
-        .expect("FFMPEG video processing process completed successfully.");
+        .spawn();
+    let mut ffmpeg_process = match ffmpeg_process {
+        Ok(process) => process,
+        Err(err) => {
+            shutdown_cam_process(camera_process_id);
+            return Err(err);
+        }
+    };
 
-    return camera_process_id;
+    if let Some(stdout) = ffmpeg_process.stdout.take() {
+        frames.attach_pipeline(claim, stdout);
+    }
+
+    let settings = CameraSettings {
+        camera_args: to_args(&rpicam_args),
+        overlay: overlay_filter,
+        audio_source: audio.as_ref().map(|audio| audio.source.to_string()),
+        audio_codec: audio.as_ref().map(|audio| audio.record_codec.to_string()),
+    };
+
+    Ok(ActiveRecording {
+        camera_process_id,
+        output,
+        started: start,
+        first_motion: start,
+        last_motion: start,
+        settings,
+        ffmpeg_process,
+        _in_progress: in_progress,
+    })
 }
 
-pub fn start_stream_rtp() -> u32 {
+pub fn start_stream_rtp(frames: &FrameHub) -> u32 {
+    let claim = frames.claim_camera();
     let command_args = [
         "-t",
         "0",
@@ -75,26 +156,59 @@ pub fn start_stream_rtp() -> u32 {
         .expect("Expected Camera command to succeed without error.");
     let camera_process_id = camera_process.id();
 
-    let ffmpeg_args = [
-        "-i",
-        "-",
+    let audio = AudioConfig::from_env();
+    let mut ffmpeg_args = to_args(&["-i", "-"]);
+    if let Some(audio) = &audio {
+        ffmpeg_args.extend(audio.input_args());
+    }
+    ffmpeg_args.extend(to_args(&["-map", "0:v"]));
+    if audio.is_some() {
+        ffmpeg_args.extend(to_args(&["-map", "1:a"]));
+    }
+    let overlay_filter = OverlayConfig::from_env()
+        .filter(|overlay| overlay.apply_to_stream)
+        .and_then(|overlay| overlay.drawtext_filter());
+    if let Some(filter) = &overlay_filter {
+        ffmpeg_args.extend(["-vf".to_string(), filter.clone()]);
+    }
+    ffmpeg_args.extend(to_args(&[
         "-c:v",
         "libx264",
         "-preset",
         "ultrafast",
         "-tune",
         "zerolatency",
-        "-f",
-        "rtp",
-        "rtp://127.0.0.1:5004",
-    ];
-    Command::new("ffmpeg")
+        "-force_key_frames",
+    ]));
+    // the live HLS playlist can only cut segments on keyframes
+    ffmpeg_args.push(format!("expr:gte(t,n_forced*{})", live_hls::segment_secs()));
+    if let Some(audio) = &audio {
+        ffmpeg_args.extend(audio.stream_codec_args());
+    }
+
+    // everything is encoded once and the tee muxer hands it to each output, rtp carries a single
+    // stream per output so audio is sent to its own port
+    let mut outputs = vec!["[select=v:f=rtp]rtp://127.0.0.1:5004".to_string()];
+    if audio.is_some() {
+        outputs.push(format!("[select=a:f=rtp]rtp://127.0.0.1:{AUDIO_RTP_PORT}"));
+    }
+    match live_hls::prepare_live_dir() {
+        Ok(dir) => outputs.push(live_hls::tee_output(&dir)),
+        Err(err) => error!("Unable to prepare live HLS directory, error: {err}"),
+    }
+    ffmpeg_args.extend(["-f".to_string(), "tee".to_string(), outputs.join("|")]);
+    ffmpeg_args.extend(frame_output_args(overlay_filter.as_deref()));
+    let mut ffmpeg_process = Command::new("ffmpeg")
+        .env("TZ", local_timezone().name())
         .args(ffmpeg_args)
         .stdin(Stdio::from(camera_process.stdout.unwrap()))
-        .stdout(Stdio::null())
+        .stdout(Stdio::piped())
         .stderr(Stdio::null())
         .spawn()
         .expect("FFMPEG video processing process completed successfully.");
+    if let Some(stdout) = ffmpeg_process.stdout.take() {
+        frames.attach_pipeline(claim, stdout);
+    }
 
     return camera_process_id;
 }
@@ -105,3 +219,7 @@ pub fn shutdown_cam_process(camera_process_id: u32) {
         .output()
         .expect("SIGUSR signal sent to camera thread");
 }
+
+fn to_args(args: &[&str]) -> Vec<String> {
+    args.iter().map(|arg| arg.to_string()).collect()
+}
@@ -0,0 +1,265 @@
+use bytes::Bytes;
+use std::{
+    env::var,
+    io::{self, Read},
+    process::{Child, ChildStdout, Command, Stdio},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+use tokio::{sync::watch, time::interval};
+use tracing::{error, info};
+
+const JPEG_START: [u8; 2] = [0xff, 0xd8];
+const JPEG_END: [u8; 2] = [0xff, 0xd9];
+/// Largest frame accepted before the reader gives up on finding the end of it.
+const MAX_FRAME_BYTES: usize = 8 * 1024 * 1024;
+
+/// Frames per second of the JPEG frames, set with `MJPEG_FPS`, defaults to 5.
+pub fn frame_rate() -> u32 {
+    var("MJPEG_FPS")
+        .ok()
+        .and_then(|fps| fps.parse().ok())
+        .filter(|fps| *fps > 0)
+        .unwrap_or(5)
+}
+
+/// ffmpeg output arguments writing JPEG frames of the camera video to stdout for a [`FrameHub`].
+pub fn frame_output_args(overlay_filter: Option<&str>) -> Vec<String> {
+    let fps = format!("fps={}", frame_rate());
+    let filter = match overlay_filter {
+        Some(overlay) => format!("{overlay},{fps}"),
+        None => fps,
+    };
+    [
+        "-map",
+        "0:v",
+        "-vf",
+        filter.as_str(),
+        "-c:v",
+        "mjpeg",
+        "-q:v",
+        "5",
+        "-f",
+        "image2pipe",
+        "pipe:1",
+    ]
+    .into_iter()
+    .map(String::from)
+    .collect()
+}
+
+/// Latest JPEG frame from the camera, shared by the MJPEG and snapshot endpoints.
+///
+/// While recording or streaming, frames come from the running ffmpeg pipeline. Otherwise an
+/// `rpicam-vid` MJPEG capture is started on demand and stopped once nobody is watching.
+#[derive(Clone)]
+pub struct FrameHub {
+    latest: Arc<watch::Sender<Option<Bytes>>>,
+    pipeline_readers: Arc<AtomicUsize>,
+    idle_capture: Arc<Mutex<Option<Child>>>,
+}
+
+impl FrameHub {
+    pub fn new() -> Self {
+        FrameHub {
+            latest: Arc::new(watch::channel(None).0),
+            pipeline_readers: Arc::new(AtomicUsize::new(0)),
+            idle_capture: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<Option<Bytes>> {
+        self.latest.subscribe()
+    }
+
+    fn is_live(&self) -> bool {
+        self.pipeline_readers.load(Ordering::SeqCst) > 0
+            || self.idle_capture.lock().unwrap().is_some()
+    }
+
+    /// The most recent frame, if a pipeline or capture is currently producing frames.
+    pub fn latest(&self) -> Option<Bytes> {
+        if !self.is_live() {
+            return None;
+        }
+        self.latest.borrow().clone()
+    }
+
+    /// Registers a pipeline about to open the camera and stops the on demand capture holding it.
+    ///
+    /// Both happen under the capture lock, so [`FrameHub::ensure_frames`] can't start another
+    /// capture between the two. The pipeline counts as active until the returned claim is dropped,
+    /// which releases it if the pipeline fails to start.
+    pub fn claim_camera(&self) -> PipelineClaim {
+        let mut idle_capture = self.idle_capture.lock().unwrap();
+        self.pipeline_readers.fetch_add(1, Ordering::SeqCst);
+        if let Some(capture) = idle_capture.take() {
+            stop_capture(capture);
+        }
+        PipelineClaim {
+            readers: self.pipeline_readers.clone(),
+        }
+    }
+
+    /// Publishes frames read from a camera pipeline's ffmpeg stdout until it exits.
+    pub fn attach_pipeline(&self, claim: PipelineClaim, stdout: ChildStdout) {
+        let hub = self.clone();
+        thread::spawn(move || {
+            if let Err(err) = hub.read_frames(stdout) {
+                error!("Error reading camera frames, error: {err}");
+            }
+            drop(claim);
+        });
+    }
+
+    /// Starts an on demand capture if nothing else is producing frames.
+    ///
+    /// The camera can only be opened by one process, so pipelines stop the capture before starting.
+    pub fn ensure_frames(&self) -> io::Result<()> {
+        let mut idle_capture = self.idle_capture.lock().unwrap();
+        if self.pipeline_readers.load(Ordering::SeqCst) > 0 {
+            return Ok(());
+        }
+        if let Some(capture) = idle_capture.as_mut() {
+            if capture.try_wait()?.is_none() {
+                return Ok(());
+            }
+        }
+
+        let mut capture = Command::new("rpicam-vid")
+            .args([
+                "-t",
+                "0",
+                "-n",
+                "--codec",
+                "mjpeg",
+                "--framerate",
+                frame_rate().to_string().as_str(),
+                "-o",
+                "-",
+            ])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()?;
+        let stdout = capture.stdout.take().expect("capture stdout is piped");
+        let hub = self.clone();
+        thread::spawn(move || {
+            if let Err(err) = hub.read_frames(stdout) {
+                error!("Error reading idle capture frames, error: {err}");
+            }
+        });
+        info!("Started on demand frame capture");
+        *idle_capture = Some(capture);
+        Ok(())
+    }
+
+    /// Stops the on demand capture, freeing the camera for a pipeline.
+    pub fn stop_idle_capture(&self) {
+        if let Some(capture) = self.idle_capture.lock().unwrap().take() {
+            stop_capture(capture);
+        }
+    }
+
+    /// Splits a stream of concatenated JPEGs into frames and publishes each one.
+    fn read_frames(&self, mut reader: impl Read) -> io::Result<()> {
+        let mut buffer = Vec::new();
+        let mut chunk = [0u8; 64 * 1024];
+        loop {
+            let read = reader.read(&mut chunk)?;
+            if read == 0 {
+                return Ok(());
+            }
+            buffer.extend_from_slice(&chunk[..read]);
+            while let Some((start, end)) = find_frame(&buffer) {
+                let frame = Bytes::copy_from_slice(&buffer[start..end]);
+                self.latest.send_replace(Some(frame));
+                buffer.drain(..end);
+            }
+            if buffer.len() > MAX_FRAME_BYTES {
+                buffer.clear();
+            }
+        }
+    }
+}
+
+impl Default for FrameHub {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A pipeline counted as producing frames, see [`FrameHub::claim_camera`].
+pub struct PipelineClaim {
+    readers: Arc<AtomicUsize>,
+}
+
+impl Drop for PipelineClaim {
+    fn drop(&mut self) {
+        self.readers.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn stop_capture(mut capture: Child) {
+    if let Err(err) = capture.kill().and_then(|_| capture.wait()) {
+        error!("Error stopping idle frame capture, error: {err}");
+    }
+    info!("Stopped on demand frame capture");
+}
+
+/// Finds the first complete JPEG in `buffer`, returning where it starts and ends.
+fn find_frame(buffer: &[u8]) -> Option<(usize, usize)> {
+    let start = buffer.windows(2).position(|bytes| bytes == JPEG_START)?;
+    let end = buffer[start + 2..]
+        .windows(2)
+        .position(|bytes| bytes == JPEG_END)?;
+    Some((start, start + 2 + end + 2))
+}
+
+/// Stops the on demand capture once no MJPEG client or snapshot request is waiting on frames.
+pub async fn idle_capture_task(frames: FrameHub) {
+    let mut ticker = interval(Duration::from_secs(5));
+    loop {
+        ticker.tick().await;
+        if frames.latest.receiver_count() == 0 {
+            frames.stop_idle_capture();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn claim_keeps_capture_from_starting_until_dropped() {
+        let frames = FrameHub::new();
+        let claim = frames.claim_camera();
+        assert!(frames.is_live());
+        // would spawn `rpicam-vid` if the pipeline weren't counted
+        frames.ensure_frames().unwrap();
+        assert!(frames.idle_capture.lock().unwrap().is_none());
+        drop(claim);
+        assert_eq!(frames.pipeline_readers.load(Ordering::SeqCst), 0);
+        assert!(!frames.is_live());
+    }
+
+    #[test]
+    fn attached_pipeline_releases_claim_when_output_ends() {
+        let frames = FrameHub::new();
+        let claim = frames.claim_camera();
+        let mut child = Command::new("true").stdout(Stdio::piped()).spawn().unwrap();
+        frames.attach_pipeline(claim, child.stdout.take().unwrap());
+        child.wait().unwrap();
+        for _ in 0..100 {
+            if frames.pipeline_readers.load(Ordering::SeqCst) == 0 {
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("pipeline claim was not released");
+    }
+}
@@ -0,0 +1,62 @@
+use std::{
+    env::var,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+pub const PLAYLIST_NAME: &str = "live.m3u8";
+const INIT_SEGMENT_NAME: &str = "live_init.mp4";
+
+/// Directory the rolling live playlist and its segments are written to, set with `LIVE_HLS_PATH`.
+pub fn live_hls_path() -> PathBuf {
+    match var("LIVE_HLS_PATH") {
+        Ok(path) => PathBuf::from(path),
+        Err(_) => std::env::temp_dir().join("motion_detector_live"),
+    }
+}
+
+/// Empties the live directory so a new stream doesn't start by serving the last one's segments.
+pub fn prepare_live_dir() -> io::Result<PathBuf> {
+    let dir = live_hls_path();
+    if dir.exists() {
+        fs::remove_dir_all(&dir)?;
+    }
+    fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+/// A `tee` muxer slave writing short fMP4 segments, old segments are deleted as the playlist rolls on.
+///
+/// Segments can only be cut on keyframes, so the encoder has to force one every `LIVE_HLS_SEGMENT_SECS`.
+pub fn tee_output(dir: &Path) -> String {
+    format!(
+        "[f=hls:hls_time={}:hls_list_size=6:hls_flags=delete_segments+independent_segments+omit_endlist:hls_segment_type=fmp4:hls_fmp4_init_filename={INIT_SEGMENT_NAME}:hls_segment_filename={}]{}",
+        segment_secs(),
+        dir.join("live_%05d.m4s").display(),
+        dir.join(PLAYLIST_NAME).display(),
+    )
+}
+
+/// Target length of live segments, set with `LIVE_HLS_SEGMENT_SECS`, defaults to 1 second.
+pub fn segment_secs() -> u32 {
+    var("LIVE_HLS_SEGMENT_SECS")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .filter(|secs| *secs > 0)
+        .unwrap_or(1)
+}
+
+/// Content type to serve a live HLS file with, `None` if the name isn't one the muxer writes.
+pub fn content_type(name: &str) -> Option<&'static str> {
+    if name == PLAYLIST_NAME {
+        return Some("application/vnd.apple.mpegurl");
+    }
+    if name == INIT_SEGMENT_NAME {
+        return Some("video/mp4");
+    }
+    let index = name.strip_prefix("live_")?.strip_suffix(".m4s")?;
+    if index.is_empty() || !index.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    Some("video/iso.segment")
+}
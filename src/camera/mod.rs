@@ -1,2 +1,6 @@
+pub mod audio;
 pub mod camera;
+pub mod frames;
+pub mod live_hls;
+pub mod overlay;
 pub mod webrtc;
@@ -0,0 +1,236 @@
+use std::{env::var, str::FromStr};
+
+const DEFAULT_TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+const DEFAULT_FONT_SIZE: u32 = 24;
+const EDGE_MARGIN: u32 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OverlayPosition {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+impl FromStr for OverlayPosition {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().replace(['-', '_'], "").as_str() {
+            "topleft" => Ok(OverlayPosition::TopLeft),
+            "topright" => Ok(OverlayPosition::TopRight),
+            "bottomleft" => Ok(OverlayPosition::BottomLeft),
+            "bottomright" => Ok(OverlayPosition::BottomRight),
+            other => Err(format!("Unknown overlay position: {other}")),
+        }
+    }
+}
+
+impl OverlayPosition {
+    /// x/y expressions understood by the ffmpeg drawtext filter.
+    fn coordinates(&self) -> (String, String) {
+        let left = EDGE_MARGIN.to_string();
+        let right = format!("w-tw-{EDGE_MARGIN}");
+        let top = EDGE_MARGIN.to_string();
+        let bottom = format!("h-th-{EDGE_MARGIN}");
+        match self {
+            OverlayPosition::TopLeft => (left, top),
+            OverlayPosition::TopRight => (right, top),
+            OverlayPosition::BottomLeft => (left, bottom),
+            OverlayPosition::BottomRight => (right, bottom),
+        }
+    }
+}
+
+/// Burned-in text drawn onto recordings and, optionally, the live stream.
+///
+/// Read from the environment:
+/// - `OVERLAY_ENABLED`: `true` to draw the overlay at all.
+/// - `OVERLAY_CAMERA_NAME`: camera name shown before the timestamp.
+/// - `OVERLAY_TEXT`: optional custom text shown after the timestamp.
+/// - `OVERLAY_TIME_FORMAT`: strftime format for the timestamp, empty to hide it.
+/// - `OVERLAY_POSITION`: `top_left`, `top_right`, `bottom_left` or `bottom_right`.
+/// - `OVERLAY_FONT_SIZE`, `OVERLAY_FONT_FILE`: text size and optional font path.
+/// - `OVERLAY_ON_STREAM`: `true` to also draw the overlay on the live stream.
+#[derive(Debug, Clone)]
+pub struct OverlayConfig {
+    pub camera_name: Option<String>,
+    pub custom_text: Option<String>,
+    pub time_format: Option<String>,
+    pub position: OverlayPosition,
+    pub font_size: u32,
+    pub font_file: Option<String>,
+    pub apply_to_stream: bool,
+}
+
+impl OverlayConfig {
+    pub fn from_env() -> Option<Self> {
+        if !env_flag("OVERLAY_ENABLED") {
+            return None;
+        }
+        let time_format = match var("OVERLAY_TIME_FORMAT") {
+            Ok(format) if format.is_empty() => None,
+            Ok(format) => Some(format),
+            Err(_) => Some(DEFAULT_TIME_FORMAT.to_string()),
+        };
+        let position = var("OVERLAY_POSITION")
+            .ok()
+            .and_then(|position| position.parse().ok())
+            .unwrap_or(OverlayPosition::TopLeft);
+        let font_size = var("OVERLAY_FONT_SIZE")
+            .ok()
+            .and_then(|size| size.parse().ok())
+            .unwrap_or(DEFAULT_FONT_SIZE);
+
+        Some(OverlayConfig {
+            camera_name: non_empty_var("OVERLAY_CAMERA_NAME"),
+            custom_text: non_empty_var("OVERLAY_TEXT"),
+            time_format,
+            position,
+            font_size,
+            font_file: non_empty_var("OVERLAY_FONT_FILE"),
+            apply_to_stream: env_flag("OVERLAY_ON_STREAM"),
+        })
+    }
+
+    /// Builds the drawtext filter passed to ffmpeg with `-vf`, or `None` if there is nothing to draw.
+    pub fn drawtext_filter(&self) -> Option<String> {
+        let mut parts = Vec::new();
+        if let Some(name) = &self.camera_name {
+            parts.push(escape_drawtext(name));
+        }
+        if let Some(format) = &self.time_format {
+            // the timestamp is expanded by ffmpeg on every frame so it tracks the clip as it records
+            parts.push(format!("%{{localtime:{}}}", escape_expansion_arg(format)));
+        }
+        if let Some(text) = &self.custom_text {
+            parts.push(escape_drawtext(text));
+        }
+        if parts.is_empty() {
+            return None;
+        }
+
+        let (x, y) = self.position.coordinates();
+        let mut filter = format!(
+            "drawtext=text={}:x={x}:y={y}:fontsize={}:fontcolor=white:box=1:boxcolor=black@0.5:boxborderw=5",
+            quote_option(&parts.join("  |  ")),
+            self.font_size
+        );
+        if let Some(font_file) = &self.font_file {
+            filter.push_str(&format!(":fontfile={}", quote_option(font_file)));
+        }
+        Some(filter)
+    }
+}
+
+fn env_flag(key: &str) -> bool {
+    var(key)
+        .map(|value| matches!(value.to_lowercase().as_str(), "1" | "true" | "yes"))
+        .unwrap_or(false)
+}
+
+fn non_empty_var(key: &str) -> Option<String> {
+    var(key).ok().filter(|value| !value.trim().is_empty())
+}
+
+// Text passes through three rounds of unescaping inside ffmpeg: the filtergraph parser,
+// the filter option parser and finally drawtext's own expansion of `%{...}` sequences.
+
+/// Escapes plain text for drawtext so `%` and `\` are drawn literally.
+pub(crate) fn escape_drawtext(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('%', "\\%")
+}
+
+/// Escapes an argument of a `%{...}` drawtext expansion, where `:` and `}` are separators.
+fn escape_expansion_arg(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(':', "\\:")
+        .replace('}', "\\}")
+}
+
+/// Escapes a value for the filter option parser and quotes it for the filtergraph parser.
+pub(crate) fn quote_option(value: &str) -> String {
+    let escaped = value
+        .replace('\\', "\\\\")
+        .replace(':', "\\:")
+        .replace('\'', "\\'");
+    format!("'{}'", escaped.replace('\'', "'\\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> OverlayConfig {
+        OverlayConfig {
+            camera_name: None,
+            custom_text: None,
+            time_format: None,
+            position: OverlayPosition::TopLeft,
+            font_size: DEFAULT_FONT_SIZE,
+            font_file: None,
+            apply_to_stream: false,
+        }
+    }
+
+    #[test]
+    fn drawtext_escapes_are_plain_text_only() {
+        assert_eq!(escape_drawtext(r"50% \ done: it's"), r"50\% \\ done: it's");
+        assert_eq!(escape_expansion_arg(r"%H:%M} \"), r"%H\:%M\} \\");
+    }
+
+    #[test]
+    fn quoted_options_escape_separators_and_quotes() {
+        assert_eq!(quote_option("plain"), "'plain'");
+        assert_eq!(quote_option("/fonts/a:b.ttf"), r"'/fonts/a\:b.ttf'");
+        assert_eq!(quote_option(r"C:\fonts"), r"'C\:\\fonts'");
+        assert_eq!(quote_option("it's"), r"'it\'\''s'");
+    }
+
+    #[test]
+    fn filter_draws_name_time_and_text() {
+        let overlay = OverlayConfig {
+            camera_name: Some("Front: Door's".to_string()),
+            time_format: Some("%H:%M".to_string()),
+            custom_text: Some(r"50% \ done".to_string()),
+            position: OverlayPosition::BottomRight,
+            font_size: 20,
+            font_file: Some("/fonts/a:b.ttf".to_string()),
+            ..config()
+        };
+        assert_eq!(
+            overlay.drawtext_filter().unwrap(),
+            r"drawtext=text='Front\: Door\'\''s  |  %{localtime\:%H\\\:%M}  |  50\\% \\\\ done':x=w-tw-10:y=h-th-10:fontsize=20:fontcolor=white:box=1:boxcolor=black@0.5:boxborderw=5:fontfile='/fonts/a\:b.ttf'"
+        );
+    }
+
+    #[test]
+    fn filter_with_only_a_time() {
+        let overlay = OverlayConfig {
+            time_format: Some(DEFAULT_TIME_FORMAT.to_string()),
+            ..config()
+        };
+        assert_eq!(
+            overlay.drawtext_filter().unwrap(),
+            r"drawtext=text='%{localtime\:%Y-%m-%d %H\\\:%M\\\:%S}':x=10:y=10:fontsize=24:fontcolor=white:box=1:boxcolor=black@0.5:boxborderw=5"
+        );
+    }
+
+    #[test]
+    fn nothing_to_draw_has_no_filter() {
+        assert_eq!(config().drawtext_filter(), None);
+    }
+
+    #[test]
+    fn positions_parse_with_any_separator() {
+        assert_eq!(
+            "top-right".parse::<OverlayPosition>(),
+            Ok(OverlayPosition::TopRight)
+        );
+        assert_eq!(
+            "Bottom_Left".parse::<OverlayPosition>(),
+            Ok(OverlayPosition::BottomLeft)
+        );
+        assert!("middle".parse::<OverlayPosition>().is_err());
+    }
+}
@@ -1,3 +1,4 @@
+use super::audio::{AudioConfig, AUDIO_RTP_PORT};
 use anyhow::Result;
 use axum::{
     extract::ws::{Message, WebSocket, WebSocketUpgrade},
@@ -6,12 +7,12 @@ use axum::{
 use futures_util::{SinkExt, StreamExt};
 use serde::{Deserialize, Serialize};
 use std::{sync::Arc, env::var};
-use tokio::{net::UdpSocket, spawn, sync::Mutex};
+use tokio::{net::UdpSocket, spawn, sync::Mutex, task::JoinHandle};
 use tracing::{debug, error, info};
 use webrtc::{
     api::{
         interceptor_registry::register_default_interceptors,
-        media_engine::{MediaEngine, MIME_TYPE_H264},
+        media_engine::{MediaEngine, MIME_TYPE_H264, MIME_TYPE_OPUS},
         APIBuilder, API,
     },
     ice_transport::{
@@ -21,6 +22,7 @@ use webrtc::{
     interceptor::registry::Registry,
     peer_connection::{
         configuration::RTCConfiguration, sdp::session_description::RTCSessionDescription,
+        RTCPeerConnection,
     },
     rtp_transceiver::rtp_codec::RTCRtpCodecCapability,
     track::track_local::{
@@ -117,6 +119,11 @@ async fn handle_socket(socket: WebSocket) {
         }
     });
 
+    let audio_forwarder = match AudioConfig::from_env() {
+        Some(_) => Some(add_audio_track(&peer_conn).await),
+        None => None,
+    };
+
     let ice_sender = sender.clone();
     peer_conn.on_ice_candidate(Box::new(move |candidate: Option<RTCIceCandidate>| {
         let ice_sender_clone = ice_sender.clone();
@@ -196,6 +203,57 @@ async fn handle_socket(socket: WebSocket) {
     info!("socket closed");
     buff_reader.abort();
     track_writer.abort();
+    if let Some((audio_buff_reader, audio_track_writer)) = audio_forwarder {
+        audio_buff_reader.abort();
+        audio_track_writer.abort();
+    }
+}
+
+/// Adds an Opus track fed by the audio RTP stream ffmpeg sends while streaming.
+async fn add_audio_track(
+    peer_conn: &Arc<RTCPeerConnection>,
+) -> (JoinHandle<Result<()>>, JoinHandle<()>) {
+    let audio_track = Arc::new(TrackLocalStaticRTP::new(
+        RTCRtpCodecCapability {
+            mime_type: MIME_TYPE_OPUS.to_owned(),
+            clock_rate: 48000,
+            channels: 2,
+            ..Default::default()
+        },
+        "audio".to_owned(),
+        "webrtc-rs".to_owned(),
+    ));
+
+    let rtp_sender = peer_conn
+        .add_track(Arc::clone(&audio_track) as Arc<dyn TrackLocal + Send + Sync>)
+        .await
+        .expect("add audio track to peer connection");
+
+    let buff_reader = spawn(async move {
+        let mut rtcp_buf = vec![0u8; 1500];
+        while let Ok((_, _)) = rtp_sender.read(&mut rtcp_buf).await {}
+        Result::<()>::Ok(())
+    });
+
+    let udp_socket = UdpSocket::bind(("127.0.0.1", AUDIO_RTP_PORT))
+        .await
+        .unwrap();
+
+    let track_writer = spawn(async move {
+        let mut inbound_rtp_packet = vec![0u8; 1500]; // UDP MTU
+        while let Ok((n, _)) = udp_socket.recv_from(&mut inbound_rtp_packet).await {
+            if let Err(err) = audio_track.write(&inbound_rtp_packet[..n]).await {
+                if Error::ErrClosedPipe == err {
+                    error!("The peer conn has been closed");
+                } else {
+                    error!("audio_track write err: {err}");
+                }
+                return;
+            }
+        }
+    });
+
+    (buff_reader, track_writer)
 }
 
 fn build_api() -> API {
@@ -0,0 +1,107 @@
+use crate::timezone::serialize_local;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::{collections::VecDeque, sync::Mutex};
+use tokio::sync::broadcast;
+use tracing::{info, warn};
+
+const RECENT_EVENT_LIMIT: usize = 200;
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "type")]
+pub enum EventKind {
+    MotionDetected,
+    RecordingStarted {
+        file_name: String,
+    },
+    RecordingStopped {
+        file_name: String,
+    },
+    RecordingFailed {
+        reason: String,
+    },
+    RecordingRefused {
+        reason: String,
+    },
+    LowDiskSpace {
+        free_bytes: u64,
+        min_free_bytes: u64,
+    },
+    DiskSpaceRecovered {
+        free_bytes: u64,
+    },
+}
+
+impl EventKind {
+    /// Name of the kind, as in the `type` field events are serialized with.
+    pub fn name(&self) -> &'static str {
+        match self {
+            EventKind::MotionDetected => "MotionDetected",
+            EventKind::RecordingStarted { .. } => "RecordingStarted",
+            EventKind::RecordingStopped { .. } => "RecordingStopped",
+            EventKind::RecordingFailed { .. } => "RecordingFailed",
+            EventKind::RecordingRefused { .. } => "RecordingRefused",
+            EventKind::LowDiskSpace { .. } => "LowDiskSpace",
+            EventKind::DiskSpaceRecovered { .. } => "DiskSpaceRecovered",
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Event {
+    #[serde(serialize_with = "serialize_local")]
+    pub timestamp: DateTime<Utc>,
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+/// Fans out events raised by the motion detector to anything subscribed and keeps the most recent ones.
+pub struct EventBus {
+    sender: broadcast::Sender<Event>,
+    recent: Mutex<VecDeque<Event>>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(64);
+        EventBus {
+            sender,
+            recent: Mutex::new(VecDeque::with_capacity(RECENT_EVENT_LIMIT)),
+        }
+    }
+
+    pub fn raise(&self, kind: EventKind) {
+        let event = Event {
+            timestamp: Utc::now(),
+            kind,
+        };
+        match &event.kind {
+            EventKind::RecordingFailed { .. }
+            | EventKind::RecordingRefused { .. }
+            | EventKind::LowDiskSpace { .. } => warn!("event: {:?}", event.kind),
+            _ => info!("event: {:?}", event.kind),
+        }
+
+        let mut recent = self.recent.lock().unwrap();
+        if recent.len() == RECENT_EVENT_LIMIT {
+            recent.pop_front();
+        }
+        recent.push_back(event.clone());
+        // no subscribers is not an error, the event is still kept in the recent list
+        let _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+
+    pub fn recent(&self) -> Vec<Event> {
+        self.recent.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,15 +1,23 @@
 use crate::motion_detect::gpio::MotionDetector;
+use crate::storage::{
+    backend::backend_from_env, backup::Backup, catalog::Catalog, store::RecordingStore,
+    video_save_path,
+};
 use axum_server::tls_rustls::RustlsConfig;
 use dotenvy::dotenv;
-use std::{env::var, fs::File, io::stdout, net::SocketAddr, path::PathBuf};
+use std::{env::var, fs::File, io::stdout, net::SocketAddr, path::PathBuf, sync::Arc};
 use tokio;
 use tokio_rustls::rustls;
-use tracing::info;
+use tracing::{error, info};
 use tracing_subscriber::{fmt::layer, prelude::*, registry};
 
 pub mod app;
 mod camera;
+mod events;
+mod media;
 pub mod motion_detect;
+mod storage;
+mod timezone;
 
 #[tokio::main]
 async fn main() {
@@ -27,10 +35,44 @@ async fn main() {
     let trace_layer = layer().pretty().with_writer(stdout);
     registry().with(trace_layer).init();
 
-    let motion_detector = MotionDetector::new(4);
-    let app = app::app::create_app(motion_detector).await;
+    ffmpeg_next::init().expect("ffmpeg initialised successfully");
+    let catalog = Arc::new(Catalog::open_default().expect("Catalog opened successfully"));
+    let catalog_sync = catalog.clone();
+    tokio::task::spawn_blocking(move || {
+        if let Err(err) = catalog_sync.rebuild(&video_save_path(), false) {
+            error!("Error syncing catalog with recordings on disk, error: {err}");
+        }
+    });
+
+    let remote = backend_from_env().expect("Storage backend configured successfully");
+    let store = Arc::new(RecordingStore::from_env().with_remote(remote));
+
+    let motion_detector = Arc::new(MotionDetector::new(4, catalog.clone()));
+    tokio::spawn(camera::frames::idle_capture_task(
+        motion_detector.frames.clone(),
+    ));
+    tokio::spawn(storage::stats::event_log_task(
+        catalog.clone(),
+        motion_detector.events.subscribe(),
+    ));
+    tokio::spawn(storage::offload::offload_task(
+        store.clone(),
+        catalog.clone(),
+        motion_detector.events.subscribe(),
+    ));
+    let backup = Arc::new(
+        Backup::from_env(catalog.clone(), store.clone())
+            .expect("Backup destination configured successfully"),
+    );
+    tokio::spawn(storage::backup::backup_task(
+        backup.clone(),
+        motion_detector.events.subscribe(),
+    ));
+    let app = app::app::create_app(motion_detector, catalog.clone(), store, backup).await;
 
     tokio::spawn(app::app::redirect_http_to_https());
+    tokio::spawn(storage::retention::retention_task(catalog.clone()));
+    tokio::spawn(storage::trash::trash_task(catalog));
 
     let config = RustlsConfig::from_pem_file(
         PathBuf::from(env!("CARGO_MANIFEST_DIR"))
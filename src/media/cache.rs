@@ -0,0 +1,95 @@
+use anyhow::Result;
+use std::{
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::{error, info};
+
+/// A directory of generated files kept under a size cap, least recently used entries are evicted first.
+///
+/// Entries are the top level files or directories inside `dir`, a directory counts as one entry.
+pub struct DiskCache {
+    dir: PathBuf,
+    max_bytes: u64,
+}
+
+impl DiskCache {
+    pub fn new(dir: PathBuf, max_bytes: u64) -> Self {
+        DiskCache { dir, max_bytes }
+    }
+
+    /// Where the entry called `name` lives, the cache directory is created if needed.
+    pub fn path(&self, name: &str) -> Result<PathBuf> {
+        fs::create_dir_all(&self.dir)?;
+        Ok(self.dir.join(name))
+    }
+
+    /// Returns the entry called `name` if it exists, marking it as recently used.
+    pub fn get(&self, name: &str) -> Option<PathBuf> {
+        let path = self.dir.join(name);
+        if !path.exists() {
+            return None;
+        }
+        if let Err(err) = touch(&path) {
+            error!("Unable to mark {} as used, error: {err}", path.display());
+        }
+        Some(path)
+    }
+
+    /// Removes least recently used entries until the cache fits within its size cap.
+    ///
+    /// Entries still being written end in `.part` and are left alone.
+    pub fn evict(&self) -> Result<()> {
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path
+                .extension()
+                .is_some_and(|extension| extension == "part")
+            {
+                continue;
+            }
+            let used = fs::metadata(&path)?.modified().unwrap_or(UNIX_EPOCH);
+            entries.push((used, entry_size(&path)?, path));
+        }
+        let mut total: u64 = entries.iter().map(|(_, size, _)| size).sum();
+        entries.sort_by_key(|(used, _, _)| *used);
+
+        for (_, size, path) in entries {
+            if total <= self.max_bytes {
+                break;
+            }
+            remove_entry(&path)?;
+            info!("Evicted {} from cache", path.display());
+            total -= size;
+        }
+        Ok(())
+    }
+}
+
+/// Sets the modified time to now, eviction treats it as the last time the entry was used.
+fn touch(path: &Path) -> io::Result<()> {
+    File::open(path)?.set_modified(SystemTime::now())
+}
+
+fn entry_size(path: &Path) -> io::Result<u64> {
+    let metadata = fs::metadata(path)?;
+    if !metadata.is_dir() {
+        return Ok(metadata.len());
+    }
+    let mut size = 0;
+    for entry in fs::read_dir(path)? {
+        size += entry_size(&entry?.path())?;
+    }
+    Ok(size)
+}
+
+fn remove_entry(path: &Path) -> io::Result<()> {
+    if path.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }
+}
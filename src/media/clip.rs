@@ -0,0 +1,618 @@
+use crate::app::jobs::{JobId, JobManager, JobOutput};
+use crate::storage::{
+    audit,
+    catalog::{probe_recording, Annotation, Catalog},
+    metadata::{read_sidecar, write_sidecar},
+    CLIP_MARKER,
+};
+use anyhow::{anyhow, bail, Result};
+use ffmpeg_next::{
+    codec, decoder, encoder, ffi::AV_TIME_BASE, format, frame, media::Type, picture, Dictionary,
+    Packet, Rational,
+};
+use std::{
+    fs::{self, OpenOptions},
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::task::spawn_blocking;
+use tracing::info;
+
+/// How a clip is cut out of a recording.
+#[derive(Clone, Debug)]
+pub enum TrimMode {
+    /// Packets are copied as they are, so the clip starts at the keyframe at or before the start
+    /// offset. Fast and lossless, but can start a few seconds early.
+    KeyframeCopy,
+    /// Video is decoded and re-encoded with the named encoder so the clip starts exactly on time.
+    Accurate { encoder: String },
+}
+
+/// Starts a job cutting the part of the recording at `source` between `start` and `end` seconds.
+///
+/// With `save` set the clip is added to the recordings as a new protected recording, otherwise it
+/// is written to the job output directory for download. Either way it is the job's result.
+pub fn spawn_export(
+    jobs: &Arc<JobManager>,
+    catalog: Arc<Catalog>,
+    owner: &str,
+    source: PathBuf,
+    (start, end): (f64, f64),
+    mode: TrimMode,
+    save: bool,
+) -> JobId {
+    let output_jobs = jobs.clone();
+    let actor = owner.to_string();
+    jobs.spawn(owner, "export", move |job| async move {
+        let destination = if save {
+            source.with_file_name(clip_name(&source, start, end, 1))
+        } else {
+            output_jobs.output_path(job.id, "mp4")?
+        };
+        let partial = destination.with_extension(format!("{}.part", job.id));
+        let clip_source = source.clone();
+        let output = spawn_blocking(move || {
+            let trimmed = trim(
+                &clip_source,
+                &partial,
+                start,
+                end,
+                &mode,
+                |progress| job.set_progress(progress),
+                || job.is_cancelled(),
+            );
+            let finished = trimmed.and_then(|_| {
+                if save {
+                    save_clip(&partial, &clip_source, start, end)
+                } else {
+                    fs::rename(&partial, &destination)?;
+                    Ok(destination)
+                }
+            });
+            if finished.is_err() {
+                let _ = fs::remove_file(&partial);
+            }
+            finished
+        })
+        .await??;
+
+        if save {
+            let clip = output.clone();
+            spawn_blocking(move || index_clip(&catalog, &source, &clip, (start, end), &actor))
+                .await??;
+        }
+        info!("Exported clip {}", output.display());
+        Ok(JobOutput::File(output))
+    })
+}
+
+/// File name of a clip saved from `source`, made from its stem and the range in milliseconds.
+///
+/// Copies after the first of the same range get a `_<copy>` suffix.
+fn clip_name(source: &Path, start: f64, end: f64, copy: u32) -> String {
+    let stem = source
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let suffix = if copy > 1 {
+        format!("_{copy}")
+    } else {
+        String::new()
+    };
+    format!(
+        "{stem}{CLIP_MARKER}{}-{}{suffix}.mp4",
+        (start * 1000.0) as u64,
+        (end * 1000.0) as u64
+    )
+}
+
+/// Moves a finished clip from `partial` to the first free clip name next to `source`.
+///
+/// Saving the same range again makes another copy rather than replacing an earlier, protected,
+/// clip. Each name is claimed by creating it before the clip is renamed over it, so two exports
+/// finishing together can't pick the same one.
+fn save_clip(partial: &Path, source: &Path, start: f64, end: f64) -> Result<PathBuf> {
+    let mut copy = 1;
+    loop {
+        let destination = source.with_file_name(clip_name(source, start, end, copy));
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&destination)
+        {
+            Ok(_) => {
+                if let Err(err) = fs::rename(partial, &destination) {
+                    let _ = fs::remove_file(&destination);
+                    return Err(err.into());
+                }
+                return Ok(destination);
+            }
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => copy += 1,
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+/// Adds a saved clip to the catalog as a protected recording, carrying over the source's metadata.
+fn index_clip(
+    catalog: &Catalog,
+    source: &Path,
+    clip: &Path,
+    (start, end): (f64, f64),
+    actor: &str,
+) -> Result<()> {
+    if let Some(metadata) = read_sidecar(source) {
+        write_sidecar(clip, &metadata)?;
+    }
+    let source_name = source
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let source_entry = catalog.get(&source_name)?;
+    let mut entry = probe_recording(
+        clip,
+        source_entry
+            .as_ref()
+            .and_then(|entry| entry.trigger.clone()),
+    )?;
+    // the clip was recorded when its range started, not when it was exported
+    if let Some(source_entry) = &source_entry {
+        entry.recorded = source_entry.recorded + start as i64;
+    }
+    catalog.upsert(&entry)?;
+    catalog.annotate(
+        &entry.file_name,
+        &Annotation {
+            tags: None,
+            starred: None,
+            notes: Some(format!(
+                "Exported from {source_name}, {start:.1}s to {end:.1}s"
+            )),
+            protected: Some(true),
+        },
+    )?;
+    audit::record(
+        actor,
+        "export",
+        clip,
+        &format!("clip of {source_name} from {start:.1}s to {end:.1}s"),
+    );
+    Ok(())
+}
+
+/// Writes the part of `source` between `start` and `end`, in seconds from its start, to `destination`.
+///
+/// Audio is always copied, only video is re-encoded in accurate mode.
+pub fn trim(
+    source: &Path,
+    destination: &Path,
+    start: f64,
+    end: f64,
+    mode: &TrimMode,
+    progress: impl Fn(f32),
+    cancelled: impl Fn() -> bool,
+) -> Result<()> {
+    if start < 0.0 || end <= start {
+        bail!("Invalid clip range {start}-{end}");
+    }
+    let mut input = format::input(&source)?;
+    let video_index = input
+        .streams()
+        .best(Type::Video)
+        .ok_or_else(|| anyhow!("{} has no video stream", source.display()))?
+        .index();
+    let mut output = format::output_as(&destination, "mp4")?;
+
+    let mut clip = match mode {
+        TrimMode::KeyframeCopy => None,
+        TrimMode::Accurate { encoder } => Some(ClipEncoder::new(
+            &input,
+            &mut output,
+            video_index,
+            encoder,
+            start,
+            end,
+        )?),
+    };
+    let mut stream_mapping = vec![None; input.nb_streams() as usize];
+    for stream in input.streams() {
+        let medium = stream.parameters().medium();
+        if medium != Type::Video && medium != Type::Audio {
+            continue;
+        }
+        if stream.index() == video_index {
+            if let Some(clip) = &clip {
+                stream_mapping[video_index] = Some(clip.output_index);
+                continue;
+            }
+        }
+        let mut output_stream = output.add_stream(encoder::find(codec::Id::None))?;
+        output_stream.set_parameters(stream.parameters());
+        // SAFETY: the parameters pointer is valid for the lifetime of the output stream
+        unsafe {
+            (*output_stream.parameters().as_mut_ptr()).codec_tag = 0;
+        }
+        stream_mapping[stream.index()] = Some(output_stream.index());
+    }
+
+    let mut header_options = Dictionary::new();
+    header_options.set("movflags", "+faststart");
+    output.write_header_with(header_options)?;
+    if let Some(clip) = &mut clip {
+        clip.output_time_base = output.stream(clip.output_index).unwrap().time_base();
+    }
+
+    // lands on the keyframe at or before the start, the decoder needs it even in accurate mode
+    let seek_to = (start * AV_TIME_BASE as f64) as i64;
+    input.seek(seek_to, ..seek_to)?;
+
+    // in copy mode the clip starts at the first keyframe, in accurate mode exactly at `start`
+    let mut clip_start = clip.as_ref().map(|_| start);
+    for (stream, mut packet) in input.packets() {
+        if cancelled() {
+            bail!("Export of {} cancelled", source.display());
+        }
+        let Some(output_index) = stream_mapping[stream.index()] else {
+            continue;
+        };
+        let Some(timestamp) = packet.pts().or(packet.dts()) else {
+            continue;
+        };
+        let time_base = stream.time_base();
+        let secs = timestamp as f64 * f64::from(time_base);
+        let is_video = stream.index() == video_index;
+
+        if is_video {
+            if let Some(clip) = &mut clip {
+                clip.decoder.send_packet(&packet)?;
+                clip.receive_frames(&mut output)?;
+                if clip.finished {
+                    break;
+                }
+                progress(((secs - start) / (end - start)).clamp(0.0, 1.0) as f32);
+                continue;
+            }
+        }
+        if clip_start.is_none() {
+            // nothing is written before the first keyframe in copy mode
+            if !(is_video && packet.is_key()) {
+                continue;
+            }
+            clip_start = Some(secs);
+        }
+        let offset_secs = clip_start.unwrap_or(start);
+        if secs < offset_secs {
+            continue;
+        }
+        if secs >= end {
+            if is_video {
+                break;
+            }
+            continue;
+        }
+
+        let offset = (offset_secs / f64::from(time_base)) as i64;
+        packet.set_pts(packet.pts().map(|pts| pts - offset));
+        packet.set_dts(packet.dts().map(|dts| dts - offset));
+        let output_time_base = output.stream(output_index).unwrap().time_base();
+        packet.rescale_ts(time_base, output_time_base);
+        packet.set_position(-1);
+        packet.set_stream(output_index);
+        packet.write_interleaved(&mut output)?;
+        if is_video {
+            progress(((secs - start) / (end - start)).clamp(0.0, 1.0) as f32);
+        }
+    }
+
+    match &mut clip {
+        Some(clip) => {
+            clip.decoder.send_eof()?;
+            clip.receive_frames(&mut output)?;
+            clip.encoder.send_eof()?;
+            clip.receive_packets(&mut output)?;
+        }
+        None if clip_start.is_none() => {
+            bail!("No keyframe found in {} after {start}s", source.display())
+        }
+        None => {}
+    }
+    output.write_trailer()?;
+    Ok(())
+}
+
+/// Re-encodes the video frames inside the clip range, dropping the rest.
+struct ClipEncoder {
+    decoder: decoder::Video,
+    encoder: encoder::Video,
+    input_time_base: Rational,
+    output_index: usize,
+    output_time_base: Rational,
+    /// Clip range in the input stream's time base.
+    start_pts: i64,
+    end_pts: i64,
+    /// Set once a frame past the end of the range has been decoded.
+    finished: bool,
+}
+
+impl ClipEncoder {
+    fn new(
+        input: &format::context::Input,
+        output: &mut format::context::Output,
+        video_index: usize,
+        encoder_name: &str,
+        start: f64,
+        end: f64,
+    ) -> Result<Self> {
+        let stream = input.stream(video_index).unwrap();
+        let input_time_base = stream.time_base();
+        let decoder = codec::context::Context::from_parameters(stream.parameters())?
+            .decoder()
+            .video()?;
+
+        let codec = encoder::find_by_name(encoder_name)
+            .ok_or_else(|| anyhow!("Encoder {encoder_name} is not available"))?;
+        let mut video_encoder = codec::context::Context::new_with_codec(codec)
+            .encoder()
+            .video()?;
+        video_encoder.set_width(decoder.width());
+        video_encoder.set_height(decoder.height());
+        video_encoder.set_format(decoder.format());
+        video_encoder.set_aspect_ratio(decoder.aspect_ratio());
+        video_encoder.set_frame_rate(decoder.frame_rate());
+        video_encoder.set_time_base(input_time_base);
+        if output
+            .format()
+            .flags()
+            .contains(format::Flags::GLOBAL_HEADER)
+        {
+            video_encoder.set_flags(codec::Flags::GLOBAL_HEADER);
+        }
+        let mut encoder_options = Dictionary::new();
+        if encoder_name == "libx264" {
+            encoder_options.set("preset", "veryfast");
+            encoder_options.set("crf", "20");
+        }
+        let video_encoder = video_encoder.open_with(encoder_options)?;
+        let output_index = {
+            let mut output_stream = output.add_stream(codec)?;
+            output_stream.set_parameters(&video_encoder);
+            output_stream.index()
+        };
+
+        Ok(ClipEncoder {
+            decoder,
+            encoder: video_encoder,
+            input_time_base,
+            output_index,
+            output_time_base: input_time_base,
+            start_pts: (start / f64::from(input_time_base)) as i64,
+            end_pts: (end / f64::from(input_time_base)) as i64,
+            finished: false,
+        })
+    }
+
+    fn receive_frames(&mut self, output: &mut format::context::Output) -> Result<()> {
+        let mut decoded = frame::Video::empty();
+        while self.decoder.receive_frame(&mut decoded).is_ok() {
+            let Some(pts) = decoded.timestamp() else {
+                continue;
+            };
+            if pts < self.start_pts {
+                continue;
+            }
+            if pts >= self.end_pts {
+                self.finished = true;
+                continue;
+            }
+            decoded.set_pts(Some(pts - self.start_pts));
+            decoded.set_kind(picture::Type::None);
+            self.encoder.send_frame(&decoded)?;
+            self.receive_packets(output)?;
+        }
+        Ok(())
+    }
+
+    fn receive_packets(&mut self, output: &mut format::context::Output) -> Result<()> {
+        let mut encoded = Packet::empty();
+        while self.encoder.receive_packet(&mut encoded).is_ok() {
+            encoded.set_stream(self.output_index);
+            encoded.rescale_ts(self.input_time_base, self.output_time_base);
+            encoded.write_interleaved(output)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::catalog::CatalogEntry;
+    use std::process::Command;
+    use tempfile::TempDir;
+
+    const SOURCE: &str = "motion_2025-06-01_14-03-00_UTC0100.mp4";
+
+    #[test]
+    fn clip_name_has_range_in_milliseconds_and_copy_suffix() {
+        let source = Path::new("/recordings").join(SOURCE);
+        assert_eq!(
+            clip_name(&source, 1.5, 12.25, 1),
+            "motion_2025-06-01_14-03-00_UTC0100_clip_1500-12250.mp4"
+        );
+        assert_eq!(
+            clip_name(&source, 1.5, 12.25, 3),
+            "motion_2025-06-01_14-03-00_UTC0100_clip_1500-12250_3.mp4"
+        );
+    }
+
+    #[test]
+    fn saving_same_range_again_keeps_earlier_clips() {
+        let dir = TempDir::new().unwrap();
+        let source = dir.path().join(SOURCE);
+        let mut saved = Vec::new();
+        for contents in ["first", "second"] {
+            let partial = dir.path().join("clip.part");
+            fs::write(&partial, contents).unwrap();
+            saved.push(save_clip(&partial, &source, 1.5, 12.25).unwrap());
+            assert!(!partial.exists());
+        }
+        assert_eq!(
+            saved[0],
+            source.with_file_name(clip_name(&source, 1.5, 12.25, 1))
+        );
+        assert_eq!(
+            saved[1],
+            source.with_file_name(clip_name(&source, 1.5, 12.25, 2))
+        );
+        assert_eq!(fs::read_to_string(&saved[0]).unwrap(), "first");
+        assert_eq!(fs::read_to_string(&saved[1]).unwrap(), "second");
+    }
+
+    #[test]
+    fn claimed_name_is_skipped() {
+        let dir = TempDir::new().unwrap();
+        let source = dir.path().join(SOURCE);
+        // another export has claimed the first name but not yet renamed its clip over it
+        let claimed = source.with_file_name(clip_name(&source, 0.0, 5.0, 1));
+        fs::File::create(&claimed).unwrap();
+        let partial = dir.path().join("clip.part");
+        fs::write(&partial, "clip").unwrap();
+
+        let saved = save_clip(&partial, &source, 0.0, 5.0).unwrap();
+        assert_eq!(
+            saved,
+            source.with_file_name(clip_name(&source, 0.0, 5.0, 2))
+        );
+        assert_eq!(fs::read(&claimed).unwrap(), b"");
+    }
+
+    #[test]
+    fn failed_rename_releases_claimed_name() {
+        let dir = TempDir::new().unwrap();
+        let source = dir.path().join(SOURCE);
+        assert!(save_clip(&dir.path().join("missing.part"), &source, 0.0, 5.0).is_err());
+        assert!(!source
+            .with_file_name(clip_name(&source, 0.0, 5.0, 1))
+            .exists());
+    }
+
+    /// Duration and first video timestamp of a file, both in seconds.
+    fn duration_and_start(path: &Path) -> (f64, f64) {
+        let mut input = format::input(&path).unwrap();
+        let duration = input.duration() as f64 / AV_TIME_BASE as f64;
+        let video = input.streams().best(Type::Video).unwrap();
+        let (video_index, time_base) = (video.index(), video.time_base());
+        let start = input
+            .packets()
+            .filter(|(stream, _)| stream.index() == video_index)
+            .filter_map(|(_, packet)| packet.pts())
+            .min()
+            .unwrap();
+        (duration, start as f64 * f64::from(time_base))
+    }
+
+    #[test]
+    #[ignore = "needs ffmpeg"]
+    fn trim_shifts_clip_to_start_at_zero() {
+        ffmpeg_next::init().unwrap();
+        let dir = TempDir::new().unwrap();
+        let source = dir.path().join(SOURCE);
+        // six seconds at 10fps with a keyframe every second
+        let status = Command::new("ffmpeg")
+            .args([
+                "-y",
+                "-f",
+                "lavfi",
+                "-i",
+                "testsrc=duration=6:rate=10:size=320x240",
+            ])
+            .args([
+                "-c:v", "libx264", "-g", "10", "-bf", "0", "-pix_fmt", "yuv420p",
+            ])
+            .arg(&source)
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        // copy mode starts on the keyframe at or before the start, so 2.5s starts at 2s
+        let copied = dir.path().join("copied.mp4");
+        trim(
+            &source,
+            &copied,
+            2.5,
+            4.0,
+            &TrimMode::KeyframeCopy,
+            |_| {},
+            || false,
+        )
+        .unwrap();
+        let (duration, start) = duration_and_start(&copied);
+        assert!(
+            (duration - 2.0).abs() < 0.15,
+            "copied clip lasts {duration}s"
+        );
+        assert!(start.abs() < 0.05, "copied clip starts at {start}s");
+
+        let accurate = dir.path().join("accurate.mp4");
+        let mode = TrimMode::Accurate {
+            encoder: "libx264".to_string(),
+        };
+        trim(&source, &accurate, 2.5, 4.0, &mode, |_| {}, || false).unwrap();
+        let (duration, start) = duration_and_start(&accurate);
+        assert!(
+            (duration - 1.5).abs() < 0.15,
+            "accurate clip lasts {duration}s"
+        );
+        assert!(start.abs() < 0.05, "accurate clip starts at {start}s");
+
+        assert!(trim(&source, &accurate, 4.0, 2.0, &mode, |_| {}, || false).is_err());
+    }
+
+    #[test]
+    #[ignore = "needs ffmpeg"]
+    fn indexed_clip_is_protected_and_recorded_when_its_range_started() {
+        ffmpeg_next::init().unwrap();
+        let dir = TempDir::new().unwrap();
+        let source = dir.path().join(SOURCE);
+        let status = Command::new("ffmpeg")
+            .args([
+                "-y",
+                "-f",
+                "lavfi",
+                "-i",
+                "testsrc=duration=6:rate=10:size=320x240",
+            ])
+            .args(["-c:v", "libx264", "-g", "10", "-pix_fmt", "yuv420p"])
+            .arg(&source)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let catalog = Catalog::open(&dir.path().join("catalog.db")).unwrap();
+        let source_entry = CatalogEntry {
+            trigger: Some("gpio".to_string()),
+            ..probe_recording(&source, None).unwrap()
+        };
+        catalog.upsert(&source_entry).unwrap();
+
+        let partial = dir.path().join("clip.part");
+        trim(
+            &source,
+            &partial,
+            2.0,
+            4.0,
+            &TrimMode::KeyframeCopy,
+            |_| {},
+            || false,
+        )
+        .unwrap();
+        let clip = save_clip(&partial, &source, 2.0, 4.0).unwrap();
+        index_clip(&catalog, &source, &clip, (2.0, 4.0), "test").unwrap();
+
+        let clip_name = clip.file_name().unwrap().to_str().unwrap();
+        let entry = catalog.get(clip_name).unwrap().unwrap();
+        assert_eq!(entry.recorded, source_entry.recorded + 2);
+        assert_eq!(entry.trigger.as_deref(), Some("gpio"));
+        assert!(entry.protected);
+    }
+}
@@ -0,0 +1,160 @@
+use super::cache::DiskCache;
+use crate::storage::video_save_path;
+use anyhow::Result;
+use ffmpeg_next::{codec, encoder, format, media::Type, Dictionary};
+use std::{
+    env::var,
+    fs,
+    path::{Path, PathBuf},
+};
+use tokio::{sync::Mutex, task::spawn_blocking};
+use tracing::{error, info};
+
+pub const PLAYLIST_NAME: &str = "index.m3u8";
+const INIT_SEGMENT_NAME: &str = "init.mp4";
+const DEFAULT_HLS_CACHE_MAX_BYTES: u64 = 1024 * 1024 * 1024;
+
+/// HLS renditions of recordings, remuxed into fMP4 segments on first request and cached.
+///
+/// Each recording gets a directory holding its playlist, init segment and media segments.
+///
+/// Read from the environment:
+/// - `HLS_CACHE_PATH`: where packaged recordings are kept, defaults to `.hls` in the recordings directory.
+/// - `HLS_CACHE_MAX_BYTES`: size cap of the cache, defaults to 1GiB.
+/// - `HLS_SEGMENT_SECS`: target segment length, defaults to 4 seconds.
+pub struct HlsCache {
+    cache: DiskCache,
+    segment_secs: u32,
+    /// Packaging is quick, so recordings are packaged one at a time rather than tracked individually.
+    packaging: Mutex<()>,
+}
+
+impl HlsCache {
+    pub fn from_env() -> Self {
+        let dir = match var("HLS_CACHE_PATH") {
+            Ok(path) => PathBuf::from(path),
+            Err(_) => video_save_path().join(".hls"),
+        };
+        let max_bytes = var("HLS_CACHE_MAX_BYTES")
+            .ok()
+            .and_then(|bytes| bytes.parse().ok())
+            .unwrap_or(DEFAULT_HLS_CACHE_MAX_BYTES);
+        let segment_secs = var("HLS_SEGMENT_SECS")
+            .ok()
+            .and_then(|secs| secs.parse().ok())
+            .unwrap_or(4);
+        HlsCache {
+            cache: DiskCache::new(dir, max_bytes),
+            segment_secs,
+            packaging: Mutex::new(()),
+        }
+    }
+
+    /// Returns the directory holding the HLS files of the recording at `source`, packaging it first if needed.
+    pub async fn packaged(&self, source: &Path) -> Result<PathBuf> {
+        let name = package_name(source);
+        if let Some(dir) = self.cache.get(&name) {
+            return Ok(dir);
+        }
+
+        let _packaging = self.packaging.lock().await;
+        // another request may have packaged it while this one waited
+        if let Some(dir) = self.cache.get(&name) {
+            return Ok(dir);
+        }
+        let dir = self.cache.path(&name)?;
+        let partial = self.cache.path(&format!("{name}.part"))?;
+        let source = source.to_path_buf();
+        let segment_secs = self.segment_secs;
+        let packaged = spawn_blocking(move || {
+            if partial.exists() {
+                fs::remove_dir_all(&partial)?;
+            }
+            fs::create_dir_all(&partial)?;
+            let packaged = package_hls(&source, &partial, segment_secs)
+                .and_then(|_| Ok(fs::rename(&partial, &dir)?));
+            match packaged {
+                Ok(_) => Ok(dir),
+                Err(err) => {
+                    let _ = fs::remove_dir_all(&partial);
+                    Err(err)
+                }
+            }
+        })
+        .await??;
+        if let Err(err) = self.cache.evict() {
+            error!("Error evicting HLS packages from cache, error: {err}");
+        }
+        info!("Packaged {} for HLS", packaged.display());
+        Ok(packaged)
+    }
+}
+
+fn package_name(source: &Path) -> String {
+    source
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+/// Content type to serve a file from a HLS package with, `None` if the name isn't one the packager writes.
+pub fn content_type(name: &str) -> Option<&'static str> {
+    if name == PLAYLIST_NAME {
+        return Some("application/vnd.apple.mpegurl");
+    }
+    if name == INIT_SEGMENT_NAME {
+        return Some("video/mp4");
+    }
+    let index = name.strip_prefix("segment_")?.strip_suffix(".m4s")?;
+    if index.is_empty() || !index.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    Some("video/iso.segment")
+}
+
+/// Remuxes a recording into a VOD playlist of fMP4 segments without re-encoding.
+///
+/// Segments can only start on a keyframe, so their length follows the recording's keyframe interval.
+fn package_hls(source: &Path, dir: &Path, segment_secs: u32) -> Result<()> {
+    let mut input = format::input(&source)?;
+    let mut output = format::output_as(&dir.join(PLAYLIST_NAME), "hls")?;
+
+    let mut stream_mapping = vec![None; input.nb_streams() as usize];
+    for stream in input.streams() {
+        let medium = stream.parameters().medium();
+        if medium != Type::Video && medium != Type::Audio {
+            continue;
+        }
+        let mut output_stream = output.add_stream(encoder::find(codec::Id::None))?;
+        output_stream.set_parameters(stream.parameters());
+        // SAFETY: the parameters pointer is valid for the lifetime of the output stream
+        unsafe {
+            (*output_stream.parameters().as_mut_ptr()).codec_tag = 0;
+        }
+        stream_mapping[stream.index()] = Some(output_stream.index());
+    }
+
+    let mut options = Dictionary::new();
+    options.set("hls_time", &segment_secs.to_string());
+    options.set("hls_playlist_type", "vod");
+    options.set("hls_segment_type", "fmp4");
+    options.set("hls_fmp4_init_filename", INIT_SEGMENT_NAME);
+    options.set(
+        "hls_segment_filename",
+        &dir.join("segment_%03d.m4s").to_string_lossy(),
+    );
+    output.write_header_with(options)?;
+
+    for (stream, mut packet) in input.packets() {
+        let Some(output_index) = stream_mapping[stream.index()] else {
+            continue;
+        };
+        let output_time_base = output.stream(output_index).unwrap().time_base();
+        packet.rescale_ts(stream.time_base(), output_time_base);
+        packet.set_position(-1);
+        packet.set_stream(output_index);
+        packet.write_interleaved(&mut output)?;
+    }
+    output.write_trailer()?;
+    Ok(())
+}
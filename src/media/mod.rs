@@ -0,0 +1,7 @@
+pub mod cache;
+pub mod clip;
+pub mod hls;
+pub mod proxy;
+pub mod sprites;
+pub mod summary;
+pub mod thumbnails;
@@ -0,0 +1,341 @@
+use super::cache::DiskCache;
+use crate::app::jobs::{JobId, JobManager, JobOutput};
+use crate::storage::video_save_path;
+use anyhow::{anyhow, bail, Result};
+use ffmpeg_next::{
+    codec, decoder, encoder,
+    ffi::AV_TIME_BASE,
+    format::{self, Pixel},
+    frame,
+    media::Type,
+    picture,
+    software::scaling,
+    Dictionary, Packet, Rational,
+};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    env::var,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+use tokio::task::spawn_blocking;
+use tracing::{error, info};
+
+const DEFAULT_PROXY_CACHE_MAX_BYTES: u64 = 2 * 1024 * 1024 * 1024;
+
+/// Renditions a recording can be transcoded to for playback over slow connections.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyQuality {
+    Low,
+    Medium,
+}
+
+impl ProxyQuality {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ProxyQuality::Low => "low",
+            ProxyQuality::Medium => "medium",
+        }
+    }
+
+    fn height(&self) -> u32 {
+        match self {
+            ProxyQuality::Low => 360,
+            ProxyQuality::Medium => 720,
+        }
+    }
+
+    fn bit_rate(&self) -> usize {
+        match self {
+            ProxyQuality::Low => 500_000,
+            ProxyQuality::Medium => 1_500_000,
+        }
+    }
+}
+
+/// Lower bitrate copies of recordings, made on first request and kept in a size capped cache.
+///
+/// Read from the environment:
+/// - `PROXY_CACHE_PATH`: where proxies are kept, defaults to `.proxies` in the recordings directory.
+/// - `PROXY_CACHE_MAX_BYTES`: size cap of the cache, defaults to 2GiB.
+/// - `PROXY_ENCODER`: ffmpeg H264 encoder to use, defaults to `libx264`.
+pub struct ProxyCache {
+    cache: DiskCache,
+    encoder: String,
+    /// Proxies being generated, keyed by cache name, so each is only transcoded once.
+    pending: Mutex<HashMap<String, JobId>>,
+}
+
+impl ProxyCache {
+    pub fn from_env() -> Self {
+        let dir = match var("PROXY_CACHE_PATH") {
+            Ok(path) => PathBuf::from(path),
+            Err(_) => video_save_path().join(".proxies"),
+        };
+        let max_bytes = var("PROXY_CACHE_MAX_BYTES")
+            .ok()
+            .and_then(|bytes| bytes.parse().ok())
+            .unwrap_or(DEFAULT_PROXY_CACHE_MAX_BYTES);
+        ProxyCache {
+            cache: DiskCache::new(dir, max_bytes),
+            encoder: var("PROXY_ENCODER").unwrap_or("libx264".to_string()),
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn cached(&self, file_name: &str, quality: ProxyQuality) -> Option<PathBuf> {
+        self.cache.get(&proxy_name(file_name, quality))
+    }
+
+    /// Starts transcoding a proxy of the recording at `source`, or returns the job already doing so.
+    ///
+    /// A job started by another session is shared with `owner` so it can follow it and fetch the proxy.
+    pub fn generate(
+        self: &Arc<Self>,
+        jobs: &JobManager,
+        owner: &str,
+        source: PathBuf,
+        quality: ProxyQuality,
+    ) -> JobId {
+        let file_name = source
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or_default();
+        let name = proxy_name(file_name, quality);
+        // held while spawning so the job can't finish and clear its entry before it is added
+        let mut pending = self.pending.lock().unwrap();
+        if let Some(job_id) = pending.get(&name) {
+            jobs.share(*job_id, owner);
+            return *job_id;
+        }
+
+        let proxies = self.clone();
+        let pending_name = name.clone();
+        let job_id = jobs.spawn(owner, "transcode", move |job| async move {
+            let _pending = PendingProxy {
+                proxies: proxies.clone(),
+                name: pending_name.clone(),
+                job_id: job.id,
+            };
+            let output = proxies.cache.path(&pending_name)?;
+            let partial = output.with_extension(format!("{}.part", job.id));
+            let encoder = proxies.encoder.clone();
+            let output = spawn_blocking(move || {
+                let transcoded = transcode(
+                    &source,
+                    &partial,
+                    quality,
+                    &encoder,
+                    |progress| job.set_progress(progress),
+                    || job.is_cancelled(),
+                );
+                match transcoded.and_then(|_| Ok(fs::rename(&partial, &output)?)) {
+                    Ok(_) => Ok(output),
+                    Err(err) => {
+                        let _ = fs::remove_file(&partial);
+                        Err(err)
+                    }
+                }
+            })
+            .await??;
+            if let Err(err) = proxies.cache.evict() {
+                error!("Error evicting proxies from cache, error: {err}");
+            }
+            info!("Transcoded proxy {}", output.display());
+            Ok(JobOutput::File(output))
+        });
+        pending.insert(name, job_id);
+        job_id
+    }
+}
+
+/// Clears a proxy's pending entry when its job ends, including when the job is cancelled.
+struct PendingProxy {
+    proxies: Arc<ProxyCache>,
+    name: String,
+    job_id: JobId,
+}
+
+impl Drop for PendingProxy {
+    fn drop(&mut self) {
+        let mut pending = self.proxies.pending.lock().unwrap();
+        if pending.get(&self.name) == Some(&self.job_id) {
+            pending.remove(&self.name);
+        }
+    }
+}
+
+fn proxy_name(file_name: &str, quality: ProxyQuality) -> String {
+    let stem = Path::new(file_name)
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or(file_name);
+    format!("{stem}_{}.mp4", quality.as_str())
+}
+
+/// Scales `width` x `height` down to `max_height`, keeping the aspect ratio and even dimensions.
+fn scaled_size(width: u32, height: u32, max_height: u32) -> (u32, u32) {
+    if height <= max_height || height == 0 {
+        return (width & !1, height & !1);
+    }
+    let scaled_width = (width as u64 * max_height as u64 / height as u64) as u32;
+    (scaled_width & !1, max_height & !1)
+}
+
+/// Decodes, scales and re-encodes the video stream, writing encoded packets to the output.
+struct VideoTranscoder {
+    decoder: decoder::Video,
+    scaler: scaling::Context,
+    encoder: encoder::Video,
+    input_time_base: Rational,
+    output_index: usize,
+    output_time_base: Rational,
+}
+
+impl VideoTranscoder {
+    fn receive_frames(&mut self, output: &mut format::context::Output) -> Result<()> {
+        let mut decoded = frame::Video::empty();
+        while self.decoder.receive_frame(&mut decoded).is_ok() {
+            let mut scaled = frame::Video::empty();
+            self.scaler.run(&decoded, &mut scaled)?;
+            scaled.set_pts(decoded.timestamp());
+            scaled.set_kind(picture::Type::None);
+            self.encoder.send_frame(&scaled)?;
+            self.receive_packets(output)?;
+        }
+        Ok(())
+    }
+
+    fn receive_packets(&mut self, output: &mut format::context::Output) -> Result<()> {
+        let mut encoded = Packet::empty();
+        while self.encoder.receive_packet(&mut encoded).is_ok() {
+            encoded.set_stream(self.output_index);
+            encoded.rescale_ts(self.input_time_base, self.output_time_base);
+            encoded.write_interleaved(output)?;
+        }
+        Ok(())
+    }
+}
+
+/// Re-encodes a recording at a lower resolution and bitrate, copying the audio as is.
+pub fn transcode(
+    source: &Path,
+    destination: &Path,
+    quality: ProxyQuality,
+    encoder_name: &str,
+    progress: impl Fn(f32),
+    cancelled: impl Fn() -> bool,
+) -> Result<()> {
+    let mut input = format::input(&source)?;
+    let mut output = format::output_as(&destination, "mp4")?;
+    let duration = input.duration() as f64 / AV_TIME_BASE as f64;
+
+    let (video_index, input_time_base, decoder) = {
+        let stream = input
+            .streams()
+            .best(Type::Video)
+            .ok_or_else(|| anyhow!("{} has no video stream", source.display()))?;
+        let decoder = codec::context::Context::from_parameters(stream.parameters())?
+            .decoder()
+            .video()?;
+        (stream.index(), stream.time_base(), decoder)
+    };
+    let (width, height) = scaled_size(decoder.width(), decoder.height(), quality.height());
+
+    let codec = encoder::find_by_name(encoder_name)
+        .ok_or_else(|| anyhow!("Encoder {encoder_name} is not available"))?;
+    let mut video_encoder = codec::context::Context::new_with_codec(codec)
+        .encoder()
+        .video()?;
+    video_encoder.set_width(width);
+    video_encoder.set_height(height);
+    video_encoder.set_format(Pixel::YUV420P);
+    video_encoder.set_aspect_ratio(decoder.aspect_ratio());
+    video_encoder.set_frame_rate(decoder.frame_rate());
+    video_encoder.set_time_base(input_time_base);
+    video_encoder.set_bit_rate(quality.bit_rate());
+    video_encoder.set_max_bit_rate(quality.bit_rate() * 2);
+    if output
+        .format()
+        .flags()
+        .contains(format::Flags::GLOBAL_HEADER)
+    {
+        video_encoder.set_flags(codec::Flags::GLOBAL_HEADER);
+    }
+    let mut encoder_options = Dictionary::new();
+    if encoder_name == "libx264" {
+        encoder_options.set("preset", "veryfast");
+    }
+    let video_encoder = video_encoder.open_with(encoder_options)?;
+    let output_index = {
+        let mut stream = output.add_stream(codec)?;
+        stream.set_parameters(&video_encoder);
+        stream.index()
+    };
+
+    let audio = match input.streams().best(Type::Audio) {
+        Some(stream) => {
+            let mut output_stream = output.add_stream(encoder::find(codec::Id::None))?;
+            output_stream.set_parameters(stream.parameters());
+            // SAFETY: the parameters pointer is valid for the lifetime of the output stream
+            unsafe {
+                (*output_stream.parameters().as_mut_ptr()).codec_tag = 0;
+            }
+            Some((stream.index(), output_stream.index()))
+        }
+        None => None,
+    };
+
+    let mut header_options = Dictionary::new();
+    header_options.set("movflags", "+faststart");
+    output.write_header_with(header_options)?;
+
+    let scaler = scaling::Context::get(
+        decoder.format(),
+        decoder.width(),
+        decoder.height(),
+        Pixel::YUV420P,
+        width,
+        height,
+        scaling::Flags::BILINEAR,
+    )?;
+    let mut video = VideoTranscoder {
+        decoder,
+        scaler,
+        encoder: video_encoder,
+        input_time_base,
+        output_index,
+        output_time_base: output.stream(output_index).unwrap().time_base(),
+    };
+
+    for (stream, mut packet) in input.packets() {
+        if cancelled() {
+            bail!("Transcode of {} cancelled", source.display());
+        }
+        if stream.index() == video_index {
+            video.decoder.send_packet(&packet)?;
+            video.receive_frames(&mut output)?;
+            if let Some(pts) = packet.pts().filter(|_| duration > 0.0) {
+                progress((pts as f64 * f64::from(input_time_base) / duration) as f32);
+            }
+        } else if let Some((_, audio_output_index)) =
+            audio.filter(|(audio_index, _)| *audio_index == stream.index())
+        {
+            let audio_time_base = output.stream(audio_output_index).unwrap().time_base();
+            packet.rescale_ts(stream.time_base(), audio_time_base);
+            packet.set_position(-1);
+            packet.set_stream(audio_output_index);
+            packet.write_interleaved(&mut output)?;
+        }
+    }
+
+    video.decoder.send_eof()?;
+    video.receive_frames(&mut output)?;
+    video.encoder.send_eof()?;
+    video.receive_packets(&mut output)?;
+    output.write_trailer()?;
+    Ok(())
+}
@@ -0,0 +1,312 @@
+use super::{
+    cache::DiskCache,
+    thumbnails::{duration_secs, frames_only, grab_frames, tile_frames, write_jpeg},
+};
+use crate::storage::video_save_path;
+use anyhow::{bail, Result};
+use ffmpeg_next::format::Pixel;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use std::{
+    env::var,
+    fmt::Write,
+    fs,
+    path::{Path, PathBuf},
+};
+use tokio::{sync::Mutex, task::spawn_blocking};
+use tracing::{error, info};
+
+pub const SPRITE_SHEET_NAME: &str = "sprites.jpg";
+pub const SPRITE_VTT_NAME: &str = "sprites.vtt";
+const DEFAULT_SPRITE_CACHE_MAX_BYTES: u64 = 256 * 1024 * 1024;
+/// Characters escaped in a query string value, all but the unreserved ones.
+const QUERY_VALUE: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+/// Keeps sheets of long recordings a sensible size, the interval is stretched to stay under it.
+const MAX_SPRITE_TILES: usize = 400;
+
+/// Sprite sheets of recordings for scrubbing previews, made on first request and cached.
+///
+/// Each recording gets a directory holding a single JPEG of thumbnails taken at a fixed interval,
+/// and a WebVTT file mapping each interval to its thumbnail with the `#xywh=` fragment web players
+/// understand.
+///
+/// Read from the environment:
+/// - `SPRITE_CACHE_PATH`: where sheets are kept, defaults to `.sprites` in the recordings directory.
+/// - `SPRITE_CACHE_MAX_BYTES`: size cap of the cache, defaults to 256MiB.
+/// - `SPRITE_INTERVAL_SECS`: time between thumbnails, defaults to 5 seconds.
+/// - `SPRITE_TILE_WIDTH`: width of each thumbnail, defaults to 160 pixels.
+/// - `SPRITE_COLUMNS`: thumbnails per row of the sheet, defaults to 10.
+pub struct SpriteCache {
+    cache: DiskCache,
+    interval_secs: f64,
+    tile_width: u32,
+    columns: usize,
+    /// Sheets decode a frame per interval, so only one is made at a time.
+    generating: Mutex<()>,
+}
+
+impl SpriteCache {
+    pub fn from_env() -> Self {
+        let dir = match var("SPRITE_CACHE_PATH") {
+            Ok(path) => PathBuf::from(path),
+            Err(_) => video_save_path().join(".sprites"),
+        };
+        let max_bytes = var("SPRITE_CACHE_MAX_BYTES")
+            .ok()
+            .and_then(|bytes| bytes.parse().ok())
+            .unwrap_or(DEFAULT_SPRITE_CACHE_MAX_BYTES);
+        let interval_secs = var("SPRITE_INTERVAL_SECS")
+            .ok()
+            .and_then(|secs| secs.parse().ok())
+            .filter(|secs: &f64| *secs > 0.0)
+            .unwrap_or(5.0);
+        let tile_width = var("SPRITE_TILE_WIDTH")
+            .ok()
+            .and_then(|width| width.parse().ok())
+            .unwrap_or(160);
+        let columns = var("SPRITE_COLUMNS")
+            .ok()
+            .and_then(|columns| columns.parse().ok())
+            .filter(|columns: &usize| *columns > 0)
+            .unwrap_or(10);
+        SpriteCache {
+            cache: DiskCache::new(dir, max_bytes),
+            interval_secs,
+            tile_width,
+            columns,
+            generating: Mutex::new(()),
+        }
+    }
+
+    /// Returns the directory holding the sprite sheet and WebVTT file of the recording at
+    /// `source`, making them first if needed.
+    ///
+    /// Cues in the WebVTT file point at the sheet relative to the file itself, so both must be
+    /// served from the same directory.
+    pub async fn sprites(&self, source: &Path, file_name: &str) -> Result<PathBuf> {
+        let name = sprites_name(source);
+        if let Some(dir) = self.cache.get(&name) {
+            return Ok(dir);
+        }
+
+        let _generating = self.generating.lock().await;
+        // another request may have made them while this one waited
+        if let Some(dir) = self.cache.get(&name) {
+            return Ok(dir);
+        }
+        let dir = self.cache.path(&name)?;
+        let partial = self.cache.path(&format!("{name}.part"))?;
+        let source = source.to_path_buf();
+        let sheet_url = format!(
+            "{SPRITE_SHEET_NAME}?filename={}",
+            utf8_percent_encode(file_name, QUERY_VALUE)
+        );
+        let (interval_secs, tile_width, columns) =
+            (self.interval_secs, self.tile_width, self.columns);
+        let made = spawn_blocking(move || {
+            if partial.exists() {
+                fs::remove_dir_all(&partial)?;
+            }
+            fs::create_dir_all(&partial)?;
+            let made = make_sprites(
+                &source,
+                &partial,
+                &sheet_url,
+                interval_secs,
+                tile_width,
+                columns,
+            )
+            .and_then(|_| Ok(fs::rename(&partial, &dir)?));
+            match made {
+                Ok(_) => Ok(dir),
+                Err(err) => {
+                    let _ = fs::remove_dir_all(&partial);
+                    Err(err)
+                }
+            }
+        })
+        .await??;
+        if let Err(err) = self.cache.evict() {
+            error!("Error evicting sprite sheets from cache, error: {err}");
+        }
+        info!("Made sprite sheet {}", made.display());
+        Ok(made)
+    }
+}
+
+fn sprites_name(source: &Path) -> String {
+    source
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+/// Writes the sheet of thumbnails and the WebVTT file indexing it into `dir`.
+fn make_sprites(
+    source: &Path,
+    dir: &Path,
+    sheet_url: &str,
+    interval_secs: f64,
+    tile_width: u32,
+    columns: usize,
+) -> Result<()> {
+    let duration = duration_secs(source)?;
+    let interval_secs = interval_secs.max(duration / MAX_SPRITE_TILES as f64);
+    let count = ((duration / interval_secs).ceil() as usize).max(1);
+    let times: Vec<f64> = (0..count)
+        .map(|index| index as f64 * interval_secs)
+        .collect();
+    let frames = grab_frames(source, &times, tile_width, Pixel::YUVJ420P)?;
+    if frames.is_empty() {
+        bail!("No frames found in {}", source.display());
+    }
+    let (width, height) = (frames[0].1.width(), frames[0].1.height());
+    let frame_times: Vec<f64> = frames.iter().map(|(time, _)| *time).collect();
+    write_jpeg(
+        &tile_frames(&frames_only(frames), columns)?,
+        &dir.join(SPRITE_SHEET_NAME),
+    )?;
+    fs::write(
+        dir.join(SPRITE_VTT_NAME),
+        sprite_vtt(&frame_times, duration, sheet_url, columns, width, height)?,
+    )?;
+    Ok(())
+}
+
+/// WebVTT file with a cue per tile of the sheet, `times` being when each tile's frame was taken.
+///
+/// A cue lasts until the next tile's frame, so one missing from the middle of the recording leaves
+/// the tile before it standing in for the gap rather than shifting every later cue.
+fn sprite_vtt(
+    times: &[f64],
+    duration: f64,
+    sheet_url: &str,
+    columns: usize,
+    width: u32,
+    height: u32,
+) -> Result<String> {
+    let mut vtt = String::from("WEBVTT\n");
+    for (index, &start) in times.iter().enumerate() {
+        // the last thumbnail stands for the rest of the recording
+        let end = match times.get(index + 1) {
+            Some(&next) => next,
+            None => duration.max(start),
+        };
+        let x = (index % columns) as u32 * width;
+        let y = (index / columns) as u32 * height;
+        write!(
+            vtt,
+            "\n{} --> {}\n{sheet_url}#xywh={x},{y},{width},{height}\n",
+            vtt_timestamp(start),
+            vtt_timestamp(end)
+        )?;
+    }
+    Ok(vtt)
+}
+
+/// Content type to serve a file from a recording's sprite directory with, `None` if it isn't one.
+pub fn content_type(name: &str) -> Option<&'static str> {
+    match name {
+        SPRITE_SHEET_NAME => Some("image/jpeg"),
+        SPRITE_VTT_NAME => Some("text/vtt"),
+        _ => None,
+    }
+}
+
+/// Formats seconds as a WebVTT timestamp, `hh:mm:ss.ttt`.
+fn vtt_timestamp(secs: f64) -> String {
+    let millis = (secs * 1000.0).round() as u64;
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        millis % 1000
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+    use tempfile::TempDir;
+
+    #[test]
+    fn vtt_timestamp_rounds_to_milliseconds() {
+        assert_eq!(vtt_timestamp(0.0), "00:00:00.000");
+        assert_eq!(vtt_timestamp(5.0004), "00:00:05.000");
+        assert_eq!(vtt_timestamp(5.0006), "00:00:05.001");
+        assert_eq!(vtt_timestamp(59.9999), "00:01:00.000");
+        assert_eq!(vtt_timestamp(3_723.456), "01:02:03.456");
+        assert_eq!(vtt_timestamp(100.0 * 3600.0), "100:00:00.000");
+    }
+
+    #[test]
+    fn cues_fill_rows_left_to_right() {
+        let vtt = sprite_vtt(&[0.0, 5.0, 10.0, 15.0, 20.0], 22.5, "s.jpg", 2, 160, 90).unwrap();
+        assert_eq!(
+            vtt,
+            "WEBVTT\n\
+             \n00:00:00.000 --> 00:00:05.000\ns.jpg#xywh=0,0,160,90\n\
+             \n00:00:05.000 --> 00:00:10.000\ns.jpg#xywh=160,0,160,90\n\
+             \n00:00:10.000 --> 00:00:15.000\ns.jpg#xywh=0,90,160,90\n\
+             \n00:00:15.000 --> 00:00:20.000\ns.jpg#xywh=160,90,160,90\n\
+             \n00:00:20.000 --> 00:00:22.500\ns.jpg#xywh=0,180,160,90\n"
+        );
+    }
+
+    #[test]
+    fn last_cue_never_ends_before_it_starts() {
+        let vtt = sprite_vtt(&[0.0, 5.0], 4.0, "s.jpg", 10, 160, 90).unwrap();
+        assert!(vtt.ends_with("\n00:00:05.000 --> 00:00:05.000\ns.jpg#xywh=160,0,160,90\n"));
+    }
+
+    #[test]
+    fn missing_frame_stretches_cue_before_it() {
+        // no frame was found for 10s, so the tile taken at 5s covers until the one taken at 15s
+        let vtt = sprite_vtt(&[0.0, 5.0, 15.0], 20.0, "s.jpg", 10, 160, 90).unwrap();
+        assert!(vtt.contains("\n00:00:05.000 --> 00:00:15.000\ns.jpg#xywh=160,0,160,90\n"));
+        assert!(vtt.contains("\n00:00:15.000 --> 00:00:20.000\ns.jpg#xywh=320,0,160,90\n"));
+    }
+
+    #[test]
+    #[ignore = "needs ffmpeg"]
+    fn sheet_has_a_tile_per_cue() {
+        ffmpeg_next::init().unwrap();
+        let dir = TempDir::new().unwrap();
+        let source = dir.path().join("motion.mp4");
+        let status = Command::new("ffmpeg")
+            .args([
+                "-y",
+                "-f",
+                "lavfi",
+                "-i",
+                "testsrc=duration=12:rate=10:size=320x240",
+            ])
+            .args(["-c:v", "libx264", "-g", "10", "-pix_fmt", "yuv420p"])
+            .arg(&source)
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        make_sprites(&source, dir.path(), "s.jpg", 5.0, 160, 2).unwrap();
+        let vtt = fs::read_to_string(dir.path().join(SPRITE_VTT_NAME)).unwrap();
+        assert!(vtt.contains("\n00:00:00.000 --> 00:00:05.000\ns.jpg#xywh=0,0,160,120\n"));
+        assert!(vtt.contains("\n00:00:05.000 --> 00:00:10.000\ns.jpg#xywh=160,0,160,120\n"));
+        assert!(vtt.contains("\n00:00:10.000 --> 00:00:12.000\ns.jpg#xywh=0,120,160,120\n"));
+        let input = ffmpeg_next::format::input(&dir.path().join(SPRITE_SHEET_NAME)).unwrap();
+        let sheet = input
+            .streams()
+            .best(ffmpeg_next::media::Type::Video)
+            .unwrap();
+        let decoder = ffmpeg_next::codec::context::Context::from_parameters(sheet.parameters())
+            .unwrap()
+            .decoder()
+            .video()
+            .unwrap();
+        assert_eq!((decoder.width(), decoder.height()), (320, 240));
+    }
+}
@@ -0,0 +1,593 @@
+use crate::app::jobs::{JobId, JobManager, JobOutput};
+use crate::camera::overlay::{escape_drawtext, quote_option};
+use crate::storage::{
+    audit,
+    catalog::{probe_recording, Catalog, CatalogEntry},
+    store::RecordingStore,
+    CLIP_MARKER,
+};
+use crate::timezone::{day_bounds, local_now, local_timezone, next_local_hour};
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, NaiveDate, TimeDelta};
+use chrono_tz::Tz;
+use ffmpeg_next::{
+    codec, decoder, encoder, filter,
+    format::{self, Pixel},
+    frame,
+    media::Type,
+    picture,
+    software::scaling,
+    Dictionary, Packet, Rational,
+};
+use std::{
+    env::var,
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{task::spawn_blocking, time::sleep};
+use tracing::{error, info};
+
+pub const SUMMARY_PREFIX: &str = "summary_";
+/// Format of the local date in summary file names, `summary_2025-06-01.mp4`.
+pub const SUMMARY_DATE_FORMAT: &str = "%Y-%m-%d";
+/// How long the caption card before each clip is shown, in seconds.
+const CAPTION_SECS: f64 = 1.5;
+pub const MAX_SPEED: f64 = 32.0;
+
+/// How a day's summary is put together.
+///
+/// Read from the environment for nightly summaries and as defaults for on demand ones:
+/// - `SUMMARY_SPEED`: how many times faster than real time clips play, defaults to 1.
+/// - `SUMMARY_CAPTIONS`: `false` to leave out the caption card before each clip.
+/// - `SUMMARY_ENCODER`: ffmpeg H264 encoder to use, defaults to `libx264`.
+/// - `SUMMARY_FONT_FILE`: font for captions, falling back to `OVERLAY_FONT_FILE`.
+#[derive(Clone, Debug)]
+pub struct SummaryOptions {
+    pub speed: f64,
+    pub captions: bool,
+    pub encoder: String,
+    pub font_file: Option<String>,
+}
+
+impl SummaryOptions {
+    pub fn from_env() -> Self {
+        SummaryOptions {
+            speed: var("SUMMARY_SPEED")
+                .ok()
+                .and_then(|speed| speed.parse().ok())
+                .unwrap_or(1.0_f64)
+                .clamp(1.0, MAX_SPEED),
+            captions: var("SUMMARY_CAPTIONS").map_or(true, |captions| captions != "false"),
+            encoder: var("SUMMARY_ENCODER").unwrap_or("libx264".to_string()),
+            font_file: var("SUMMARY_FONT_FILE")
+                .or_else(|_| var("OVERLAY_FONT_FILE"))
+                .ok()
+                .filter(|font_file| !font_file.trim().is_empty()),
+        }
+    }
+}
+
+/// File name of the summary of `date`, it sits with the recordings so it is listed with them.
+pub fn summary_name(date: NaiveDate) -> String {
+    format!("{SUMMARY_PREFIX}{}.mp4", date.format(SUMMARY_DATE_FORMAT))
+}
+
+/// Starts a job concatenating the recordings of `date` into one summary video.
+///
+/// The summary replaces any earlier one for the same day and is added to the catalog.
+pub fn spawn_summary(
+    jobs: &JobManager,
+    catalog: Arc<Catalog>,
+    store: Arc<RecordingStore>,
+    owner: &str,
+    date: NaiveDate,
+    options: SummaryOptions,
+) -> JobId {
+    let actor = owner.to_string();
+    jobs.spawn(owner, "summary", move |job| async move {
+        let (day_start, day_end) = day_bounds(date);
+        let entries: Vec<CatalogEntry> = catalog
+            .recorded_between(day_start, day_end)?
+            .into_iter()
+            // earlier summaries and saved clips repeat footage of the day's recordings
+            .filter(|entry| {
+                !entry.file_name.starts_with(SUMMARY_PREFIX)
+                    && !entry.file_name.contains(CLIP_MARKER)
+            })
+            .collect();
+        let clips: Vec<SummaryClip> = entries
+            .iter()
+            .filter_map(|entry| {
+                let path = store.resolve(&entry.file_name).ok()?;
+                Some(SummaryClip {
+                    path,
+                    caption: caption(entry),
+                })
+            })
+            .collect();
+        if clips.is_empty() {
+            bail!("No recordings on {date} to summarise");
+        }
+
+        let output = store.root().join(summary_name(date));
+        let partial = output.with_extension(format!("{}.part", job.id));
+        let rendered = spawn_blocking(move || {
+            let rendered = render_summary(
+                &clips,
+                &partial,
+                &options,
+                |progress| job.set_progress(progress),
+                || job.is_cancelled(),
+            );
+            match rendered.and_then(|_| Ok(fs::rename(&partial, &output)?)) {
+                Ok(_) => Ok((output, clips.len())),
+                Err(err) => {
+                    let _ = fs::remove_file(&partial);
+                    Err(err)
+                }
+            }
+        })
+        .await?;
+        let (output, clip_count) = rendered?;
+
+        let mut entry = probe_recording(&output, Some("summary".to_string()))?;
+        entry.recorded = day_start;
+        catalog.upsert(&entry)?;
+        audit::record(
+            &actor,
+            "summary",
+            &output,
+            &format!("{clip_count} recordings from {date}"),
+        );
+        info!("Summarised {clip_count} recordings from {date}");
+        Ok(JobOutput::File(output))
+    })
+}
+
+/// Text shown on the card before a clip, its local start time and what triggered it.
+fn caption(entry: &CatalogEntry) -> String {
+    caption_in(local_timezone(), entry)
+}
+
+fn caption_in(timezone: Tz, entry: &CatalogEntry) -> String {
+    let time = DateTime::from_timestamp(entry.recorded, 0)
+        .unwrap_or_default()
+        .with_timezone(&timezone)
+        .format("%H:%M:%S")
+        .to_string();
+    match &entry.trigger {
+        Some(trigger) => format!("{time}  {trigger}"),
+        None => time,
+    }
+}
+
+/// Makes yesterday's summary every night, at the local hour set with `SUMMARY_NIGHTLY_HOUR`.
+///
+/// Nothing is scheduled when the variable isn't set.
+pub async fn summary_task(
+    jobs: Arc<JobManager>,
+    catalog: Arc<Catalog>,
+    store: Arc<RecordingStore>,
+) {
+    let Some(hour) = var("SUMMARY_NIGHTLY_HOUR")
+        .ok()
+        .and_then(|hour| hour.parse::<u32>().ok())
+        .filter(|hour| *hour < 24)
+    else {
+        return;
+    };
+    loop {
+        let wait = (next_local_hour(hour) - local_now())
+            .to_std()
+            .unwrap_or(Duration::from_secs(60 * 60));
+        sleep(wait).await;
+
+        let yesterday = local_now().date_naive() - TimeDelta::days(1);
+        let job_id = spawn_summary(
+            &jobs,
+            catalog.clone(),
+            store.clone(),
+            "summary",
+            yesterday,
+            SummaryOptions::from_env(),
+        );
+        info!("Started nightly summary of {yesterday}, job {job_id}");
+    }
+}
+
+struct SummaryClip {
+    path: PathBuf,
+    caption: String,
+}
+
+/// Encodes frames from every clip into the summary at a steady position on its timeline.
+struct SummaryWriter {
+    encoder: encoder::Video,
+    output_index: usize,
+    /// The encoder's time base, timestamps are in milliseconds.
+    time_base: Rational,
+    output_time_base: Rational,
+    width: u32,
+    height: u32,
+    frame_rate: u32,
+    /// Timeline position the next frame may be placed at, in milliseconds.
+    position_ms: f64,
+}
+
+impl SummaryWriter {
+    fn frame_interval_ms(&self) -> f64 {
+        1000.0 / self.frame_rate as f64
+    }
+
+    /// Encodes `frame` at `at_ms`, frames arriving faster than the frame rate are dropped.
+    fn push(
+        &mut self,
+        frame: &mut frame::Video,
+        at_ms: f64,
+        output: &mut format::context::Output,
+    ) -> Result<()> {
+        if at_ms < self.position_ms {
+            return Ok(());
+        }
+        frame.set_pts(Some(at_ms as i64));
+        frame.set_kind(picture::Type::None);
+        self.encoder.send_frame(frame)?;
+        self.receive_packets(output)?;
+        self.position_ms = at_ms + self.frame_interval_ms();
+        Ok(())
+    }
+
+    fn receive_packets(&mut self, output: &mut format::context::Output) -> Result<()> {
+        let mut encoded = Packet::empty();
+        while self.encoder.receive_packet(&mut encoded).is_ok() {
+            encoded.set_stream(self.output_index);
+            encoded.rescale_ts(self.time_base, self.output_time_base);
+            encoded.write_interleaved(output)?;
+        }
+        Ok(())
+    }
+
+    fn write_caption(
+        &mut self,
+        text: &str,
+        font_file: Option<&str>,
+        output: &mut format::context::Output,
+    ) -> Result<()> {
+        let mut spec = format!(
+            "color=c=black:s={}x{}:r={}:d={CAPTION_SECS},drawtext=text={}:x=(w-tw)/2:y=(h-th)/2:fontsize={}:fontcolor=white",
+            self.width,
+            self.height,
+            self.frame_rate,
+            quote_option(&escape_drawtext(text)),
+            (self.height / 12).max(12)
+        );
+        if let Some(font_file) = font_file {
+            spec.push_str(&format!(":fontfile={}", quote_option(font_file)));
+        }
+        spec.push_str(",format=yuv420p");
+
+        let mut graph = filter::Graph::new();
+        let sink =
+            filter::find("buffersink").ok_or_else(|| anyhow!("buffersink filter missing"))?;
+        graph.add(&sink, "out", "")?;
+        graph.input("out", 0)?.parse(&spec)?;
+        graph.validate()?;
+
+        let start_ms = self.position_ms;
+        let mut card = frame::Video::empty();
+        let mut index = 0;
+        while graph.get("out").unwrap().sink().frame(&mut card).is_ok() {
+            let at_ms = start_ms + index as f64 * self.frame_interval_ms();
+            self.push(&mut card, at_ms, output)?;
+            index += 1;
+        }
+        Ok(())
+    }
+
+    /// Adds every frame of the clip at `path`, played `speed` times faster than real time.
+    fn write_clip(
+        &mut self,
+        path: &Path,
+        speed: f64,
+        output: &mut format::context::Output,
+        cancelled: &impl Fn() -> bool,
+    ) -> Result<()> {
+        let mut input = format::input(&path)?;
+        let (video_index, time_base, decoder) = {
+            let stream = input
+                .streams()
+                .best(Type::Video)
+                .ok_or_else(|| anyhow!("{} has no video stream", path.display()))?;
+            let decoder = codec::context::Context::from_parameters(stream.parameters())?
+                .decoder()
+                .video()?;
+            (stream.index(), stream.time_base(), decoder)
+        };
+        let scaler = scaling::Context::get(
+            decoder.format(),
+            decoder.width(),
+            decoder.height(),
+            Pixel::YUV420P,
+            self.width,
+            self.height,
+            scaling::Flags::BILINEAR,
+        )?;
+
+        let mut frames = ClipFrames {
+            decoder,
+            scaler,
+            time_base,
+            speed,
+            start_ms: self.position_ms,
+            first_pts: None,
+        };
+        for (stream, packet) in input.packets() {
+            if cancelled() {
+                bail!("Summary cancelled");
+            }
+            if stream.index() != video_index {
+                continue;
+            }
+            frames.decoder.send_packet(&packet)?;
+            frames.drain(self, output)?;
+        }
+        frames.decoder.send_eof()?;
+        frames.drain(self, output)?;
+        Ok(())
+    }
+}
+
+/// Decodes one clip and places its frames on the summary's timeline.
+struct ClipFrames {
+    decoder: decoder::Video,
+    scaler: scaling::Context,
+    time_base: Rational,
+    speed: f64,
+    /// Where on the summary's timeline the clip starts, in milliseconds.
+    start_ms: f64,
+    first_pts: Option<i64>,
+}
+
+impl ClipFrames {
+    fn drain(
+        &mut self,
+        writer: &mut SummaryWriter,
+        output: &mut format::context::Output,
+    ) -> Result<()> {
+        let mut decoded = frame::Video::empty();
+        while self.decoder.receive_frame(&mut decoded).is_ok() {
+            let Some(pts) = decoded.timestamp() else {
+                continue;
+            };
+            let first = *self.first_pts.get_or_insert(pts);
+            let offset_ms = (pts - first) as f64 * f64::from(self.time_base) * 1000.0 / self.speed;
+            let mut scaled = frame::Video::empty();
+            self.scaler.run(&decoded, &mut scaled)?;
+            writer.push(&mut scaled, self.start_ms + offset_ms, output)?;
+        }
+        Ok(())
+    }
+}
+
+/// Re-encodes the clips one after another into a single silent video, each optionally preceded by
+/// a caption card. Clips are scaled to the size of the first one.
+fn render_summary(
+    clips: &[SummaryClip],
+    destination: &Path,
+    options: &SummaryOptions,
+    progress: impl Fn(f32),
+    cancelled: impl Fn() -> bool,
+) -> Result<()> {
+    let (width, height, frame_rate) = {
+        let input = format::input(&clips[0].path)?;
+        let stream = input
+            .streams()
+            .best(Type::Video)
+            .ok_or_else(|| anyhow!("{} has no video stream", clips[0].path.display()))?;
+        let decoder = codec::context::Context::from_parameters(stream.parameters())?
+            .decoder()
+            .video()?;
+        let rate = stream.avg_frame_rate();
+        let frame_rate = if rate.denominator() > 0 && rate.numerator() > 0 {
+            (f64::from(rate).round() as u32).max(1)
+        } else {
+            25
+        };
+        (decoder.width() & !1, decoder.height() & !1, frame_rate)
+    };
+
+    let mut output = format::output_as(&destination, "mp4")?;
+    let codec = encoder::find_by_name(&options.encoder)
+        .ok_or_else(|| anyhow!("Encoder {} is not available", options.encoder))?;
+    let mut video_encoder = codec::context::Context::new_with_codec(codec)
+        .encoder()
+        .video()?;
+    video_encoder.set_width(width);
+    video_encoder.set_height(height);
+    video_encoder.set_format(Pixel::YUV420P);
+    let time_base = Rational::new(1, 1000);
+    video_encoder.set_frame_rate(Some(Rational::new(frame_rate as i32, 1)));
+    video_encoder.set_time_base(time_base);
+    if output
+        .format()
+        .flags()
+        .contains(format::Flags::GLOBAL_HEADER)
+    {
+        video_encoder.set_flags(codec::Flags::GLOBAL_HEADER);
+    }
+    let mut encoder_options = Dictionary::new();
+    if options.encoder == "libx264" {
+        encoder_options.set("preset", "veryfast");
+    }
+    let video_encoder = video_encoder.open_with(encoder_options)?;
+    let output_index = {
+        let mut stream = output.add_stream(codec)?;
+        stream.set_parameters(&video_encoder);
+        stream.index()
+    };
+    let mut header_options = Dictionary::new();
+    header_options.set("movflags", "+faststart");
+    output.write_header_with(header_options)?;
+
+    let mut writer = SummaryWriter {
+        encoder: video_encoder,
+        output_index,
+        time_base,
+        output_time_base: output.stream(output_index).unwrap().time_base(),
+        width,
+        height,
+        frame_rate,
+        position_ms: 0.0,
+    };
+    for (done, clip) in clips.iter().enumerate() {
+        if options.captions {
+            writer.write_caption(&clip.caption, options.font_file.as_deref(), &mut output)?;
+        }
+        // one broken recording shouldn't stop the rest of the day being summarised
+        if let Err(err) = writer.write_clip(&clip.path, options.speed, &mut output, &cancelled) {
+            if cancelled() {
+                return Err(err);
+            }
+            error!("Skipping {} in summary, error: {err}", clip.path.display());
+        }
+        progress((done + 1) as f32 / clips.len() as f32);
+    }
+    writer.encoder.send_eof()?;
+    writer.receive_packets(&mut output)?;
+    output.write_trailer()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono_tz::{Europe::London, UTC};
+    use std::{cell::RefCell, process::Command};
+    use tempfile::TempDir;
+
+    fn catalog_entry(recorded: i64, trigger: Option<&str>) -> CatalogEntry {
+        CatalogEntry {
+            file_name: "motion.mp4".to_string(),
+            recorded,
+            duration: 4.0,
+            width: 320,
+            height: 240,
+            size_bytes: 1000,
+            codec: "h264".to_string(),
+            trigger: trigger.map(str::to_string),
+            metadata: None,
+            tags: Vec::new(),
+            starred: false,
+            notes: None,
+            protected: false,
+            trashed_at: None,
+            offloaded_to: None,
+            offloaded_at: None,
+        }
+    }
+
+    #[test]
+    fn summary_name_has_local_date() {
+        let date = NaiveDate::from_ymd_opt(2025, 6, 1).unwrap();
+        assert_eq!(summary_name(date), "summary_2025-06-01.mp4");
+        assert!(summary_name(date).starts_with(SUMMARY_PREFIX));
+    }
+
+    #[test]
+    fn caption_has_local_time_and_trigger() {
+        // 2025-06-01 13:03:09 UTC, 14:03:09 in British summer time
+        let recorded = 1_748_782_989;
+        assert_eq!(
+            caption_in(London, &catalog_entry(recorded, Some("gpio"))),
+            "14:03:09  gpio"
+        );
+        assert_eq!(caption_in(UTC, &catalog_entry(recorded, None)), "13:03:09");
+    }
+
+    /// Writes `secs` of the ffmpeg test pattern at 10fps to `path`.
+    fn test_clip(path: &Path, secs: u32) -> SummaryClip {
+        let status = Command::new("ffmpeg")
+            .args([
+                "-y",
+                "-f",
+                "lavfi",
+                "-i",
+                &format!("testsrc=duration={secs}:rate=10:size=320x240"),
+            ])
+            .args(["-c:v", "libx264", "-g", "10", "-pix_fmt", "yuv420p"])
+            .arg(path)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        SummaryClip {
+            path: path.to_path_buf(),
+            caption: path.display().to_string(),
+        }
+    }
+
+    fn duration(path: &Path) -> f64 {
+        format::input(&path).unwrap().duration() as f64 / ffmpeg_next::ffi::AV_TIME_BASE as f64
+    }
+
+    fn options(speed: f64, captions: bool) -> SummaryOptions {
+        SummaryOptions {
+            speed,
+            captions,
+            encoder: "libx264".to_string(),
+            font_file: None,
+        }
+    }
+
+    #[test]
+    #[ignore = "needs ffmpeg"]
+    fn clips_play_one_after_another_at_speed() {
+        ffmpeg_next::init().unwrap();
+        let dir = TempDir::new().unwrap();
+        let clips = [
+            test_clip(&dir.path().join("first.mp4"), 4),
+            test_clip(&dir.path().join("second.mp4"), 6),
+            // a broken recording is skipped rather than failing the summary
+            SummaryClip {
+                path: dir.path().join("missing.mp4"),
+                caption: "missing".to_string(),
+            },
+        ];
+        let destination = dir.path().join("summary.mp4");
+        let progress = RefCell::new(Vec::new());
+        render_summary(
+            &clips,
+            &destination,
+            &options(2.0, false),
+            |done| progress.borrow_mut().push(done),
+            || false,
+        )
+        .unwrap();
+        let duration = duration(&destination);
+        assert!((duration - 5.0).abs() < 0.2, "summary lasts {duration}s");
+        assert_eq!(progress.into_inner(), [1.0 / 3.0, 2.0 / 3.0, 1.0]);
+
+        assert!(
+            render_summary(&clips, &destination, &options(2.0, false), |_| {}, || true).is_err()
+        );
+    }
+
+    #[test]
+    #[ignore = "needs ffmpeg built with drawtext"]
+    fn caption_card_comes_before_each_clip() {
+        ffmpeg_next::init().unwrap();
+        let dir = TempDir::new().unwrap();
+        let clips = [
+            test_clip(&dir.path().join("first.mp4"), 2),
+            test_clip(&dir.path().join("second.mp4"), 2),
+        ];
+        let destination = dir.path().join("summary.mp4");
+        render_summary(&clips, &destination, &options(1.0, true), |_| {}, || false).unwrap();
+        let duration = duration(&destination);
+        assert!(
+            (duration - 2.0 * (2.0 + CAPTION_SECS)).abs() < 0.2,
+            "summary lasts {duration}s"
+        );
+    }
+}
@@ -0,0 +1,540 @@
+use super::cache::DiskCache;
+use crate::storage::video_save_path;
+use anyhow::{anyhow, bail, Result};
+use ffmpeg_next::{
+    codec, encoder,
+    ffi::AV_TIME_BASE,
+    filter,
+    format::{self, Pixel},
+    frame,
+    media::Type,
+    software::scaling,
+    Dictionary, Packet, Rational,
+};
+use std::{
+    env::var,
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+use tokio::{sync::Mutex, task::spawn_blocking};
+use tracing::{error, info};
+
+const DEFAULT_THUMBNAIL_CACHE_MAX_BYTES: u64 = 256 * 1024 * 1024;
+const POSTER_WIDTH: u32 = 640;
+const PREVIEW_WIDTH: u32 = 320;
+const PREVIEW_FRAMES: usize = 24;
+const PREVIEW_FPS: i32 = 4;
+const SHEET_COLUMNS: usize = 4;
+const SHEET_ROWS: usize = 4;
+const SHEET_TILE_WIDTH: u32 = 320;
+
+/// Still and animated images made from a recording to show before it is opened.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ThumbnailKind {
+    /// A single frame from early in the recording.
+    Poster,
+    /// A short looping flipbook of frames spread over the recording.
+    Preview,
+    /// A grid of frames spread over the recording.
+    ContactSheet,
+}
+
+impl FromStr for ThumbnailKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "poster" => Ok(ThumbnailKind::Poster),
+            "preview" => Ok(ThumbnailKind::Preview),
+            "contact_sheet" => Ok(ThumbnailKind::ContactSheet),
+            other => Err(format!("Unknown thumbnail kind: {other}")),
+        }
+    }
+}
+
+impl ThumbnailKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ThumbnailKind::Poster => "poster",
+            ThumbnailKind::Preview => "preview",
+            ThumbnailKind::ContactSheet => "contact_sheet",
+        }
+    }
+}
+
+/// Container animated previews are written in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PreviewFormat {
+    WebP,
+    Gif,
+}
+
+impl PreviewFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            PreviewFormat::WebP => "webp",
+            PreviewFormat::Gif => "gif",
+        }
+    }
+
+    fn encoder(&self) -> &'static str {
+        match self {
+            PreviewFormat::WebP => "libwebp_anim",
+            PreviewFormat::Gif => "gif",
+        }
+    }
+
+    fn content_type(&self) -> &'static str {
+        match self {
+            PreviewFormat::WebP => "image/webp",
+            PreviewFormat::Gif => "image/gif",
+        }
+    }
+}
+
+/// Posters, animated previews and contact sheets of recordings, made on first request and cached.
+///
+/// Read from the environment:
+/// - `THUMBNAIL_CACHE_PATH`: where images are kept, defaults to `.thumbnails` in the recordings directory.
+/// - `THUMBNAIL_CACHE_MAX_BYTES`: size cap of the cache, defaults to 256MiB.
+/// - `PREVIEW_FORMAT`: `webp` or `gif`, defaults to `webp` when ffmpeg has libwebp and `gif` otherwise.
+pub struct ThumbnailCache {
+    cache: DiskCache,
+    preview_format: PreviewFormat,
+    /// Images are made one at a time so a page of new recordings doesn't start dozens of decoders.
+    generating: Mutex<()>,
+}
+
+impl ThumbnailCache {
+    pub fn from_env() -> Self {
+        let dir = match var("THUMBNAIL_CACHE_PATH") {
+            Ok(path) => PathBuf::from(path),
+            Err(_) => video_save_path().join(".thumbnails"),
+        };
+        let max_bytes = var("THUMBNAIL_CACHE_MAX_BYTES")
+            .ok()
+            .and_then(|bytes| bytes.parse().ok())
+            .unwrap_or(DEFAULT_THUMBNAIL_CACHE_MAX_BYTES);
+        let webp_available = encoder::find_by_name(PreviewFormat::WebP.encoder()).is_some();
+        let preview_format = match var("PREVIEW_FORMAT").as_deref() {
+            Ok("gif") => PreviewFormat::Gif,
+            _ if webp_available => PreviewFormat::WebP,
+            _ => PreviewFormat::Gif,
+        };
+        ThumbnailCache {
+            cache: DiskCache::new(dir, max_bytes),
+            preview_format,
+            generating: Mutex::new(()),
+        }
+    }
+
+    pub fn content_type(&self, kind: ThumbnailKind) -> &'static str {
+        match kind {
+            ThumbnailKind::Preview => self.preview_format.content_type(),
+            ThumbnailKind::Poster | ThumbnailKind::ContactSheet => "image/jpeg",
+        }
+    }
+
+    fn image_name(&self, source: &Path, kind: ThumbnailKind) -> String {
+        let stem = source
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let extension = match kind {
+            ThumbnailKind::Preview => self.preview_format.extension(),
+            ThumbnailKind::Poster | ThumbnailKind::ContactSheet => "jpg",
+        };
+        format!("{stem}_{}.{extension}", kind.as_str())
+    }
+
+    /// Returns the image of the recording at `source`, making it first if needed.
+    pub async fn image(&self, source: &Path, kind: ThumbnailKind) -> Result<PathBuf> {
+        let name = self.image_name(source, kind);
+        if let Some(path) = self.cache.get(&name) {
+            return Ok(path);
+        }
+
+        let _generating = self.generating.lock().await;
+        // another request may have made it while this one waited
+        if let Some(path) = self.cache.get(&name) {
+            return Ok(path);
+        }
+        let path = self.cache.path(&name)?;
+        let partial = self.cache.path(&format!("{name}.part"))?;
+        let source = source.to_path_buf();
+        let preview_format = self.preview_format;
+        let image = spawn_blocking(move || {
+            let made = match kind {
+                ThumbnailKind::Poster => make_poster(&source, &partial),
+                ThumbnailKind::Preview => make_preview(&source, &partial, preview_format),
+                ThumbnailKind::ContactSheet => make_contact_sheet(&source, &partial),
+            };
+            match made.and_then(|_| Ok(fs::rename(&partial, &path)?)) {
+                Ok(_) => Ok(path),
+                Err(err) => {
+                    let _ = fs::remove_file(&partial);
+                    Err(err)
+                }
+            }
+        })
+        .await??;
+        if let Err(err) = self.cache.evict() {
+            error!("Error evicting thumbnails from cache, error: {err}");
+        }
+        info!("Made {}", image.display());
+        Ok(image)
+    }
+}
+
+/// Length of a recording in seconds.
+pub fn duration_secs(source: &Path) -> Result<f64> {
+    Ok(format::input(&source)?.duration() as f64 / AV_TIME_BASE as f64)
+}
+
+/// `count` times spread evenly over `duration`, each in the middle of its share.
+pub fn spread_times(duration: f64, count: usize) -> Vec<f64> {
+    (0..count)
+        .map(|index| duration * (index as f64 + 0.5) / count as f64)
+        .collect()
+}
+
+/// Decodes the first frame at or after each of `times`, in seconds, scaled to `width` wide, paired
+/// with the time it was found for.
+///
+/// Every frame has the same even height, worked out from the recording's aspect ratio. Times past
+/// the end of the recording are skipped, so there may be fewer frames than times.
+pub fn grab_frames(
+    source: &Path,
+    times: &[f64],
+    width: u32,
+    pixel_format: Pixel,
+) -> Result<Vec<(f64, frame::Video)>> {
+    let mut input = format::input(&source)?;
+    let (video_index, time_base, mut decoder) = {
+        let stream = input
+            .streams()
+            .best(Type::Video)
+            .ok_or_else(|| anyhow!("{} has no video stream", source.display()))?;
+        let decoder = codec::context::Context::from_parameters(stream.parameters())?
+            .decoder()
+            .video()?;
+        (stream.index(), stream.time_base(), decoder)
+    };
+    if decoder.width() == 0 || decoder.height() == 0 {
+        bail!("{} has no picture size", source.display());
+    }
+    let width = width.min(decoder.width()) & !1;
+    let height = ((decoder.height() as u64 * width as u64 / decoder.width() as u64) as u32) & !1;
+    let mut scaler = scaling::Context::get(
+        decoder.format(),
+        decoder.width(),
+        decoder.height(),
+        pixel_format,
+        width,
+        height,
+        scaling::Flags::BILINEAR,
+    )?;
+
+    let mut frames = Vec::with_capacity(times.len());
+    let mut decoded = frame::Video::empty();
+    for &time in times {
+        let seek_to = (time * AV_TIME_BASE as f64) as i64;
+        input.seek(seek_to, ..seek_to)?;
+        decoder.flush();
+        let target = (time / f64::from(time_base)) as i64;
+        'packets: for (stream, packet) in input.packets() {
+            if stream.index() != video_index {
+                continue;
+            }
+            decoder.send_packet(&packet)?;
+            while decoder.receive_frame(&mut decoded).is_ok() {
+                if decoded.timestamp().is_some_and(|pts| pts < target) {
+                    continue;
+                }
+                let mut scaled = frame::Video::empty();
+                scaler.run(&decoded, &mut scaled)?;
+                frames.push((time, scaled));
+                break 'packets;
+            }
+        }
+    }
+    Ok(frames)
+}
+
+/// Drops the times from frames returned by [`grab_frames`].
+pub fn frames_only(frames: Vec<(f64, frame::Video)>) -> Vec<frame::Video> {
+    frames.into_iter().map(|(_, frame)| frame).collect()
+}
+
+/// Encodes a single frame as a JPEG file, the frame must be `YUVJ420P`.
+pub fn write_jpeg(image: &frame::Video, destination: &Path) -> Result<()> {
+    let codec = encoder::find(codec::Id::MJPEG).ok_or_else(|| anyhow!("No JPEG encoder"))?;
+    let mut jpeg_encoder = codec::context::Context::new_with_codec(codec)
+        .encoder()
+        .video()?;
+    jpeg_encoder.set_width(image.width());
+    jpeg_encoder.set_height(image.height());
+    jpeg_encoder.set_format(Pixel::YUVJ420P);
+    // one frame a second, so the bit rate is the size budget of the image
+    jpeg_encoder.set_time_base(Rational::new(1, 1));
+    jpeg_encoder.set_bit_rate((image.width() * image.height() * 2) as usize);
+    let mut jpeg_encoder = jpeg_encoder.open_with(Dictionary::new())?;
+
+    let mut image = image.clone();
+    image.set_pts(Some(0));
+    jpeg_encoder.send_frame(&image)?;
+    jpeg_encoder.send_eof()?;
+    let mut encoded = Packet::empty();
+    jpeg_encoder.receive_packet(&mut encoded)?;
+    let data = encoded
+        .data()
+        .ok_or_else(|| anyhow!("JPEG encoder produced no data"))?;
+    fs::write(destination, data)?;
+    Ok(())
+}
+
+/// Lays equally sized `YUVJ420P` frames out in a grid, left to right then top to bottom.
+pub fn tile_frames(frames: &[frame::Video], columns: usize) -> Result<frame::Video> {
+    let first = frames.first().ok_or_else(|| anyhow!("No frames to tile"))?;
+    let (tile_width, tile_height) = (first.width() as usize, first.height() as usize);
+    let rows = frames.len().div_ceil(columns);
+    let mut sheet = frame::Video::new(
+        Pixel::YUVJ420P,
+        (tile_width * columns) as u32,
+        (tile_height * rows) as u32,
+    );
+    // black in full range YUV, for the gaps left in a last row that isn't full
+    sheet.data_mut(0).fill(0);
+    sheet.data_mut(1).fill(128);
+    sheet.data_mut(2).fill(128);
+
+    for (index, tile) in frames.iter().enumerate() {
+        let (column, row) = (index % columns, index / columns);
+        for plane in 0..3 {
+            // chroma planes are half the size of the luma plane in both directions
+            let subsampling = if plane == 0 { 1 } else { 2 };
+            let x = column * tile_width / subsampling;
+            let y = row * tile_height / subsampling;
+            let copy_width = (tile_width / subsampling).min(tile.plane_width(plane) as usize);
+            let copy_height = (tile_height / subsampling).min(tile.plane_height(plane) as usize);
+            let (source_stride, sheet_stride) = (tile.stride(plane), sheet.stride(plane));
+            for line in 0..copy_height {
+                let source_start = line * source_stride;
+                let sheet_start = (y + line) * sheet_stride + x;
+                sheet.data_mut(plane)[sheet_start..sheet_start + copy_width]
+                    .copy_from_slice(&tile.data(plane)[source_start..source_start + copy_width]);
+            }
+        }
+    }
+    Ok(sheet)
+}
+
+fn make_poster(source: &Path, destination: &Path) -> Result<()> {
+    // a little way in, past the dark frames some cameras start with
+    let time = (duration_secs(source)? * 0.1).min(2.0);
+    let frames = grab_frames(source, &[time], POSTER_WIDTH, Pixel::YUVJ420P)?;
+    let (_, poster) = frames
+        .first()
+        .ok_or_else(|| anyhow!("No frame found in {}", source.display()))?;
+    write_jpeg(poster, destination)
+}
+
+fn make_contact_sheet(source: &Path, destination: &Path) -> Result<()> {
+    let times = spread_times(duration_secs(source)?, SHEET_COLUMNS * SHEET_ROWS);
+    let frames = frames_only(grab_frames(
+        source,
+        &times,
+        SHEET_TILE_WIDTH,
+        Pixel::YUVJ420P,
+    )?);
+    write_jpeg(&tile_frames(&frames, SHEET_COLUMNS)?, destination)
+}
+
+fn make_preview(source: &Path, destination: &Path, preview_format: PreviewFormat) -> Result<()> {
+    let times = spread_times(duration_secs(source)?, PREVIEW_FRAMES);
+    let mut frames = frames_only(grab_frames(source, &times, PREVIEW_WIDTH, Pixel::YUV420P)?);
+    if frames.is_empty() {
+        bail!("No frames found in {}", source.display());
+    }
+    let pixel_format = match preview_format {
+        PreviewFormat::WebP => Pixel::YUV420P,
+        PreviewFormat::Gif => {
+            frames = palettize(frames)?;
+            Pixel::PAL8
+        }
+    };
+    let (width, height) = (frames[0].width(), frames[0].height());
+
+    let mut output = format::output_as(&destination, preview_format.extension())?;
+    let codec = encoder::find_by_name(preview_format.encoder())
+        .ok_or_else(|| anyhow!("Encoder {} is not available", preview_format.encoder()))?;
+    let mut preview_encoder = codec::context::Context::new_with_codec(codec)
+        .encoder()
+        .video()?;
+    preview_encoder.set_width(width);
+    preview_encoder.set_height(height);
+    preview_encoder.set_format(pixel_format);
+    preview_encoder.set_time_base(Rational::new(1, PREVIEW_FPS));
+    preview_encoder.set_frame_rate(Some(Rational::new(PREVIEW_FPS, 1)));
+    let mut preview_encoder = preview_encoder.open_with(Dictionary::new())?;
+    let output_index = {
+        let mut stream = output.add_stream(codec)?;
+        stream.set_parameters(&preview_encoder);
+        stream.index()
+    };
+    let mut header_options = Dictionary::new();
+    // loop forever
+    header_options.set("loop", "0");
+    output.write_header_with(header_options)?;
+    let output_time_base = output.stream(output_index).unwrap().time_base();
+
+    let mut encoded = Packet::empty();
+    let mut write_packets = |preview_encoder: &mut encoder::Video,
+                             output: &mut format::context::Output|
+     -> Result<()> {
+        while preview_encoder.receive_packet(&mut encoded).is_ok() {
+            encoded.set_stream(output_index);
+            encoded.rescale_ts(Rational::new(1, PREVIEW_FPS), output_time_base);
+            encoded.write_interleaved(output)?;
+        }
+        Ok(())
+    };
+    for (index, mut preview_frame) in frames.into_iter().enumerate() {
+        preview_frame.set_pts(Some(index as i64));
+        preview_encoder.send_frame(&preview_frame)?;
+        write_packets(&mut preview_encoder, &mut output)?;
+    }
+    preview_encoder.send_eof()?;
+    write_packets(&mut preview_encoder, &mut output)?;
+    output.write_trailer()?;
+    Ok(())
+}
+
+/// Converts frames to 8-bit paletted colour for GIF, using a palette made from the frames themselves.
+fn palettize(frames: Vec<frame::Video>) -> Result<Vec<frame::Video>> {
+    let (width, height) = (frames[0].width(), frames[0].height());
+    let mut graph = filter::Graph::new();
+    let buffer = filter::find("buffer").ok_or_else(|| anyhow!("buffer filter missing"))?;
+    let sink = filter::find("buffersink").ok_or_else(|| anyhow!("buffersink filter missing"))?;
+    graph.add(
+        &buffer,
+        "in",
+        &format!(
+            "video_size={width}x{height}:pix_fmt=yuv420p:time_base=1/{PREVIEW_FPS}:pixel_aspect=1/1"
+        ),
+    )?;
+    graph.add(&sink, "out", "")?;
+    graph
+        .output("in", 0)?
+        .input("out", 0)?
+        .parse("split[a][b];[a]palettegen[p];[b][p]paletteuse")?;
+    graph.validate()?;
+
+    for (index, mut source_frame) in frames.into_iter().enumerate() {
+        source_frame.set_pts(Some(index as i64));
+        graph.get("in").unwrap().source().add(&source_frame)?;
+    }
+    graph.get("in").unwrap().source().flush()?;
+
+    let mut paletted = Vec::new();
+    let mut converted = frame::Video::empty();
+    while graph
+        .get("out")
+        .unwrap()
+        .sink()
+        .frame(&mut converted)
+        .is_ok()
+    {
+        paletted.push(converted.clone());
+    }
+    Ok(paletted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+    use tempfile::TempDir;
+
+    #[test]
+    fn kinds_parse_from_their_names() {
+        for kind in [
+            ThumbnailKind::Poster,
+            ThumbnailKind::Preview,
+            ThumbnailKind::ContactSheet,
+        ] {
+            assert_eq!(kind.as_str().parse::<ThumbnailKind>(), Ok(kind));
+        }
+        assert_eq!(
+            "Poster".parse::<ThumbnailKind>(),
+            Err("Unknown thumbnail kind: Poster".to_string())
+        );
+        assert!("contact-sheet".parse::<ThumbnailKind>().is_err());
+        assert!("".parse::<ThumbnailKind>().is_err());
+    }
+
+    #[test]
+    fn spread_times_are_in_the_middle_of_their_share() {
+        assert_eq!(spread_times(8.0, 4), [1.0, 3.0, 5.0, 7.0]);
+        assert!(spread_times(8.0, 0).is_empty());
+    }
+
+    #[test]
+    fn tiles_fill_rows_and_leave_gaps_black() {
+        let mut tile = frame::Video::new(Pixel::YUVJ420P, 4, 2);
+        for plane in 0..3 {
+            tile.data_mut(plane).fill(200);
+        }
+        let sheet = tile_frames(&[tile.clone(), tile.clone(), tile], 2).unwrap();
+        assert_eq!((sheet.width(), sheet.height()), (8, 4));
+        let luma = |x: usize, y: usize| sheet.data(0)[y * sheet.stride(0) + x];
+        assert_eq!(luma(0, 0), 200);
+        assert_eq!(luma(7, 1), 200);
+        assert_eq!(luma(3, 3), 200);
+        // the last row has one tile of two
+        assert_eq!(luma(4, 2), 0);
+        assert_eq!(sheet.data(1)[sheet.stride(1) + 2], 128);
+        assert!(tile_frames(&[], 2).is_err());
+    }
+
+    #[test]
+    #[ignore = "needs ffmpeg"]
+    fn frames_are_scaled_and_paired_with_their_times() {
+        ffmpeg_next::init().unwrap();
+        let dir = TempDir::new().unwrap();
+        let source = dir.path().join("motion.mp4");
+        let status = Command::new("ffmpeg")
+            .args([
+                "-y",
+                "-f",
+                "lavfi",
+                "-i",
+                "testsrc=duration=6:rate=10:size=320x240",
+            ])
+            .args(["-c:v", "libx264", "-g", "10", "-pix_fmt", "yuv420p"])
+            .arg(&source)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        assert!((duration_secs(&source).unwrap() - 6.0).abs() < 0.15);
+
+        // past the end of the recording, so skipped
+        let times = [0.0, 2.5, 30.0, 4.0];
+        let frames = grab_frames(&source, &times, 161, Pixel::YUVJ420P).unwrap();
+        let found: Vec<f64> = frames.iter().map(|(time, _)| *time).collect();
+        assert_eq!(found, [0.0, 2.5, 4.0]);
+        for (_, frame) in &frames {
+            assert_eq!((frame.width(), frame.height()), (160, 120));
+            assert_eq!(frame.format(), Pixel::YUVJ420P);
+        }
+
+        // never scaled up past the recording's own size
+        let frames = grab_frames(&source, &[1.0], 1000, Pixel::YUV420P).unwrap();
+        assert_eq!((frames[0].1.width(), frames[0].1.height()), (320, 240));
+
+        let poster = dir.path().join("poster.jpg");
+        make_poster(&source, &poster).unwrap();
+        assert!(fs::read(&poster).unwrap().starts_with(&[0xff, 0xd8]));
+    }
+}
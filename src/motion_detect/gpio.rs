@@ -1,15 +1,25 @@
-use crate::camera;
+use crate::{
+    camera::{self, camera::ActiveRecording, frames::FrameHub},
+    events::{EventBus, EventKind},
+    storage::{
+        catalog::Catalog,
+        disk::{free_bytes, DiskGuard, LowDiskAction},
+        metadata::{write_sidecar, RecordingMetadata},
+        retention::{free_space, run_retention, RetentionPolicy},
+        video_save_path,
+    },
+};
 use chrono::prelude::*;
 use rppal::gpio::Mode::Input;
 use rppal::gpio::{Gpio, IoPin};
 use serde::Deserialize;
 // use std::collections::HashMap;
 use std::{
-    sync::RwLock,
-    thread::{self},
+    sync::{Arc, Mutex, RwLock},
+    thread::{self, JoinHandle},
     time,
 };
-use tracing::info;
+use tracing::{error, info};
 
 pub struct SensorConfig {
     pub sensor_pin: IoPin,
@@ -46,15 +56,226 @@ pub struct MotionDetector {
     pub sensor_config: SensorConfig,
     pub cam_type: RwLock<Option<CameraType>>,
     pub is_shutdown: RwLock<bool>,
+    /// Why the recorder cannot currently work as expected, reported through `/cam_status`.
+    pub degraded: RwLock<Option<String>>,
+    /// Whether the video volume was last seen short of space, so disk events are raised once per change.
+    disk_low: RwLock<bool>,
+    /// Retention pass started when motion was last seen, see [`MotionDetector::start_retention`].
+    retention_pass: Mutex<Option<JoinHandle<()>>>,
+    pub events: EventBus,
+    pub catalog: Arc<Catalog>,
+    /// Latest camera frame for the MJPEG and snapshot endpoints.
+    pub frames: FrameHub,
 }
 
 impl MotionDetector {
-    pub fn new(pin_num: u8) -> Self {
+    pub fn new(pin_num: u8, catalog: Arc<Catalog>) -> Self {
         return MotionDetector {
             sensor_config: SensorConfig::new(pin_num),
             cam_type: RwLock::new(None),
             is_shutdown: RwLock::new(false),
+            degraded: RwLock::new(None),
+            disk_low: RwLock::new(false),
+            retention_pass: Mutex::new(None),
+            events: EventBus::new(),
+            catalog,
+            frames: FrameHub::new(),
+        };
+    }
+
+    /// Describes what triggers recordings, stored alongside each clip in the catalog.
+    pub fn trigger_source(&self) -> String {
+        format!("motion_sensor:gpio{}", self.sensor_config.sensor_pin.pin())
+    }
+
+    fn set_degraded(&self, reason: Option<String>) {
+        *self.degraded.write().unwrap() = reason;
+    }
+
+    /// Records whether the video volume is short of space, returning whether that changed.
+    fn set_disk_low(&self, low: bool) -> bool {
+        let mut disk_low = self.disk_low.write().unwrap();
+        let changed = *disk_low != low;
+        *disk_low = low;
+        changed
+    }
+
+    /// Applies the retention policy on another thread, so deleting old recordings doesn't hold up
+    /// the camera starting for new motion. A pass still running from earlier motion is left to finish.
+    fn start_retention(&self) {
+        let mut pass = self.retention_pass.lock().unwrap();
+        if pass.as_ref().is_some_and(|pass| !pass.is_finished()) {
+            return;
+        }
+        let catalog = self.catalog.clone();
+        *pass = Some(thread::spawn(move || {
+            run_retention(&catalog);
+        }));
+    }
+
+    /// Waits for the pass started by [`MotionDetector::start_retention`], returning whether there was one.
+    fn wait_for_retention(&self) -> bool {
+        let Some(pass) = self.retention_pass.lock().unwrap().take() else {
+            return false;
+        };
+        if pass.join().is_err() {
+            error!("Retention pass panicked");
+        }
+        true
+    }
+
+    /// Checks the video volume has enough free space to record, cleaning up first if configured to.
+    ///
+    /// This runs on every motion check, so `LowDiskSpace`, `RecordingRefused` and
+    /// `DiskSpaceRecovered` are only raised when the volume goes from having enough space to not,
+    /// or back. Only when space is short does it wait for a retention pass still running.
+    pub fn ensure_disk_space(&self) -> bool {
+        let guard = DiskGuard::from_env();
+        let dir = video_save_path();
+        let mut free = match free_bytes(&dir) {
+            Ok(free) => free,
+            Err(err) => {
+                error!(
+                    "Unable to read free space of {}, error: {err}",
+                    dir.display()
+                );
+                return true;
+            }
+        };
+        if free < guard.min_free_bytes && self.wait_for_retention() {
+            free = free_bytes(&dir).unwrap_or(free);
+        }
+        if free >= guard.min_free_bytes {
+            if self.set_disk_low(false) {
+                self.events
+                    .raise(EventKind::DiskSpaceRecovered { free_bytes: free });
+            }
+            return true;
+        }
+        let was_low = *self.disk_low.read().unwrap();
+        if !was_low {
+            self.events.raise(EventKind::LowDiskSpace {
+                free_bytes: free,
+                min_free_bytes: guard.min_free_bytes,
+            });
+        }
+
+        // recordings are deleted outright rather than moved to the trash, which lives on the same
+        // volume by default and so would free nothing
+        if guard.action == LowDiskAction::EmergencyRetention {
+            if let Err(err) = free_space(
+                &RetentionPolicy::from_env(),
+                &dir,
+                guard.min_free_bytes,
+                &self.catalog,
+            ) {
+                error!("Emergency retention failed, error: {err}");
+            }
+            if let Ok(free) = free_bytes(&dir) {
+                if free >= guard.min_free_bytes {
+                    self.set_disk_low(false);
+                    self.events
+                        .raise(EventKind::DiskSpaceRecovered { free_bytes: free });
+                    return true;
+                }
+            }
+        }
+
+        let reason = format!(
+            "Low disk space: {free} bytes free, {} bytes required",
+            guard.min_free_bytes
+        );
+        self.set_degraded(Some(reason.clone()));
+        if self.set_disk_low(true) {
+            self.events.raise(EventKind::RecordingRefused { reason });
+        }
+        false
+    }
+
+    /// Starts recording the motion first seen at `motion_seen`.
+    fn begin_recording(&self, motion_seen: time::SystemTime) -> Option<ActiveRecording> {
+        if !self.ensure_disk_space() {
+            return None;
+        }
+        match camera::camera::start_recording(&self.frames) {
+            Ok(mut recording) => {
+                recording.first_motion = motion_seen;
+                self.set_degraded(None);
+                self.events.raise(EventKind::RecordingStarted {
+                    file_name: recording.file_name(),
+                });
+                Some(recording)
+            }
+            Err(err) => {
+                let reason = format!("Failed to start recording: {err}");
+                self.set_degraded(Some(reason.clone()));
+                self.events.raise(EventKind::RecordingFailed { reason });
+                None
+            }
+        }
+    }
+
+    /// Metadata of a finished recording, see [`MotionDetector::end_recording`] for `motion_ended`.
+    fn recording_metadata(
+        &self,
+        recording: &ActiveRecording,
+        motion_ended: bool,
+    ) -> RecordingMetadata {
+        let started_at = recording
+            .started
+            .duration_since(time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as i64;
+        // the camera starts in response to motion, so it is usually seen a moment before the clip
+        // begins and the offset is negative
+        let motion_start_offset = match recording.first_motion.duration_since(recording.started) {
+            Ok(offset) => offset.as_secs_f64(),
+            Err(err) => -err.duration().as_secs_f64(),
         };
+        let motion_stop_offset = motion_ended.then(|| {
+            recording
+                .last_motion
+                .duration_since(recording.started)
+                .unwrap_or_default()
+                .as_secs_f64()
+        });
+        RecordingMetadata::new(
+            self.trigger_source(),
+            started_at,
+            motion_start_offset,
+            motion_stop_offset,
+            recording.settings.clone(),
+        )
+    }
+
+    /// Stops a recording, `motion_ended` is false when it is cut short while there may still be
+    /// motion, such as for low disk space, ffmpeg exiting on its own or shutdown.
+    fn end_recording(&self, recording: ActiveRecording, motion_ended: bool) {
+        let file_name = recording.file_name();
+        let output = recording.output.clone();
+        let metadata = self.recording_metadata(&recording, motion_ended);
+        match recording.stop() {
+            Ok(status) => {
+                // whatever ffmpeg managed to write is kept, so describe it either way
+                if let Err(err) = write_sidecar(&output, &metadata) {
+                    error!("Unable to write metadata for {file_name}, error: {err}");
+                }
+                if !status.success() {
+                    let reason = format!("ffmpeg exited with {status} while recording {file_name}");
+                    self.set_degraded(Some(reason.clone()));
+                    self.events.raise(EventKind::RecordingFailed { reason });
+                    return;
+                }
+                if let Err(err) = self
+                    .catalog
+                    .index_recording(&output, Some(self.trigger_source()))
+                {
+                    error!("Unable to add {file_name} to catalog, error: {err}");
+                }
+                self.events.raise(EventKind::RecordingStopped { file_name });
+            }
+            Err(err) => error!("Error waiting for recording {file_name} to finish, error: {err}"),
+        }
     }
 
     pub fn is_high(&self) -> bool {
@@ -80,35 +301,51 @@ impl MotionDetector {
 pub fn monitor_loop_record(motion_detector: &MotionDetector) {
     info!("Starting motion sensor camera in monitor mode.");
     let mut is_motion: bool;
-    let mut is_recording = false;
-    let mut camera_process_id: Option<u32> = None;
+    let mut was_motion = false;
+    let mut recording: Option<ActiveRecording> = None;
     loop {
         if *motion_detector.is_shutdown.read().unwrap() {
             info!("shutdown ordered");
-            if is_recording {
+            if let Some(active_recording) = recording.take() {
                 info!("ending current recording");
-                camera::camera::shutdown_cam_process(camera_process_id.unwrap());
+                motion_detector.end_recording(active_recording, false);
             }
             *motion_detector.cam_type.write().unwrap() = None;
             *motion_detector.is_shutdown.write().unwrap() = false;
             break;
         }
+        // ffmpeg can die on its own, for example when the disk fills, so don't assume it is still recording
+        if recording
+            .as_mut()
+            .is_some_and(|active_recording| active_recording.ffmpeg_exited().is_some())
+        {
+            motion_detector.end_recording(recording.take().unwrap(), false);
+        }
+        // the sensor going from low to high is new motion, even while a recording is running, and
+        // is raised whether or not a recording can be started for it
+        let was_high = motion_detector.is_high();
         is_motion = motion_detector.is_motion();
-        if is_motion && !is_recording {
+        if is_motion && (!was_motion || !was_high) {
+            motion_detector.events.raise(EventKind::MotionDetected);
+        }
+        was_motion = is_motion;
+        if is_motion && recording.is_none() {
             info!("Motion detected starting camera");
-            camera_process_id = Some(camera::camera::start_recording());
-            is_recording = true;
+            let motion_seen = time::SystemTime::now();
+            motion_detector.start_retention();
+            recording = motion_detector.begin_recording(motion_seen);
             thread::sleep(time::Duration::from_secs(5));
-        } else if is_motion && is_recording {
+        } else if is_motion && recording.is_some() {
             info!("Motion detected camera already recording");
+            recording.as_mut().unwrap().last_motion = time::SystemTime::now();
+            if !motion_detector.ensure_disk_space() {
+                info!("Stopping recording as disk space is low");
+                motion_detector.end_recording(recording.take().unwrap(), false);
+            }
             thread::sleep(time::Duration::from_secs(1));
-        } else if !is_motion && is_recording {
+        } else if !is_motion && recording.is_some() {
             info!("No motion detected stopping recording");
-            if camera_process_id.is_none() {
-                panic!("Error is_recording evaluates to true but camera process id is none");
-            }
-            camera::camera::shutdown_cam_process(camera_process_id.unwrap());
-            is_recording = false;
+            motion_detector.end_recording(recording.take().unwrap(), true);
             thread::sleep(time::Duration::from_secs_f32(0.5));
         }
     }
@@ -117,7 +354,8 @@ pub fn monitor_loop_record(motion_detector: &MotionDetector) {
 pub fn monitor_loop_stream(motion_detector: &MotionDetector) {
     info!("Starting camera in streaming mode.");
     let mut is_motion: bool;
-    let stream_process_id = camera::camera::start_stream_rtp();
+    let mut was_motion = false;
+    let stream_process_id = camera::camera::start_stream_rtp(&motion_detector.frames);
     loop {
         if *motion_detector.is_shutdown.read().unwrap() {
             camera::camera::shutdown_cam_process(stream_process_id);
@@ -129,7 +367,11 @@ pub fn monitor_loop_stream(motion_detector: &MotionDetector) {
         if is_motion {
             let current_time = Utc::now().to_string();
             info!("Motion detected at {current_time}");
+            if !was_motion {
+                motion_detector.events.raise(EventKind::MotionDetected);
+            }
         }
+        was_motion = is_motion;
     }
 }
 
@@ -0,0 +1,37 @@
+use super::video_save_path;
+use crate::timezone::local_now;
+use std::{
+    env::var,
+    fs::OpenOptions,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+use tracing::{error, info};
+
+/// File the audit trail is appended to, set with `AUDIT_LOG_PATH`.
+fn audit_log_path() -> PathBuf {
+    match var("AUDIT_LOG_PATH") {
+        Ok(path) => PathBuf::from(path),
+        Err(_) => video_save_path().join("audit.log"),
+    }
+}
+
+/// Appends a line describing an action taken on a recording to the audit trail.
+///
+/// `actor` names who or what took the action, for example `retention` for automatic cleanup.
+pub fn record(actor: &str, action: &str, target: &Path, detail: &str) {
+    let line = format!(
+        "{}\t{actor}\t{action}\t{}\t{detail}",
+        local_now().to_rfc3339(),
+        target.display()
+    );
+    info!("audit: {line}");
+    if let Err(err) = append_line(&audit_log_path(), &line) {
+        error!("Failed to write audit log entry, error: {err}");
+    }
+}
+
+fn append_line(path: &Path, line: &str) -> io::Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{line}")
+}
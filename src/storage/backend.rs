@@ -0,0 +1,225 @@
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures_util::{future::BoxFuture, stream::BoxStream, StreamExt, TryStreamExt};
+use object_store::{
+    aws::AmazonS3Builder, path::Path as ObjectPath, GetOptions, GetRange, ObjectStore,
+    WriteMultipart,
+};
+use std::{
+    env::var,
+    io::{self, SeekFrom},
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::{
+    fs::{self, File},
+    io::{AsyncReadExt, AsyncSeekExt},
+};
+use tokio_util::io::ReaderStream;
+
+/// Size of the parts recordings are uploaded in, S3 requires at least 5MiB for all but the last.
+const UPLOAD_PART_BYTES: usize = 8 * 1024 * 1024;
+
+pub type ByteStream = BoxStream<'static, io::Result<Bytes>>;
+
+/// What a backend knows about a stored recording.
+#[derive(Clone, Debug)]
+pub struct ObjectInfo {
+    pub size: u64,
+    pub last_modified: DateTime<Utc>,
+}
+
+/// Somewhere finished recordings can be copied to and read back from.
+///
+/// Recordings are addressed by their file name, which callers validate before using.
+pub trait StorageBackend: Send + Sync {
+    /// Short name recorded in the audit log and catalog, such as `local` or `s3`.
+    fn name(&self) -> &'static str;
+
+    fn upload<'a>(&'a self, file_name: &'a str, source: &'a Path) -> BoxFuture<'a, Result<()>>;
+
+    /// `None` if the backend has no recording called `file_name`.
+    fn info<'a>(&'a self, file_name: &'a str) -> BoxFuture<'a, Result<Option<ObjectInfo>>>;
+
+    fn read_range<'a>(
+        &'a self,
+        file_name: &'a str,
+        range: Range<u64>,
+    ) -> BoxFuture<'a, Result<ByteStream>>;
+}
+
+/// Builds the backend finished recordings are offloaded to, if one is configured.
+///
+/// `OFFLOAD_BACKEND` picks the backend:
+/// - `local`: a directory, such as a mounted USB drive, set with `OFFLOAD_LOCAL_PATH`.
+/// - `s3`: an S3-compatible bucket, configured with `S3_BUCKET`, `S3_REGION`, `S3_ENDPOINT`,
+///   `S3_ACCESS_KEY_ID`, `S3_SECRET_ACCESS_KEY`, `S3_PREFIX` and `S3_ALLOW_HTTP`. Setting the
+///   endpoint and allowing plain HTTP is enough to use a local MinIO server.
+pub fn backend_from_env() -> Result<Option<Arc<dyn StorageBackend>>> {
+    let Ok(kind) = var("OFFLOAD_BACKEND") else {
+        return Ok(None);
+    };
+    let backend: Arc<dyn StorageBackend> = match kind.to_lowercase().as_str() {
+        "local" => {
+            let root = var("OFFLOAD_LOCAL_PATH")
+                .map_err(|_| anyhow!("OFFLOAD_LOCAL_PATH must be set for the local backend"))?;
+            Arc::new(LocalBackend::new(PathBuf::from(root)))
+        }
+        "s3" => Arc::new(S3Backend::from_env()?),
+        other => return Err(anyhow!("Unknown OFFLOAD_BACKEND {other}")),
+    };
+    Ok(Some(backend))
+}
+
+/// Recordings kept in a directory on a locally mounted filesystem.
+pub struct LocalBackend {
+    root: PathBuf,
+}
+
+impl LocalBackend {
+    pub fn new(root: PathBuf) -> Self {
+        LocalBackend { root }
+    }
+}
+
+impl StorageBackend for LocalBackend {
+    fn name(&self) -> &'static str {
+        "local"
+    }
+
+    fn upload<'a>(&'a self, file_name: &'a str, source: &'a Path) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            fs::create_dir_all(&self.root).await?;
+            // copy under a temporary name so a half written copy is never mistaken for the recording
+            let partial = self.root.join(format!("{file_name}.part"));
+            fs::copy(source, &partial).await?;
+            fs::rename(&partial, self.root.join(file_name)).await?;
+            Ok(())
+        })
+    }
+
+    fn info<'a>(&'a self, file_name: &'a str) -> BoxFuture<'a, Result<Option<ObjectInfo>>> {
+        Box::pin(async move {
+            match fs::metadata(self.root.join(file_name)).await {
+                Ok(metadata) => Ok(Some(ObjectInfo {
+                    size: metadata.len(),
+                    last_modified: metadata.modified()?.into(),
+                })),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+                Err(err) => Err(err.into()),
+            }
+        })
+    }
+
+    fn read_range<'a>(
+        &'a self,
+        file_name: &'a str,
+        range: Range<u64>,
+    ) -> BoxFuture<'a, Result<ByteStream>> {
+        Box::pin(async move {
+            let mut file = File::open(self.root.join(file_name)).await?;
+            file.seek(SeekFrom::Start(range.start)).await?;
+            let section = file.take(range.end - range.start);
+            Ok(ReaderStream::new(section).boxed())
+        })
+    }
+}
+
+/// Recordings kept in an S3-compatible bucket, uploaded in parts so they are never held in memory.
+pub struct S3Backend {
+    store: Box<dyn ObjectStore>,
+    prefix: String,
+}
+
+impl S3Backend {
+    pub fn from_env() -> Result<Self> {
+        let bucket =
+            var("S3_BUCKET").map_err(|_| anyhow!("S3_BUCKET must be set for the s3 backend"))?;
+        let mut builder = AmazonS3Builder::new()
+            .with_bucket_name(bucket)
+            .with_region(var("S3_REGION").unwrap_or("us-east-1".to_string()))
+            .with_allow_http(var("S3_ALLOW_HTTP").is_ok_and(|allow| allow == "true"));
+        if let Ok(endpoint) = var("S3_ENDPOINT") {
+            builder = builder.with_endpoint(endpoint);
+        }
+        if let Ok(access_key_id) = var("S3_ACCESS_KEY_ID") {
+            builder = builder.with_access_key_id(access_key_id);
+        }
+        if let Ok(secret_access_key) = var("S3_SECRET_ACCESS_KEY") {
+            builder = builder.with_secret_access_key(secret_access_key);
+        }
+        Ok(S3Backend {
+            store: Box::new(builder.build()?),
+            prefix: var("S3_PREFIX").unwrap_or_default(),
+        })
+    }
+
+    fn object_path(&self, file_name: &str) -> ObjectPath {
+        if self.prefix.is_empty() {
+            ObjectPath::from(file_name)
+        } else {
+            ObjectPath::from(format!("{}/{file_name}", self.prefix.trim_matches('/')))
+        }
+    }
+}
+
+impl StorageBackend for S3Backend {
+    fn name(&self) -> &'static str {
+        "s3"
+    }
+
+    fn upload<'a>(&'a self, file_name: &'a str, source: &'a Path) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let upload = self
+                .store
+                .put_multipart(&self.object_path(file_name))
+                .await?;
+            let mut writer = WriteMultipart::new_with_chunk_size(upload, UPLOAD_PART_BYTES);
+            let mut file = File::open(source).await?;
+            let mut buffer = vec![0u8; UPLOAD_PART_BYTES];
+            loop {
+                let read = file.read(&mut buffer).await?;
+                if read == 0 {
+                    break;
+                }
+                writer.wait_for_capacity(2).await?;
+                writer.write(&buffer[..read]);
+            }
+            writer.finish().await?;
+            Ok(())
+        })
+    }
+
+    fn info<'a>(&'a self, file_name: &'a str) -> BoxFuture<'a, Result<Option<ObjectInfo>>> {
+        Box::pin(async move {
+            match self.store.head(&self.object_path(file_name)).await {
+                Ok(meta) => Ok(Some(ObjectInfo {
+                    size: meta.size as u64,
+                    last_modified: meta.last_modified,
+                })),
+                Err(object_store::Error::NotFound { .. }) => Ok(None),
+                Err(err) => Err(err.into()),
+            }
+        })
+    }
+
+    fn read_range<'a>(
+        &'a self,
+        file_name: &'a str,
+        range: Range<u64>,
+    ) -> BoxFuture<'a, Result<ByteStream>> {
+        Box::pin(async move {
+            let options = GetOptions {
+                range: Some(GetRange::Bounded(range.start as _..range.end as _)),
+                ..Default::default()
+            };
+            let result = self
+                .store
+                .get_opts(&self.object_path(file_name), options)
+                .await?;
+            Ok(result.into_stream().map_err(io::Error::other).boxed())
+        })
+    }
+}
@@ -0,0 +1,672 @@
+use super::{
+    audit,
+    catalog::{BackupItem, Catalog},
+    metadata::sidecar_path,
+    store::{RecordingStore, StoreError},
+};
+use crate::events::{Event, EventKind};
+use anyhow::{anyhow, bail, Result};
+use chrono::Utc;
+use reqwest::{
+    blocking::{Body, Client},
+    header, Method, StatusCode,
+};
+use serde::Serialize;
+use ssh2::{CheckResult, KnownHostFileKind, OpenFlags, OpenType, Session};
+use std::{
+    env::var,
+    fs::{self, File},
+    io::{self, Read, Seek, SeekFrom},
+    net::TcpStream,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    sync::broadcast::{self, error::RecvError},
+    task::spawn_blocking,
+    time::{sleep_until, Instant},
+};
+use tracing::{error, info, warn};
+
+/// Delay before the first retry of a failed upload, doubled on each further failure.
+const BASE_RETRY_SECS: i64 = 30;
+const MAX_RETRY_SECS: i64 = 6 * 60 * 60;
+/// Longest the worker sleeps without checking the queue.
+const IDLE_CHECK_SECS: i64 = 60;
+/// How far back recordings are queued after missing events, in seconds.
+const LAGGED_LOOKBACK_SECS: i64 = 60 * 60;
+const RECENT_COMPLETED_LIMIT: usize = 20;
+
+/// Somewhere off the device recordings are copied to, so they survive the device being lost.
+///
+/// Uploads block, the worker runs them on the blocking thread pool.
+pub trait BackupDestination: Send + Sync {
+    /// Short name shown in the status and audit log, such as `webdav` or `sftp`.
+    fn name(&self) -> &'static str;
+
+    /// Copies `source` to the destination as `file_name`.
+    ///
+    /// The copy is written under a `.part` name and renamed once complete. An upload interrupted
+    /// by an error or reboot is continued from what already reached the destination.
+    fn upload(&self, file_name: &str, source: &Path) -> Result<()>;
+}
+
+/// Builds the destination set with `BACKUP_DESTINATION`, either `webdav` or `sftp`, if any.
+pub fn destination_from_env() -> Result<Option<Arc<dyn BackupDestination>>> {
+    let Ok(kind) = var("BACKUP_DESTINATION") else {
+        return Ok(None);
+    };
+    let destination: Arc<dyn BackupDestination> = match kind.to_lowercase().as_str() {
+        "webdav" => Arc::new(WebDavDestination::from_env()?),
+        "sftp" => Arc::new(SftpDestination::from_env()?),
+        other => bail!("Unknown BACKUP_DESTINATION {other}"),
+    };
+    Ok(Some(destination))
+}
+
+fn required_var(name: &str) -> Result<String> {
+    var(name).map_err(|_| anyhow!("{name} must be set for the backup destination"))
+}
+
+/// A WebDAV collection, read from `WEBDAV_URL`, `WEBDAV_USERNAME` and `WEBDAV_PASSWORD`.
+///
+/// Interrupted uploads are continued with a partial `PUT` carrying `Content-Range`, which
+/// Apache's mod_dav and most WebDAV servers accept. If the server refuses it the upload starts over.
+pub struct WebDavDestination {
+    /// Collection URL, always ending in `/`.
+    url: String,
+    username: Option<String>,
+    password: Option<String>,
+}
+
+impl WebDavDestination {
+    pub fn from_env() -> Result<Self> {
+        let mut url = required_var("WEBDAV_URL")?;
+        if !url.ends_with('/') {
+            url.push('/');
+        }
+        Ok(WebDavDestination {
+            url,
+            username: var("WEBDAV_USERNAME").ok(),
+            password: var("WEBDAV_PASSWORD").ok(),
+        })
+    }
+
+    fn request(
+        &self,
+        client: &Client,
+        method: Method,
+        name: &str,
+    ) -> reqwest::blocking::RequestBuilder {
+        let request = client.request(method, format!("{}{name}", self.url));
+        match &self.username {
+            Some(username) => request.basic_auth(username, self.password.as_ref()),
+            None => request,
+        }
+    }
+
+    /// Size of `name` on the server, `None` if it isn't there.
+    fn remote_size(&self, client: &Client, name: &str) -> Result<Option<u64>> {
+        let response = self.request(client, Method::HEAD, name).send()?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        if !response.status().is_success() {
+            bail!("HEAD {name} failed with {}", response.status());
+        }
+        Ok(response
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|length| length.to_str().ok())
+            .and_then(|length| length.parse().ok()))
+    }
+
+    /// Sends `source` from `offset` onwards to `name`.
+    fn put(
+        &self,
+        client: &Client,
+        name: &str,
+        source: &Path,
+        offset: u64,
+        size: u64,
+    ) -> Result<StatusCode> {
+        let mut file = File::open(source)?;
+        file.seek(SeekFrom::Start(offset))?;
+        let length = size - offset;
+        let mut request = self
+            .request(client, Method::PUT, name)
+            .body(Body::sized(file.take(length), length));
+        if offset > 0 {
+            request = request.header(
+                header::CONTENT_RANGE,
+                format!("bytes {offset}-{}/{size}", size - 1),
+            );
+        }
+        Ok(request.send()?.status())
+    }
+}
+
+impl BackupDestination for WebDavDestination {
+    fn name(&self) -> &'static str {
+        "webdav"
+    }
+
+    fn upload(&self, file_name: &str, source: &Path) -> Result<()> {
+        // the blocking client can't be created or dropped on the async runtime, so it is made per upload
+        let client = Client::builder()
+            .connect_timeout(Duration::from_secs(10))
+            .timeout(None)
+            .build()?;
+        let size = fs::metadata(source)?.len();
+        // an earlier attempt may have finished without its completion being recorded
+        if self.remote_size(&client, file_name)? == Some(size) {
+            return Ok(());
+        }
+
+        let part = format!("{file_name}.part");
+        let offset = match self.remote_size(&client, &part)? {
+            Some(uploaded) if uploaded <= size => uploaded,
+            _ => 0,
+        };
+        if offset < size || size == 0 {
+            let mut status = self.put(&client, &part, source, offset, size)?;
+            if !status.is_success() && offset > 0 {
+                warn!("Server refused to resume {file_name} with {status}, uploading it again");
+                status = self.put(&client, &part, source, 0, size)?;
+            }
+            if !status.is_success() {
+                bail!("PUT {part} failed with {status}");
+            }
+        }
+
+        let response = self
+            .request(&client, Method::from_bytes(b"MOVE")?, &part)
+            .header("Destination", format!("{}{file_name}", self.url))
+            .header("Overwrite", "T")
+            .send()?;
+        if !response.status().is_success() {
+            bail!("MOVE {part} failed with {}", response.status());
+        }
+        Ok(())
+    }
+}
+
+/// A directory on an SFTP server.
+///
+/// Read from the environment:
+/// - `SFTP_HOST`, `SFTP_PORT` (defaults to 22) and `SFTP_USERNAME`.
+/// - `SFTP_PRIVATE_KEY` and `SFTP_KEY_PASSPHRASE`, or `SFTP_PASSWORD`. The SSH agent is used if neither is set.
+/// - `SFTP_PATH`: directory uploads are written to, defaults to the login directory.
+/// - `SFTP_KNOWN_HOSTS`: OpenSSH known hosts file the server's key is checked against.
+pub struct SftpDestination {
+    host: String,
+    port: u16,
+    username: String,
+    password: Option<String>,
+    private_key: Option<PathBuf>,
+    key_passphrase: Option<String>,
+    known_hosts: Option<PathBuf>,
+    dir: PathBuf,
+}
+
+impl SftpDestination {
+    pub fn from_env() -> Result<Self> {
+        let known_hosts = var("SFTP_KNOWN_HOSTS").ok().map(PathBuf::from);
+        if known_hosts.is_none() {
+            warn!("SFTP_KNOWN_HOSTS is not set, the backup server's host key will not be checked");
+        }
+        Ok(SftpDestination {
+            host: required_var("SFTP_HOST")?,
+            port: var("SFTP_PORT")
+                .ok()
+                .and_then(|port| port.parse().ok())
+                .unwrap_or(22),
+            username: required_var("SFTP_USERNAME")?,
+            password: var("SFTP_PASSWORD").ok(),
+            private_key: var("SFTP_PRIVATE_KEY").ok().map(PathBuf::from),
+            key_passphrase: var("SFTP_KEY_PASSPHRASE").ok(),
+            known_hosts,
+            dir: PathBuf::from(var("SFTP_PATH").unwrap_or(".".to_string())),
+        })
+    }
+
+    fn connect(&self) -> Result<Session> {
+        let tcp = TcpStream::connect((self.host.as_str(), self.port))?;
+        let mut session = Session::new()?;
+        session.set_tcp_stream(tcp);
+        session.handshake()?;
+        self.verify_host_key(&session)?;
+
+        if let Some(private_key) = &self.private_key {
+            session.userauth_pubkey_file(
+                &self.username,
+                None,
+                private_key,
+                self.key_passphrase.as_deref(),
+            )?;
+        } else if let Some(password) = &self.password {
+            session.userauth_password(&self.username, password)?;
+        } else {
+            session.userauth_agent(&self.username)?;
+        }
+        if !session.authenticated() {
+            bail!("Authentication with {} failed", self.host);
+        }
+        Ok(session)
+    }
+
+    fn verify_host_key(&self, session: &Session) -> Result<()> {
+        let Some(known_hosts_path) = &self.known_hosts else {
+            return Ok(());
+        };
+        let mut known_hosts = session.known_hosts()?;
+        known_hosts.read_file(known_hosts_path, KnownHostFileKind::OpenSSH)?;
+        let (key, _) = session
+            .host_key()
+            .ok_or_else(|| anyhow!("{} sent no host key", self.host))?;
+        match known_hosts.check_port(&self.host, self.port, key) {
+            CheckResult::Match => Ok(()),
+            CheckResult::Mismatch => bail!("Host key of {} has changed", self.host),
+            _ => bail!("Host key of {} is not in the known hosts file", self.host),
+        }
+    }
+}
+
+impl BackupDestination for SftpDestination {
+    fn name(&self) -> &'static str {
+        "sftp"
+    }
+
+    fn upload(&self, file_name: &str, source: &Path) -> Result<()> {
+        let session = self.connect()?;
+        let sftp = session.sftp()?;
+        let destination = self.dir.join(file_name);
+        let part = self.dir.join(format!("{file_name}.part"));
+        let size = fs::metadata(source)?.len();
+        // an earlier attempt may have finished without its completion being recorded
+        if sftp.stat(&destination).ok().and_then(|stat| stat.size) == Some(size) {
+            return Ok(());
+        }
+
+        let offset = match sftp.stat(&part).ok().and_then(|stat| stat.size) {
+            Some(uploaded) if uploaded <= size => uploaded,
+            _ => 0,
+        };
+        let mut flags = OpenFlags::WRITE | OpenFlags::CREATE;
+        if offset == 0 {
+            flags |= OpenFlags::TRUNCATE;
+        }
+        let mut remote = sftp.open_mode(&part, flags, 0o644, OpenType::File)?;
+        remote.seek(SeekFrom::Start(offset))?;
+        let mut local = File::open(source)?;
+        local.seek(SeekFrom::Start(offset))?;
+        io::copy(&mut local, &mut remote)?;
+        drop(remote);
+
+        sftp.rename(&part, &destination, None)?;
+        Ok(())
+    }
+}
+
+/// What the backup worker is doing, as shown by the status endpoint.
+#[derive(Serialize)]
+pub struct BackupStatus {
+    pub destination: Option<&'static str>,
+    pub uploading: Option<String>,
+    pub pending: Vec<BackupItem>,
+    pub recently_completed: Vec<BackupItem>,
+}
+
+/// Copies each finished recording and its metadata sidecar to the backup destination.
+///
+/// The queue lives in the catalog database, so uploads waiting or half done when the device
+/// restarts are picked up again. Failed uploads are retried with exponential backoff, starting at
+/// 30 seconds and capped at six hours.
+pub struct Backup {
+    destination: Option<Arc<dyn BackupDestination>>,
+    catalog: Arc<Catalog>,
+    store: Arc<RecordingStore>,
+    /// Recording being uploaded right now.
+    uploading: Mutex<Option<String>>,
+}
+
+impl Backup {
+    pub fn from_env(catalog: Arc<Catalog>, store: Arc<RecordingStore>) -> Result<Self> {
+        Ok(Backup {
+            destination: destination_from_env()?,
+            catalog,
+            store,
+            uploading: Mutex::new(None),
+        })
+    }
+
+    pub fn status(&self) -> Result<BackupStatus> {
+        let (pending, recently_completed) = self.catalog.backup_queue(RECENT_COMPLETED_LIMIT)?;
+        Ok(BackupStatus {
+            destination: self
+                .destination
+                .as_ref()
+                .map(|destination| destination.name()),
+            uploading: self.uploading.lock().unwrap().clone(),
+            pending,
+            recently_completed,
+        })
+    }
+
+    async fn run_due(&self, destination: &Arc<dyn BackupDestination>) -> Result<()> {
+        for item in self.catalog.due_backups(Utc::now().timestamp())? {
+            self.back_up(destination, item).await?;
+        }
+        Ok(())
+    }
+
+    async fn back_up(
+        &self,
+        destination: &Arc<dyn BackupDestination>,
+        item: BackupItem,
+    ) -> Result<()> {
+        let file_name = item.file_name.clone();
+        let path = match self.store.resolve(&file_name) {
+            Ok(path) => path,
+            // only a failure to read the directory can go away on its own, anything else means
+            // the recording was deleted, offloaded or was never one, so retrying is pointless
+            Err(StoreError::Io(err)) => {
+                let delay = retry_delay(item.attempts);
+                warn!("Unable to find {file_name} to back up, retrying in {delay} seconds, error: {err}");
+                let now = Utc::now().timestamp();
+                return self
+                    .catalog
+                    .backup_failed(&file_name, &err.to_string(), now + delay);
+            }
+            Err(err) => {
+                warn!("Dropping {file_name} from the backup queue, error: {err}");
+                return self.catalog.remove_backup(&file_name);
+            }
+        };
+
+        *self.uploading.lock().unwrap() = Some(file_name.clone());
+        let upload_destination = destination.clone();
+        let upload_name = file_name.clone();
+        let uploaded = spawn_blocking(move || {
+            upload_destination.upload(&upload_name, &path)?;
+            let sidecar = sidecar_path(&path);
+            if let Some(sidecar_name) = sidecar.file_name().and_then(|name| name.to_str()) {
+                if sidecar.exists() {
+                    upload_destination.upload(sidecar_name, &sidecar)?;
+                }
+            }
+            Ok(path)
+        })
+        .await
+        .map_err(anyhow::Error::from)
+        .and_then(|uploaded| uploaded);
+        *self.uploading.lock().unwrap() = None;
+
+        let now = Utc::now().timestamp();
+        match uploaded {
+            Ok(path) => {
+                self.catalog.backup_completed(&file_name, now)?;
+                audit::record(
+                    "backup",
+                    "upload",
+                    &path,
+                    &format!("copied to {}", destination.name()),
+                );
+            }
+            Err(err) => {
+                let delay = retry_delay(item.attempts);
+                warn!(
+                    "Backup of {file_name} failed, attempt {}, retrying in {delay} seconds, error: {err}",
+                    item.attempts + 1
+                );
+                self.catalog
+                    .backup_failed(&file_name, &err.to_string(), now + delay)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn retry_delay(attempts: u32) -> i64 {
+    (BASE_RETRY_SECS << attempts.min(16)).min(MAX_RETRY_SECS)
+}
+
+/// Queues recordings as they finish and works through the backup queue.
+pub async fn backup_task(backup: Arc<Backup>, mut events: broadcast::Receiver<Event>) {
+    let Some(destination) = backup.destination.clone() else {
+        return;
+    };
+    info!("Backing up recordings to {}", destination.name());
+    loop {
+        if let Err(err) = backup.run_due(&destination).await {
+            error!("Error working through backup queue, error: {err}");
+        }
+        let now = Utc::now().timestamp();
+        let wait = match backup.catalog.next_backup_at() {
+            Ok(Some(next)) => (next - now).clamp(1, IDLE_CHECK_SECS),
+            _ => IDLE_CHECK_SECS,
+        };
+        // events that don't queue anything shouldn't cut the wait short and rerun the queue
+        let wake = Instant::now() + Duration::from_secs(wait as u64);
+        loop {
+            tokio::select! {
+                _ = sleep_until(wake) => break,
+                event = events.recv() => {
+                    let queued = match event {
+                        Ok(Event { kind: EventKind::RecordingStopped { file_name }, .. }) => {
+                            backup.catalog.enqueue_backup(&file_name, now)
+                        }
+                        Ok(_) => continue,
+                        // finished recordings may have been among the missed events
+                        Err(RecvError::Lagged(_)) => backup
+                            .catalog
+                            .enqueue_backups_since(now - LAGGED_LOOKBACK_SECS, now)
+                            .map(|_| ()),
+                        Err(RecvError::Closed) => return,
+                    };
+                    if let Err(err) = queued {
+                        error!("Error queueing recording for backup, error: {err}");
+                    }
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        body::Bytes,
+        extract::{Path as UrlPath, State},
+        http::{HeaderMap, Method as HttpMethod, StatusCode as HttpStatus},
+        response::{IntoResponse, Response},
+        routing::any,
+        Router,
+    };
+    use std::collections::HashMap;
+    use tempfile::TempDir;
+    use tokio::net::TcpListener;
+
+    /// In memory WebDAV collection understanding just what [`WebDavDestination`] sends.
+    #[derive(Clone, Default)]
+    struct DavServer {
+        files: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+        /// `Content-Range` headers of every `PUT` received.
+        ranges: Arc<Mutex<Vec<String>>>,
+        refuse_ranges: bool,
+    }
+
+    async fn dav(
+        State(server): State<DavServer>,
+        method: HttpMethod,
+        UrlPath(name): UrlPath<String>,
+        headers: HeaderMap,
+        body: Bytes,
+    ) -> Response {
+        let mut files = server.files.lock().unwrap();
+        match method.as_str() {
+            "HEAD" => match files.get(&name) {
+                Some(contents) => contents.clone().into_response(),
+                None => HttpStatus::NOT_FOUND.into_response(),
+            },
+            "PUT" => {
+                let Some(range) = headers.get(header::CONTENT_RANGE) else {
+                    files.insert(name, body.to_vec());
+                    return HttpStatus::CREATED.into_response();
+                };
+                let range = range.to_str().unwrap().to_string();
+                server.ranges.lock().unwrap().push(range.clone());
+                if server.refuse_ranges {
+                    return HttpStatus::NOT_IMPLEMENTED.into_response();
+                }
+                let start: usize = range
+                    .trim_start_matches("bytes ")
+                    .split('-')
+                    .next()
+                    .unwrap()
+                    .parse()
+                    .unwrap();
+                let contents = files.entry(name).or_default();
+                if contents.len() != start {
+                    return HttpStatus::RANGE_NOT_SATISFIABLE.into_response();
+                }
+                contents.extend_from_slice(&body);
+                HttpStatus::NO_CONTENT.into_response()
+            }
+            "MOVE" => {
+                let destination = headers["Destination"].to_str().unwrap();
+                let target = destination.rsplit('/').next().unwrap().to_string();
+                match files.remove(&name) {
+                    Some(contents) => {
+                        files.insert(target, contents);
+                        HttpStatus::CREATED.into_response()
+                    }
+                    None => HttpStatus::NOT_FOUND.into_response(),
+                }
+            }
+            _ => HttpStatus::METHOD_NOT_ALLOWED.into_response(),
+        }
+    }
+
+    async fn start_server(server: DavServer) -> WebDavDestination {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let app = Router::new().route("/{name}", any(dav)).with_state(server);
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        WebDavDestination {
+            url,
+            username: None,
+            password: None,
+        }
+    }
+
+    fn recording(dir: &TempDir) -> (PathBuf, Vec<u8>) {
+        let contents: Vec<u8> = (0..100_000u32).map(|byte| byte as u8).collect();
+        let path = dir.path().join("motion_1.mp4");
+        fs::write(&path, &contents).unwrap();
+        (path, contents)
+    }
+
+    async fn upload(destination: WebDavDestination, path: PathBuf) -> Result<()> {
+        spawn_blocking(move || destination.upload("motion_1.mp4", &path))
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn webdav_upload_resumes_partial_copy() {
+        let dir = TempDir::new().unwrap();
+        let (path, contents) = recording(&dir);
+        let server = DavServer::default();
+        server
+            .files
+            .lock()
+            .unwrap()
+            .insert("motion_1.mp4.part".to_string(), contents[..40_000].to_vec());
+        let destination = start_server(server.clone()).await;
+
+        upload(destination, path).await.unwrap();
+        let files = server.files.lock().unwrap();
+        assert_eq!(files["motion_1.mp4"], contents);
+        assert!(!files.contains_key("motion_1.mp4.part"));
+        assert_eq!(
+            *server.ranges.lock().unwrap(),
+            vec!["bytes 40000-99999/100000".to_string()]
+        );
+    }
+
+    #[tokio::test]
+    async fn webdav_upload_starts_over_when_resume_is_refused() {
+        let dir = TempDir::new().unwrap();
+        let (path, contents) = recording(&dir);
+        let server = DavServer {
+            refuse_ranges: true,
+            ..Default::default()
+        };
+        server
+            .files
+            .lock()
+            .unwrap()
+            .insert("motion_1.mp4.part".to_string(), contents[..40_000].to_vec());
+        let destination = start_server(server.clone()).await;
+
+        upload(destination, path).await.unwrap();
+        assert_eq!(server.files.lock().unwrap()["motion_1.mp4"], contents);
+        assert_eq!(server.ranges.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn webdav_upload_skips_finished_copy() {
+        let dir = TempDir::new().unwrap();
+        let (path, contents) = recording(&dir);
+        let server = DavServer::default();
+        server
+            .files
+            .lock()
+            .unwrap()
+            .insert("motion_1.mp4".to_string(), contents.clone());
+        let destination = start_server(server.clone()).await;
+
+        upload(destination, path).await.unwrap();
+        assert_eq!(server.files.lock().unwrap().len(), 1);
+        assert!(server.ranges.lock().unwrap().is_empty());
+    }
+
+    struct FailingDestination;
+
+    impl BackupDestination for FailingDestination {
+        fn name(&self) -> &'static str {
+            "failing"
+        }
+
+        fn upload(&self, _file_name: &str, _source: &Path) -> Result<()> {
+            bail!("destination unreachable")
+        }
+    }
+
+    #[tokio::test]
+    async fn missing_recording_is_dropped_from_queue() {
+        let dir = TempDir::new().unwrap();
+        recording(&dir);
+        let catalog = Arc::new(Catalog::open(&dir.path().join("catalog.db")).unwrap());
+        let backup = Backup {
+            destination: None,
+            catalog: catalog.clone(),
+            store: Arc::new(RecordingStore::new(dir.path().to_path_buf())),
+            uploading: Mutex::new(None),
+        };
+        let destination: Arc<dyn BackupDestination> = Arc::new(FailingDestination);
+        catalog.enqueue_backup("motion_1.mp4", 0).unwrap();
+        catalog.enqueue_backup("motion_2.mp4", 0).unwrap();
+
+        backup.run_due(&destination).await.unwrap();
+        let (pending, _) = catalog.backup_queue(10).unwrap();
+        // the recording that exists is kept for a retry, the missing one is gone for good
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].file_name, "motion_1.mp4");
+        assert_eq!(pending[0].attempts, 1);
+    }
+}
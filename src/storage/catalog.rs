@@ -0,0 +1,1000 @@
+use super::{
+    metadata::{read_sidecar, RecordingMetadata},
+    recording_time, video_save_path,
+};
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ffmpeg_next::{ffi::AV_TIME_BASE, format::input, media::Type};
+use glob::glob;
+use rusqlite::{params, params_from_iter, types::Value, Connection, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    env::var,
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::UNIX_EPOCH,
+};
+use tracing::{error, info};
+
+/// What is known about a recording without opening the file again.
+#[derive(Serialize, Clone, Debug)]
+pub struct CatalogEntry {
+    pub file_name: String,
+    /// Unix timestamp, in seconds, of when the recording started.
+    pub recorded: i64,
+    pub duration: f64,
+    pub width: u32,
+    pub height: u32,
+    pub size_bytes: u64,
+    pub codec: String,
+    pub trigger: Option<String>,
+    pub metadata: Option<RecordingMetadata>,
+    pub tags: Vec<String>,
+    pub starred: bool,
+    pub notes: Option<String>,
+    /// Protected recordings are never removed by automatic cleanup.
+    pub protected: bool,
+    /// Unix timestamp, in seconds, of when the recording was moved to the trash.
+    pub trashed_at: Option<i64>,
+    /// Name of the storage backend the recording was offloaded to.
+    pub offloaded_to: Option<String>,
+    /// Unix timestamp, in seconds, of when the recording finished uploading to its backend.
+    pub offloaded_at: Option<i64>,
+}
+
+/// Changes to the user-assigned details of a recording, fields left as `None` are kept as they are.
+#[derive(Deserialize, Debug)]
+pub struct Annotation {
+    pub tags: Option<Vec<String>>,
+    pub starred: Option<bool>,
+    pub notes: Option<String>,
+    pub protected: Option<bool>,
+}
+
+/// Separates tags packed into one column by `group_concat`, tags may not contain control characters.
+const TAG_SEPARATOR: char = '\u{1f}';
+
+const SELECT_ENTRIES: &str = "SELECT recordings.*,
+    (SELECT group_concat(tag, char(31)) FROM recording_tags
+        WHERE recording_tags.file_name = recordings.file_name) AS tags
+    FROM recordings";
+
+impl CatalogEntry {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(CatalogEntry {
+            file_name: row.get("file_name")?,
+            recorded: row.get("recorded")?,
+            duration: row.get("duration")?,
+            width: row.get("width")?,
+            height: row.get("height")?,
+            size_bytes: row.get::<_, i64>("size_bytes")? as u64,
+            codec: row.get("codec")?,
+            trigger: row.get("trigger")?,
+            metadata: row
+                .get::<_, Option<String>>("metadata")?
+                .and_then(|metadata| serde_json::from_str(&metadata).ok()),
+            tags: row
+                .get::<_, Option<String>>("tags")?
+                .map(|tags| tags.split(TAG_SEPARATOR).map(String::from).collect())
+                .unwrap_or_default(),
+            starred: row.get("starred")?,
+            notes: row.get("notes")?,
+            protected: row.get("protected")?,
+            trashed_at: row.get("trashed_at")?,
+            offloaded_to: row.get("offloaded_to")?,
+            offloaded_at: row.get("offloaded_at")?,
+        })
+    }
+}
+
+/// A recording waiting to be, or already, copied to the backup destination.
+#[derive(Serialize, Clone, Debug)]
+pub struct BackupItem {
+    pub file_name: String,
+    /// Unix timestamps, in seconds.
+    pub queued_at: i64,
+    pub attempts: u32,
+    pub next_attempt_at: i64,
+    pub last_error: Option<String>,
+    pub completed_at: Option<i64>,
+}
+
+impl BackupItem {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(BackupItem {
+            file_name: row.get("file_name")?,
+            queued_at: row.get("queued_at")?,
+            attempts: row.get("attempts")?,
+            next_attempt_at: row.get("next_attempt_at")?,
+            last_error: row.get("last_error")?,
+            completed_at: row.get("completed_at")?,
+        })
+    }
+}
+
+/// What search results are ordered by.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SortField {
+    #[default]
+    Recorded,
+    Duration,
+    Size,
+}
+
+impl SortField {
+    fn column(&self) -> &'static str {
+        match self {
+            SortField::Recorded => "recorded",
+            SortField::Duration => "duration",
+            SortField::Size => "size_bytes",
+        }
+    }
+
+    fn value(&self, entry: &CatalogEntry) -> f64 {
+        match self {
+            SortField::Recorded => entry.recorded as f64,
+            SortField::Duration => entry.duration,
+            SortField::Size => entry.size_bytes as f64,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    Asc,
+    #[default]
+    Desc,
+}
+
+/// Which recordings a search matches and the order they come back in, unset fields match everything.
+#[derive(Clone, Debug, Default)]
+pub struct SearchFilter {
+    /// Unix timestamps, in seconds, recordings must start at or after `from` and before `to`.
+    pub from: Option<i64>,
+    pub to: Option<i64>,
+    /// Duration bounds in seconds, both inclusive.
+    pub min_duration: Option<f64>,
+    pub max_duration: Option<f64>,
+    pub trigger: Option<String>,
+    /// Recordings must have every one of these tags.
+    pub tags: Vec<String>,
+    pub sort: SortField,
+    pub order: SortOrder,
+}
+
+/// Where a page of search results ended, the next page starts with the recording after it.
+///
+/// Positions are kept as the sort value and file name of the last recording rather than an offset,
+/// so recordings added while paging don't shift later pages.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SearchCursor {
+    value: f64,
+    file_name: String,
+}
+
+impl SearchCursor {
+    /// Packs the cursor into an opaque URL-safe string for clients to send back.
+    pub fn encode(&self) -> String {
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(self).unwrap_or_default())
+    }
+
+    pub fn decode(cursor: &str) -> Result<Self> {
+        let json = URL_SAFE_NO_PAD
+            .decode(cursor)
+            .map_err(|_| anyhow!("Invalid cursor"))?;
+        serde_json::from_slice(&json).map_err(|_| anyhow!("Invalid cursor"))
+    }
+}
+
+/// One page of search results.
+#[derive(Debug)]
+pub struct SearchPage {
+    pub entries: Vec<CatalogEntry>,
+    /// Recordings matching the filter across every page.
+    pub total: u64,
+    /// `None` on the last page.
+    pub next_cursor: Option<SearchCursor>,
+}
+
+/// Schema changes applied in order, the database's `user_version` records how many have run.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE IF NOT EXISTS recordings (
+        file_name TEXT PRIMARY KEY,
+        recorded INTEGER NOT NULL,
+        duration REAL NOT NULL,
+        width INTEGER NOT NULL,
+        height INTEGER NOT NULL,
+        size_bytes INTEGER NOT NULL,
+        codec TEXT NOT NULL,
+        trigger TEXT
+    );
+    CREATE INDEX IF NOT EXISTS recordings_recorded ON recordings (recorded);",
+    "ALTER TABLE recordings ADD COLUMN metadata TEXT;",
+    "ALTER TABLE recordings ADD COLUMN starred INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE recordings ADD COLUMN notes TEXT;
+    ALTER TABLE recordings ADD COLUMN protected INTEGER NOT NULL DEFAULT 0;
+    CREATE TABLE recording_tags (
+        file_name TEXT NOT NULL,
+        tag TEXT NOT NULL,
+        PRIMARY KEY (file_name, tag)
+    );
+    CREATE INDEX recording_tags_tag ON recording_tags (tag);",
+    "ALTER TABLE recordings ADD COLUMN trashed_at INTEGER;",
+    "ALTER TABLE recordings ADD COLUMN offloaded_to TEXT;
+    ALTER TABLE recordings ADD COLUMN offloaded_at INTEGER;",
+    "CREATE TABLE backup_queue (
+        file_name TEXT PRIMARY KEY,
+        queued_at INTEGER NOT NULL,
+        attempts INTEGER NOT NULL DEFAULT 0,
+        next_attempt_at INTEGER NOT NULL,
+        last_error TEXT,
+        completed_at INTEGER
+    );
+    CREATE INDEX backup_queue_next_attempt ON backup_queue (completed_at, next_attempt_at);",
+    "CREATE TABLE events (
+        id INTEGER PRIMARY KEY,
+        timestamp INTEGER NOT NULL,
+        kind TEXT NOT NULL
+    );
+    CREATE INDEX events_kind_timestamp ON events (kind, timestamp);",
+];
+
+fn migrate(conn: &Connection) -> Result<()> {
+    let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        conn.execute_batch(migration)?;
+        conn.pragma_update(None, "user_version", index + 1)?;
+    }
+    Ok(())
+}
+
+/// Index of every recording in the recordings directory, kept in an SQLite database.
+pub struct Catalog {
+    conn: Mutex<Connection>,
+}
+
+impl Catalog {
+    pub fn open(path: &Path) -> Result<Self> {
+        let conn = Connection::open(path)?;
+        migrate(&conn)?;
+        Ok(Catalog {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    /// Opens the catalog at `CATALOG_PATH`, defaulting to `catalog.db` in the recordings directory.
+    pub fn open_default() -> Result<Self> {
+        let path = match var("CATALOG_PATH") {
+            Ok(path) => PathBuf::from(path),
+            Err(_) => video_save_path().join("catalog.db"),
+        };
+        Catalog::open(&path)
+    }
+
+    pub fn upsert(&self, entry: &CatalogEntry) -> Result<()> {
+        self.conn.lock().unwrap().execute(
+            "INSERT INTO recordings (file_name, recorded, duration, width, height, size_bytes, codec, trigger, metadata)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+             ON CONFLICT (file_name) DO UPDATE SET
+                recorded = excluded.recorded,
+                duration = excluded.duration,
+                width = excluded.width,
+                height = excluded.height,
+                size_bytes = excluded.size_bytes,
+                codec = excluded.codec,
+                trigger = COALESCE(excluded.trigger, recordings.trigger),
+                metadata = COALESCE(excluded.metadata, recordings.metadata)",
+            params![
+                entry.file_name,
+                entry.recorded,
+                entry.duration,
+                entry.width,
+                entry.height,
+                entry.size_bytes as i64,
+                entry.codec,
+                entry.trigger,
+                entry
+                    .metadata
+                    .as_ref()
+                    .and_then(|metadata| serde_json::to_string(metadata).ok()),
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn remove(&self, file_name: &str) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute("DELETE FROM recordings WHERE file_name = ?1", [file_name])?;
+        conn.execute(
+            "DELETE FROM recording_tags WHERE file_name = ?1",
+            [file_name],
+        )?;
+        Ok(())
+    }
+
+    pub fn is_protected(&self, file_name: &str) -> Result<bool> {
+        let protected = self
+            .conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT protected FROM recordings WHERE file_name = ?1",
+                [file_name],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(protected.unwrap_or(false))
+    }
+
+    /// Applies user changes to a recording, returning `None` if it is not in the catalog.
+    pub fn annotate(
+        &self,
+        file_name: &str,
+        annotation: &Annotation,
+    ) -> Result<Option<CatalogEntry>> {
+        {
+            let mut conn = self.conn.lock().unwrap();
+            let transaction = conn.transaction()?;
+            let exists = transaction
+                .query_row(
+                    "SELECT 1 FROM recordings WHERE file_name = ?1",
+                    [file_name],
+                    |_| Ok(()),
+                )
+                .optional()?
+                .is_some();
+            if !exists {
+                return Ok(None);
+            }
+            if let Some(starred) = annotation.starred {
+                transaction.execute(
+                    "UPDATE recordings SET starred = ?2 WHERE file_name = ?1",
+                    params![file_name, starred],
+                )?;
+            }
+            if let Some(notes) = &annotation.notes {
+                let notes = Some(notes.as_str()).filter(|notes| !notes.trim().is_empty());
+                transaction.execute(
+                    "UPDATE recordings SET notes = ?2 WHERE file_name = ?1",
+                    params![file_name, notes],
+                )?;
+            }
+            if let Some(protected) = annotation.protected {
+                transaction.execute(
+                    "UPDATE recordings SET protected = ?2 WHERE file_name = ?1",
+                    params![file_name, protected],
+                )?;
+            }
+            if let Some(tags) = &annotation.tags {
+                transaction.execute(
+                    "DELETE FROM recording_tags WHERE file_name = ?1",
+                    [file_name],
+                )?;
+                for tag in tags {
+                    transaction.execute(
+                        "INSERT OR IGNORE INTO recording_tags (file_name, tag) VALUES (?1, ?2)",
+                        params![file_name, tag],
+                    )?;
+                }
+            }
+            transaction.commit()?;
+        }
+        self.get(file_name)
+    }
+
+    pub fn get(&self, file_name: &str) -> Result<Option<CatalogEntry>> {
+        let entry = self
+            .conn
+            .lock()
+            .unwrap()
+            .query_row(
+                &format!("{SELECT_ENTRIES} WHERE file_name = ?1"),
+                [file_name],
+                CatalogEntry::from_row,
+            )
+            .optional()?;
+        Ok(entry)
+    }
+
+    /// Recordings started at or after `from` and before `to`, both unix timestamps in seconds, oldest first.
+    pub fn recorded_between(&self, from: i64, to: i64) -> Result<Vec<CatalogEntry>> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare(&format!(
+            "{SELECT_ENTRIES} WHERE trashed_at IS NULL AND recorded >= ?1 AND recorded < ?2 ORDER BY recorded"
+        ))?;
+        let entries = statement
+            .query_map([from, to], CatalogEntry::from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(entries)
+    }
+
+    /// Recordings outside the trash matching `filter`, up to `limit` of them starting after `cursor`.
+    pub fn search(
+        &self,
+        filter: &SearchFilter,
+        cursor: Option<&SearchCursor>,
+        limit: usize,
+    ) -> Result<SearchPage> {
+        let mut conditions = vec!["trashed_at IS NULL".to_string()];
+        let mut values = Vec::new();
+        if let Some(from) = filter.from {
+            conditions.push("recorded >= ?".to_string());
+            values.push(Value::Integer(from));
+        }
+        if let Some(to) = filter.to {
+            conditions.push("recorded < ?".to_string());
+            values.push(Value::Integer(to));
+        }
+        if let Some(min_duration) = filter.min_duration {
+            conditions.push("duration >= ?".to_string());
+            values.push(Value::Real(min_duration));
+        }
+        if let Some(max_duration) = filter.max_duration {
+            conditions.push("duration <= ?".to_string());
+            values.push(Value::Real(max_duration));
+        }
+        if let Some(trigger) = &filter.trigger {
+            conditions.push("recordings.trigger = ?".to_string());
+            values.push(Value::Text(trigger.clone()));
+        }
+        for tag in &filter.tags {
+            conditions.push(
+                "EXISTS (SELECT 1 FROM recording_tags
+                    WHERE recording_tags.file_name = recordings.file_name AND tag = ?)"
+                    .to_string(),
+            );
+            values.push(Value::Text(tag.clone()));
+        }
+
+        let conn = self.conn.lock().unwrap();
+        let total: i64 = conn.query_row(
+            &format!(
+                "SELECT count(*) FROM recordings WHERE {}",
+                conditions.join(" AND ")
+            ),
+            params_from_iter(&values),
+            |row| row.get(0),
+        )?;
+
+        let column = filter.sort.column();
+        let (comparison, direction) = match filter.order {
+            SortOrder::Asc => (">", "ASC"),
+            SortOrder::Desc => ("<", "DESC"),
+        };
+        if let Some(cursor) = cursor {
+            // file names break ties between recordings with the same sort value
+            conditions.push(format!(
+                "({column} {comparison} ? OR ({column} = ? AND recordings.file_name {comparison} ?))"
+            ));
+            values.push(Value::Real(cursor.value));
+            values.push(Value::Real(cursor.value));
+            values.push(Value::Text(cursor.file_name.clone()));
+        }
+        // one more than asked for shows whether there is another page
+        values.push(Value::Integer(limit as i64 + 1));
+        let mut statement = conn.prepare(&format!(
+            "{SELECT_ENTRIES} WHERE {} ORDER BY {column} {direction}, recordings.file_name {direction} LIMIT ?",
+            conditions.join(" AND ")
+        ))?;
+        let mut entries = statement
+            .query_map(params_from_iter(&values), CatalogEntry::from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        let next_cursor = if entries.len() > limit {
+            entries.truncate(limit);
+            entries.last().map(|last| SearchCursor {
+                value: filter.sort.value(last),
+                file_name: last.file_name.clone(),
+            })
+        } else {
+            None
+        };
+        Ok(SearchPage {
+            entries,
+            total: total as u64,
+            next_cursor,
+        })
+    }
+
+    /// Marks a recording as moved to the trash, or back out of it with `None`.
+    pub fn set_trashed(&self, file_name: &str, trashed_at: Option<i64>) -> Result<()> {
+        self.conn.lock().unwrap().execute(
+            "UPDATE recordings SET trashed_at = ?2 WHERE file_name = ?1",
+            params![file_name, trashed_at],
+        )?;
+        Ok(())
+    }
+
+    /// Recordings in the trash, oldest deletion first.
+    pub fn trashed(&self) -> Result<Vec<CatalogEntry>> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare(&format!(
+            "{SELECT_ENTRIES} WHERE trashed_at IS NOT NULL ORDER BY trashed_at"
+        ))?;
+        let entries = statement
+            .query_map([], CatalogEntry::from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(entries)
+    }
+
+    /// Records that a recording has been uploaded to the named storage backend.
+    pub fn set_offloaded(&self, file_name: &str, backend: &str, offloaded_at: i64) -> Result<()> {
+        self.conn.lock().unwrap().execute(
+            "UPDATE recordings SET offloaded_to = ?2, offloaded_at = ?3 WHERE file_name = ?1",
+            params![file_name, backend, offloaded_at],
+        )?;
+        Ok(())
+    }
+
+    /// Recordings not yet uploaded to a storage backend, oldest first.
+    pub fn pending_offload(&self) -> Result<Vec<CatalogEntry>> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare(&format!(
+            "{SELECT_ENTRIES} WHERE trashed_at IS NULL AND offloaded_at IS NULL ORDER BY recorded"
+        ))?;
+        let entries = statement
+            .query_map([], CatalogEntry::from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(entries)
+    }
+
+    /// Adds a recording to the backup queue, leaving it alone if it is already queued or backed up.
+    pub fn enqueue_backup(&self, file_name: &str, now: i64) -> Result<()> {
+        self.conn.lock().unwrap().execute(
+            "INSERT OR IGNORE INTO backup_queue (file_name, queued_at, next_attempt_at)
+             VALUES (?1, ?2, ?2)",
+            params![file_name, now],
+        )?;
+        Ok(())
+    }
+
+    /// Queues every catalogued recording started at or after `since` that isn't queued yet.
+    pub fn enqueue_backups_since(&self, since: i64, now: i64) -> Result<usize> {
+        let queued = self.conn.lock().unwrap().execute(
+            "INSERT OR IGNORE INTO backup_queue (file_name, queued_at, next_attempt_at)
+             SELECT file_name, ?2, ?2 FROM recordings WHERE trashed_at IS NULL AND recorded >= ?1",
+            params![since, now],
+        )?;
+        Ok(queued)
+    }
+
+    /// Queued backups due to be attempted at `now`, oldest first.
+    pub fn due_backups(&self, now: i64) -> Result<Vec<BackupItem>> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare(
+            "SELECT * FROM backup_queue WHERE completed_at IS NULL AND next_attempt_at <= ?1
+             ORDER BY queued_at",
+        )?;
+        let items = statement
+            .query_map([now], BackupItem::from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(items)
+    }
+
+    /// When the next queued backup is due, `None` if nothing is waiting.
+    pub fn next_backup_at(&self) -> Result<Option<i64>> {
+        let next = self.conn.lock().unwrap().query_row(
+            "SELECT min(next_attempt_at) FROM backup_queue WHERE completed_at IS NULL",
+            [],
+            |row| row.get(0),
+        )?;
+        Ok(next)
+    }
+
+    pub fn backup_completed(&self, file_name: &str, now: i64) -> Result<()> {
+        self.conn.lock().unwrap().execute(
+            "UPDATE backup_queue SET completed_at = ?2, last_error = NULL,
+                attempts = attempts + 1 WHERE file_name = ?1",
+            params![file_name, now],
+        )?;
+        Ok(())
+    }
+
+    pub fn backup_failed(&self, file_name: &str, error: &str, next_attempt_at: i64) -> Result<()> {
+        self.conn.lock().unwrap().execute(
+            "UPDATE backup_queue SET attempts = attempts + 1, last_error = ?2, next_attempt_at = ?3
+             WHERE file_name = ?1",
+            params![file_name, error, next_attempt_at],
+        )?;
+        Ok(())
+    }
+
+    /// Takes a recording off the backup queue, for one that is gone before it could be copied.
+    pub fn remove_backup(&self, file_name: &str) -> Result<()> {
+        self.conn
+            .lock()
+            .unwrap()
+            .execute("DELETE FROM backup_queue WHERE file_name = ?1", [file_name])?;
+        Ok(())
+    }
+
+    /// Every backup still waiting, followed by the `recent` most recently completed ones.
+    pub fn backup_queue(&self, recent: usize) -> Result<(Vec<BackupItem>, Vec<BackupItem>)> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn
+            .prepare("SELECT * FROM backup_queue WHERE completed_at IS NULL ORDER BY queued_at")?;
+        let pending = statement
+            .query_map([], BackupItem::from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        let mut statement = conn.prepare(
+            "SELECT * FROM backup_queue WHERE completed_at IS NOT NULL
+             ORDER BY completed_at DESC LIMIT ?1",
+        )?;
+        let completed = statement
+            .query_map([recent as i64], BackupItem::from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok((pending, completed))
+    }
+
+    /// Keeps an event raised by the motion detector, `timestamp` is a unix timestamp in seconds.
+    pub fn record_event(&self, timestamp: i64, kind: &str) -> Result<()> {
+        self.conn.lock().unwrap().execute(
+            "INSERT INTO events (timestamp, kind) VALUES (?1, ?2)",
+            params![timestamp, kind],
+        )?;
+        Ok(())
+    }
+
+    /// Deletes events raised before `before`, returning how many were removed.
+    pub fn prune_events(&self, before: i64) -> Result<usize> {
+        let removed = self
+            .conn
+            .lock()
+            .unwrap()
+            .execute("DELETE FROM events WHERE timestamp < ?1", [before])?;
+        Ok(removed)
+    }
+
+    /// Times of the events of `kind` raised at or after `from` and before `to`, oldest first.
+    pub fn event_times(&self, kind: &str, from: i64, to: i64) -> Result<Vec<i64>> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare(
+            "SELECT timestamp FROM events WHERE kind = ?1 AND timestamp >= ?2 AND timestamp < ?3
+             ORDER BY timestamp",
+        )?;
+        let times = statement
+            .query_map(params![kind, from, to], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(times)
+    }
+
+    fn file_names(&self) -> Result<HashSet<String>> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement =
+            conn.prepare("SELECT file_name FROM recordings WHERE trashed_at IS NULL")?;
+        let names = statement
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<HashSet<String>>>()?;
+        Ok(names)
+    }
+
+    /// Probes a finished recording and adds it to the catalog.
+    pub fn index_recording(&self, path: &Path, trigger: Option<String>) -> Result<CatalogEntry> {
+        let entry = probe_recording(path, trigger)?;
+        self.upsert(&entry)?;
+        Ok(entry)
+    }
+
+    /// Brings the catalog in line with the recordings on disk.
+    ///
+    /// Files missing from the catalog are probed and added and entries whose file is gone are dropped,
+    /// unless the recording was offloaded to a storage backend and is only kept there.
+    /// With `full` set every file is probed again, not just new ones.
+    pub fn rebuild(&self, dir: &Path, full: bool) -> Result<usize> {
+        let pattern = format!("{}/*.mp4", dir.display());
+        let known = self.file_names()?;
+        let mut on_disk = HashSet::new();
+        let mut indexed = 0;
+
+        for path in glob(&pattern)?.filter_map(|path| path.ok()) {
+            let Some(file_name) = path.file_name().and_then(|name| name.to_str()) else {
+                continue;
+            };
+            on_disk.insert(file_name.to_string());
+            if !full && known.contains(file_name) {
+                continue;
+            }
+            match self.index_recording(&path, None) {
+                Ok(_) => indexed += 1,
+                Err(err) => error!("Unable to index {}, error: {err}", path.display()),
+            }
+        }
+        for stale in known.difference(&on_disk) {
+            if self
+                .get(stale)?
+                .is_some_and(|entry| entry.offloaded_at.is_some())
+            {
+                continue;
+            }
+            self.remove(stale)?;
+        }
+        info!("Catalog rebuilt, {indexed} recordings indexed");
+        Ok(indexed)
+    }
+}
+
+/// Opens a recording with ffmpeg to read its duration, resolution and codec.
+///
+/// The trigger falls back to the one in the recording's sidecar metadata when not given.
+pub fn probe_recording(path: &Path, trigger: Option<String>) -> Result<CatalogEntry> {
+    let metadata_sidecar = read_sidecar(path);
+    let trigger = trigger.or_else(|| {
+        metadata_sidecar
+            .as_ref()
+            .map(|metadata| metadata.trigger_source.clone())
+    });
+    let context = input(path)?;
+    let duration = context.duration() as f64 / AV_TIME_BASE as f64;
+    let (width, height, codec) = match context.streams().best(Type::Video) {
+        Some(stream) => {
+            let parameters = stream.parameters();
+            let codec = parameters.id().name().to_string();
+            let decoder = ffmpeg_next::codec::context::Context::from_parameters(parameters)?
+                .decoder()
+                .video()?;
+            (decoder.width(), decoder.height(), codec)
+        }
+        None => (0, 0, "none".to_string()),
+    };
+
+    let metadata = fs::metadata(path)?;
+    let recorded = recording_time(path)
+        .unwrap_or(metadata.modified()?)
+        .duration_since(UNIX_EPOCH)?
+        .as_secs() as i64;
+    let file_name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| anyhow!("Recording path {} has no file name", path.display()))?
+        .to_string();
+
+    Ok(CatalogEntry {
+        file_name,
+        recorded,
+        duration,
+        width,
+        height,
+        size_bytes: metadata.len(),
+        codec,
+        trigger,
+        metadata: metadata_sidecar,
+        tags: Vec::new(),
+        starred: false,
+        notes: None,
+        protected: false,
+        trashed_at: None,
+        offloaded_to: None,
+        offloaded_at: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn catalog_entry(file_name: &str, recorded: i64, duration: f64) -> CatalogEntry {
+        CatalogEntry {
+            file_name: file_name.to_string(),
+            recorded,
+            duration,
+            width: 1920,
+            height: 1080,
+            size_bytes: 1000,
+            codec: "h264".to_string(),
+            trigger: None,
+            metadata: None,
+            tags: Vec::new(),
+            starred: false,
+            notes: None,
+            protected: false,
+            trashed_at: None,
+            offloaded_to: None,
+            offloaded_at: None,
+        }
+    }
+
+    fn tag(catalog: &Catalog, file_name: &str, tags: &[&str]) {
+        let annotation = Annotation {
+            tags: Some(tags.iter().map(|tag| tag.to_string()).collect()),
+            starred: None,
+            notes: None,
+            protected: None,
+        };
+        catalog.annotate(file_name, &annotation).unwrap().unwrap();
+    }
+
+    fn names(entries: &[CatalogEntry]) -> Vec<&str> {
+        entries
+            .iter()
+            .map(|entry| entry.file_name.as_str())
+            .collect()
+    }
+
+    /// Follows the cursor through every page, checking the total doesn't change along the way.
+    fn all_pages(catalog: &Catalog, filter: &SearchFilter, limit: usize) -> Vec<String> {
+        let mut file_names = Vec::new();
+        let mut cursor: Option<SearchCursor> = None;
+        let mut total = None;
+        loop {
+            let page = catalog.search(filter, cursor.as_ref(), limit).unwrap();
+            assert!(page.entries.len() <= limit);
+            assert_eq!(*total.get_or_insert(page.total), page.total);
+            file_names.extend(page.entries.iter().map(|entry| entry.file_name.clone()));
+            // cursors go out to clients and come back, so send them the same way
+            match page.next_cursor {
+                Some(next) => cursor = Some(SearchCursor::decode(&next.encode()).unwrap()),
+                None => break,
+            }
+        }
+        assert_eq!(total, Some(file_names.len() as u64));
+        file_names
+    }
+
+    #[test]
+    fn search_filters_combine() {
+        let dir = TempDir::new().unwrap();
+        let catalog = Catalog::open(&dir.path().join("catalog.db")).unwrap();
+        for (file_name, recorded, duration, trigger) in [
+            ("a.mp4", 100, 10.0, Some("gpio")),
+            ("b.mp4", 200, 30.0, Some("gpio")),
+            ("c.mp4", 300, 60.0, Some("schedule")),
+            ("d.mp4", 400, 90.0, None),
+        ] {
+            let mut entry = catalog_entry(file_name, recorded, duration);
+            entry.trigger = trigger.map(String::from);
+            catalog.upsert(&entry).unwrap();
+        }
+        tag(&catalog, "a.mp4", &["cat"]);
+        tag(&catalog, "b.mp4", &["cat", "night"]);
+        tag(&catalog, "c.mp4", &["night"]);
+        catalog.set_trashed("d.mp4", Some(500)).unwrap();
+
+        let search = |filter: SearchFilter| {
+            let page = catalog.search(&filter, None, 10).unwrap();
+            assert_eq!(page.total, page.entries.len() as u64);
+            names(&page.entries)
+                .into_iter()
+                .map(String::from)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(search(SearchFilter::default()), ["c.mp4", "b.mp4", "a.mp4"]);
+        // from is inclusive and to exclusive
+        assert_eq!(
+            search(SearchFilter {
+                from: Some(200),
+                to: Some(300),
+                ..Default::default()
+            }),
+            ["b.mp4"]
+        );
+        // both duration bounds are inclusive
+        assert_eq!(
+            search(SearchFilter {
+                min_duration: Some(10.0),
+                max_duration: Some(30.0),
+                ..Default::default()
+            }),
+            ["b.mp4", "a.mp4"]
+        );
+        assert_eq!(
+            search(SearchFilter {
+                trigger: Some("schedule".to_string()),
+                ..Default::default()
+            }),
+            ["c.mp4"]
+        );
+        assert_eq!(
+            search(SearchFilter {
+                tags: vec!["cat".to_string(), "night".to_string()],
+                ..Default::default()
+            }),
+            ["b.mp4"]
+        );
+        assert_eq!(
+            search(SearchFilter {
+                tags: vec!["night".to_string()],
+                trigger: Some("gpio".to_string()),
+                min_duration: Some(20.0),
+                ..Default::default()
+            }),
+            ["b.mp4"]
+        );
+    }
+
+    #[test]
+    fn paging_through_equal_sort_values_skips_and_repeats_nothing() {
+        let dir = TempDir::new().unwrap();
+        let catalog = Catalog::open(&dir.path().join("catalog.db")).unwrap();
+        // three share a start time and three a duration, so pages end in the middle of ties
+        for (file_name, recorded, duration) in [
+            ("a.mp4", 100, 5.0),
+            ("b.mp4", 200, 5.0),
+            ("c.mp4", 200, 5.0),
+            ("d.mp4", 200, 7.5),
+            ("e.mp4", 300, 2.0),
+            ("f.mp4", 50, 7.5),
+        ] {
+            catalog
+                .upsert(&catalog_entry(file_name, recorded, duration))
+                .unwrap();
+        }
+
+        for (sort, order, expected) in [
+            (
+                SortField::Recorded,
+                SortOrder::Desc,
+                ["e.mp4", "d.mp4", "c.mp4", "b.mp4", "a.mp4", "f.mp4"],
+            ),
+            (
+                SortField::Recorded,
+                SortOrder::Asc,
+                ["f.mp4", "a.mp4", "b.mp4", "c.mp4", "d.mp4", "e.mp4"],
+            ),
+            (
+                SortField::Duration,
+                SortOrder::Desc,
+                ["f.mp4", "d.mp4", "c.mp4", "b.mp4", "a.mp4", "e.mp4"],
+            ),
+            (
+                SortField::Duration,
+                SortOrder::Asc,
+                ["e.mp4", "a.mp4", "b.mp4", "c.mp4", "d.mp4", "f.mp4"],
+            ),
+        ] {
+            let filter = SearchFilter {
+                sort,
+                order,
+                ..Default::default()
+            };
+            for limit in 1..=7 {
+                assert_eq!(
+                    all_pages(&catalog, &filter, limit),
+                    expected,
+                    "{sort:?} {order:?} in pages of {limit}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn total_counts_every_page_and_ignores_the_cursor() {
+        let dir = TempDir::new().unwrap();
+        let catalog = Catalog::open(&dir.path().join("catalog.db")).unwrap();
+        for index in 0..5 {
+            catalog
+                .upsert(&catalog_entry(&format!("{index}.mp4"), 100, 1.0))
+                .unwrap();
+        }
+        let filter = SearchFilter::default();
+        let first = catalog.search(&filter, None, 2).unwrap();
+        assert_eq!(names(&first.entries), ["4.mp4", "3.mp4"]);
+        assert_eq!(first.total, 5);
+        let second = catalog
+            .search(&filter, first.next_cursor.as_ref(), 2)
+            .unwrap();
+        assert_eq!(names(&second.entries), ["2.mp4", "1.mp4"]);
+        assert_eq!(second.total, 5);
+        let last = catalog
+            .search(&filter, second.next_cursor.as_ref(), 2)
+            .unwrap();
+        assert_eq!(names(&last.entries), ["0.mp4"]);
+        assert!(last.next_cursor.is_none());
+        // exactly filling the last page leaves no cursor to an empty one
+        assert!(catalog
+            .search(&filter, None, 5)
+            .unwrap()
+            .next_cursor
+            .is_none());
+    }
+
+    #[test]
+    fn malformed_cursor_is_rejected() {
+        assert!(SearchCursor::decode("not a cursor").is_err());
+        assert!(SearchCursor::decode(&URL_SAFE_NO_PAD.encode(b"{}")).is_err());
+    }
+}
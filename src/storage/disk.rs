@@ -0,0 +1,55 @@
+use std::{env::var, ffi::CString, io, mem::MaybeUninit, os::unix::ffi::OsStrExt, path::Path};
+
+const DEFAULT_MIN_FREE_BYTES: u64 = 512 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LowDiskAction {
+    /// Delete the oldest unprotected recordings until enough space is free.
+    EmergencyRetention,
+    /// Refuse to start new recordings until space is freed by hand.
+    Refuse,
+}
+
+/// What the recorder does when the video volume runs low on space.
+///
+/// Read from the environment:
+/// - `MIN_FREE_BYTES`: free space below which the volume counts as low, defaults to 512 MiB.
+/// - `LOW_DISK_ACTION`: `retention` or `refuse`, defaults to `retention`.
+#[derive(Debug, Clone)]
+pub struct DiskGuard {
+    pub min_free_bytes: u64,
+    pub action: LowDiskAction,
+}
+
+impl DiskGuard {
+    pub fn from_env() -> Self {
+        let action = match var("LOW_DISK_ACTION").map(|action| action.to_lowercase()) {
+            Ok(action) if action == "refuse" => LowDiskAction::Refuse,
+            _ => LowDiskAction::EmergencyRetention,
+        };
+        DiskGuard {
+            min_free_bytes: var("MIN_FREE_BYTES")
+                .ok()
+                .and_then(|bytes| bytes.parse().ok())
+                .unwrap_or(DEFAULT_MIN_FREE_BYTES),
+            action,
+        }
+    }
+}
+
+/// Bytes available to unprivileged users on the filesystem holding `path`.
+pub fn free_bytes(path: &Path) -> io::Result<u64> {
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let mut stats = MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: c_path is a valid nul terminated string and stats is only read after statvfs succeeds
+    let result = unsafe { libc::statvfs(c_path.as_ptr(), stats.as_mut_ptr()) };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let stats = unsafe { stats.assume_init() };
+    // both fields are narrower than u64 on 32 bit targets such as older Raspberry Pi OS images
+    #[allow(clippy::unnecessary_cast)]
+    let free = stats.f_bavail as u64 * stats.f_frsize as u64;
+    Ok(free)
+}
@@ -0,0 +1,82 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::{
+    ffi::CStr,
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// How the camera pipeline was configured when a clip was recorded.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CameraSettings {
+    pub camera_args: Vec<String>,
+    pub overlay: Option<String>,
+    pub audio_source: Option<String>,
+    pub audio_codec: Option<String>,
+}
+
+/// Why and how a recording was made, written next to the clip as `<stem>.json`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RecordingMetadata {
+    pub trigger_source: String,
+    /// Unix timestamp, in seconds, of when the recording started.
+    pub started_at: i64,
+    /// Seconds into the clip motion was first seen, negative when it was seen before the camera started.
+    pub motion_start_offset: f64,
+    /// Seconds into the clip motion was last seen, `None` if the recording was cut short, such as by low
+    /// disk space, ffmpeg failing or shutdown, rather than ending with the motion.
+    pub motion_stop_offset: Option<f64>,
+    pub camera_settings: CameraSettings,
+    pub host_name: String,
+    pub software_version: String,
+}
+
+impl RecordingMetadata {
+    pub fn new(
+        trigger_source: String,
+        started_at: i64,
+        motion_start_offset: f64,
+        motion_stop_offset: Option<f64>,
+        camera_settings: CameraSettings,
+    ) -> Self {
+        RecordingMetadata {
+            trigger_source,
+            started_at,
+            motion_start_offset,
+            motion_stop_offset,
+            camera_settings,
+            host_name: host_name(),
+            software_version: env!("CARGO_PKG_VERSION").to_string(),
+        }
+    }
+}
+
+pub fn sidecar_path(video_path: &Path) -> PathBuf {
+    video_path.with_extension("json")
+}
+
+pub fn write_sidecar(video_path: &Path, metadata: &RecordingMetadata) -> Result<()> {
+    fs::write(
+        sidecar_path(video_path),
+        serde_json::to_string_pretty(metadata)?,
+    )?;
+    Ok(())
+}
+
+pub fn read_sidecar(video_path: &Path) -> Option<RecordingMetadata> {
+    let contents = fs::read_to_string(sidecar_path(video_path)).ok()?;
+    serde_json::from_str(&contents).ok()
+}
+
+fn host_name() -> String {
+    let mut buffer = [0u8; 256];
+    // SAFETY: the buffer length passed matches the buffer and gethostname nul terminates on success
+    let result =
+        unsafe { libc::gethostname(buffer.as_mut_ptr() as *mut libc::c_char, buffer.len()) };
+    if result != 0 {
+        return "unknown".to_string();
+    }
+    CStr::from_bytes_until_nul(&buffer)
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or("unknown".to_string())
+}
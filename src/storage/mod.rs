@@ -0,0 +1,234 @@
+use crate::media::summary::{SUMMARY_DATE_FORMAT, SUMMARY_PREFIX};
+use crate::timezone::{day_bounds, local_timezone};
+use chrono::{DateTime, FixedOffset, NaiveDate, NaiveDateTime, Utc};
+use chrono_tz::Tz;
+use std::{
+    env::var,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+pub mod audit;
+pub mod backend;
+pub mod backup;
+pub mod catalog;
+pub mod disk;
+pub mod metadata;
+pub mod offload;
+pub mod retention;
+pub mod stats;
+pub mod store;
+pub mod trash;
+
+/// Directory recordings are saved to, set with `VIDEO_SAVE_PATH`.
+pub fn video_save_path() -> PathBuf {
+    PathBuf::from(var("VIDEO_SAVE_PATH").unwrap_or("/home".to_string()))
+}
+
+/// Whether `name` looks like a recording file directly inside the recordings directory.
+///
+/// Only plain `.mp4` file names made of letters, digits, `_`, `-` and `.` are accepted,
+/// so a name can never point outside the directory.
+pub fn is_recording_name(name: &str) -> bool {
+    name.ends_with(".mp4")
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+}
+
+/// Recordings ffmpeg is still writing.
+static RECORDINGS_IN_PROGRESS: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// Marks the recording at `path` as being written until the returned guard is dropped, so cleanup
+/// leaves it alone whatever the retention policy says.
+pub fn mark_in_progress(path: &Path) -> InProgress {
+    RECORDINGS_IN_PROGRESS
+        .lock()
+        .unwrap()
+        .push(path.to_path_buf());
+    InProgress {
+        path: path.to_path_buf(),
+    }
+}
+
+/// Whether the recording at `path` is still being written.
+pub fn is_in_progress(path: &Path) -> bool {
+    RECORDINGS_IN_PROGRESS
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|recording| recording == path)
+}
+
+/// A recording being written, see [`mark_in_progress`].
+pub struct InProgress {
+    path: PathBuf,
+}
+
+impl Drop for InProgress {
+    fn drop(&mut self) {
+        let mut recordings = RECORDINGS_IN_PROGRESS.lock().unwrap();
+        if let Some(index) = recordings.iter().position(|path| *path == self.path) {
+            recordings.remove(index);
+        }
+    }
+}
+
+/// Format of the local time in recording file names, `motion_2025-06-01_14-03-00_UTC0100.mp4`.
+const RECORDING_TIME_FORMAT: &str = "%Y-%m-%d_%H-%M-%S";
+
+/// File name of a recording started at `start`, its local time followed by the UTC offset so
+/// names stay unique when clocks go back.
+pub fn recording_name(start: SystemTime) -> String {
+    recording_name_in(local_timezone(), start)
+}
+
+fn recording_name_in(timezone: Tz, start: SystemTime) -> String {
+    let start = DateTime::<Utc>::from(start).with_timezone(&timezone);
+    // `+` is left out of positive offsets as it isn't allowed in recording names
+    let offset = start.format("%z").to_string().replace('+', "");
+    format!(
+        "motion_{}_UTC{offset}.mp4",
+        start.format(RECORDING_TIME_FORMAT)
+    )
+}
+
+/// Marks a clip saved from a recording, `<recording stem>_clip_<start ms>-<end ms>[_<n>].mp4`.
+pub const CLIP_MARKER: &str = "_clip_";
+
+/// Start time of a recording taken from its file name.
+///
+/// Used instead of the file's creation time, which many filesystems do not record. Names made
+/// before times were local, `motion_<epoch>.mp4`, are still understood. Clips start their range's
+/// start offset into the recording they were saved from, and summaries at the start of their day.
+pub fn recording_time(path: &Path) -> Option<SystemTime> {
+    let stem = path.file_stem()?.to_str()?;
+    if let Some(date) = stem.strip_prefix(SUMMARY_PREFIX) {
+        let date = NaiveDate::parse_from_str(date, SUMMARY_DATE_FORMAT).ok()?;
+        let (day_start, _) = day_bounds(date);
+        return DateTime::from_timestamp(day_start, 0).map(SystemTime::from);
+    }
+    let stem = stem.strip_prefix("motion_")?;
+    let (stem, clip_offset) = match stem.split_once(CLIP_MARKER) {
+        Some((source, range)) => {
+            let start_millis = range.split('-').next()?.parse().ok()?;
+            (source, Duration::from_millis(start_millis))
+        }
+        None => (stem, Duration::ZERO),
+    };
+    if let Ok(secs) = stem.parse::<u64>() {
+        return Some(UNIX_EPOCH + Duration::from_secs(secs) + clip_offset);
+    }
+    let (local, offset) = stem.rsplit_once("_UTC")?;
+    let local = NaiveDateTime::parse_from_str(local, RECORDING_TIME_FORMAT).ok()?;
+    let start = local
+        .and_local_timezone(parse_utc_offset(offset)?)
+        .single()?;
+    Some(SystemTime::from(start.to_utc()) + clip_offset)
+}
+
+/// Parses an offset written as `[-]HHMM`.
+fn parse_utc_offset(offset: &str) -> Option<FixedOffset> {
+    let (sign, digits) = match offset.strip_prefix('-') {
+        Some(digits) => (-1, digits),
+        None => (1, offset),
+    };
+    if digits.len() != 4 || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let hours: i32 = digits[..2].parse().ok()?;
+    let minutes: i32 = digits[2..].parse().ok()?;
+    FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60))
+}
+
+/// Deletes a recording along with the files that describe it, such as its sidecar metadata.
+pub fn remove_recording_files(path: &Path) -> io::Result<()> {
+    fs::remove_file(path)?;
+    let sidecar = metadata::sidecar_path(path);
+    if sidecar.exists() {
+        fs::remove_file(sidecar)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recording_is_in_progress_until_guard_is_dropped() {
+        let path = Path::new("/recordings/motion_2025-06-01_14-03-00_UTC0100.mp4");
+        let in_progress = mark_in_progress(path);
+        assert!(is_in_progress(path));
+        assert!(!is_in_progress(Path::new("/recordings/other.mp4")));
+        drop(in_progress);
+        assert!(!is_in_progress(path));
+    }
+
+    fn unix_time(path: &str) -> Option<u64> {
+        recording_time(Path::new(path)).map(|time| {
+            time.duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs()
+        })
+    }
+
+    #[test]
+    fn recording_time_reads_clip_names() {
+        // 2025-06-01 13:03:00 UTC
+        let start = 1_748_782_980;
+        assert_eq!(
+            unix_time("motion_2025-06-01_14-03-00_UTC0100.mp4"),
+            Some(start)
+        );
+        assert_eq!(
+            unix_time("motion_2025-06-01_14-03-00_UTC0100_clip_12500-30000.mp4"),
+            Some(start + 12)
+        );
+        assert_eq!(
+            unix_time("motion_2025-06-01_14-03-00_UTC0100_clip_12500-30000_2.mp4"),
+            Some(start + 12)
+        );
+        assert_eq!(
+            unix_time("motion_1748782980_clip_5000-9000.mp4"),
+            Some(start + 5)
+        );
+        assert_eq!(unix_time("motion_1748782980_clip_.mp4"), None);
+        assert_eq!(unix_time("other_clip_5000-9000.mp4"), None);
+    }
+
+    #[test]
+    fn recording_names_round_trip_across_fall_back() {
+        // 01:30 happens twice in London on 2025-10-26, first in summer time then in winter time
+        let first = UNIX_EPOCH + Duration::from_secs(1_761_438_600);
+        let second = first + Duration::from_secs(60 * 60);
+        let first_name = recording_name_in(chrono_tz::Europe::London, first);
+        let second_name = recording_name_in(chrono_tz::Europe::London, second);
+        assert_eq!(first_name, "motion_2025-10-26_01-30-00_UTC0100.mp4");
+        assert_eq!(second_name, "motion_2025-10-26_01-30-00_UTC0000.mp4");
+        assert_eq!(recording_time(Path::new(&first_name)), Some(first));
+        assert_eq!(recording_time(Path::new(&second_name)), Some(second));
+    }
+
+    #[test]
+    fn recording_names_round_trip_with_negative_offsets() {
+        let start = UNIX_EPOCH + Duration::from_secs(1_748_782_980);
+        let name = recording_name_in(chrono_tz::America::New_York, start);
+        assert_eq!(name, "motion_2025-06-01_09-03-00_UTC-0400.mp4");
+        assert_eq!(recording_time(Path::new(&name)), Some(start));
+    }
+
+    #[test]
+    fn recording_time_reads_summary_names() {
+        let date = NaiveDate::from_ymd_opt(2025, 6, 1).unwrap();
+        assert_eq!(
+            unix_time("summary_2025-06-01.mp4"),
+            Some(day_bounds(date).0 as u64)
+        );
+        assert_eq!(unix_time("summary_2025-06-31.mp4"), None);
+        assert_eq!(unix_time("summary_today.mp4"), None);
+    }
+}
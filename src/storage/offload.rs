@@ -0,0 +1,300 @@
+use super::{
+    audit,
+    backend::StorageBackend,
+    catalog::Catalog,
+    store::{RecordingStore, StoreError},
+};
+use crate::events::{Event, EventKind};
+use anyhow::Result;
+use chrono::Utc;
+use std::{env::var, path::Path, sync::Arc, time::Duration};
+use tokio::{
+    fs,
+    sync::broadcast::{self, error::RecvError},
+    time::interval,
+};
+use tracing::{error, info, warn};
+
+/// How often recordings whose upload failed are tried again.
+const RETRY_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Uploads finished recordings to the store's remote backend as they are saved.
+///
+/// Recordings left over from before a restart, or whose upload failed, are picked up on start and
+/// then every ten minutes. Set `OFFLOAD_DELETE_LOCAL` to `true` to delete the local copy once a
+/// recording is uploaded, it is then streamed from the backend instead.
+pub async fn offload_task(
+    store: Arc<RecordingStore>,
+    catalog: Arc<Catalog>,
+    mut events: broadcast::Receiver<Event>,
+) {
+    let Some(backend) = store.remote().cloned() else {
+        return;
+    };
+    let delete_local = var("OFFLOAD_DELETE_LOCAL").is_ok_and(|delete| delete == "true");
+    let mut retry = interval(RETRY_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = retry.tick() => {}
+            event = events.recv() => match event {
+                Ok(Event { kind: EventKind::RecordingStopped { .. }, .. }) => {}
+                Ok(_) => continue,
+                // missed events may have included finished recordings, so check the catalog anyway
+                Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => return,
+            },
+        }
+        if let Err(err) = offload_pending(&backend, &store, &catalog, delete_local).await {
+            error!("Error offloading recordings, error: {err}");
+        }
+    }
+}
+
+/// Uploads every catalogued recording not yet on the backend, returning how many were uploaded.
+async fn offload_pending(
+    backend: &Arc<dyn StorageBackend>,
+    store: &RecordingStore,
+    catalog: &Catalog,
+    delete_local: bool,
+) -> Result<usize> {
+    let mut offloaded = 0;
+    for entry in catalog.pending_offload()? {
+        let path = match store.resolve(&entry.file_name) {
+            Ok(path) => path,
+            Err(StoreError::NotFound) => continue,
+            Err(err) => {
+                warn!("Not offloading {}, error: {err}", entry.file_name);
+                continue;
+            }
+        };
+        if let Err(err) = backend.upload(&entry.file_name, &path).await {
+            error!(
+                "Error uploading {} to {}, error: {err}",
+                entry.file_name,
+                backend.name()
+            );
+            continue;
+        }
+        catalog.set_offloaded(&entry.file_name, backend.name(), Utc::now().timestamp())?;
+        audit::record(
+            "offload",
+            "upload",
+            &path,
+            &format!("uploaded to {}", backend.name()),
+        );
+
+        // a failure here leaves the recording on disk, it is still offloaded so the pass carries on
+        if delete_local {
+            match remove_local_copy(backend.as_ref(), &entry.file_name, &path).await {
+                Ok(true) => audit::record(
+                    "offload",
+                    "delete",
+                    &path,
+                    &format!("local copy removed, kept in {}", backend.name()),
+                ),
+                Ok(false) => {}
+                Err(err) => error!(
+                    "Error removing local copy of {}, error: {err}",
+                    entry.file_name
+                ),
+            }
+        }
+        offloaded += 1;
+    }
+    if offloaded > 0 {
+        info!("Offloaded {offloaded} recordings to {}", backend.name());
+    }
+    Ok(offloaded)
+}
+
+/// Deletes the local copy of an uploaded recording, returning whether it was removed.
+///
+/// The copy is kept unless the backend holds a recording of the same size, so an upload that was
+/// cut short or overwritten is never the only copy left.
+async fn remove_local_copy(
+    backend: &dyn StorageBackend,
+    file_name: &str,
+    path: &Path,
+) -> Result<bool> {
+    let local_size = fs::metadata(path).await?.len();
+    match backend.info(file_name).await? {
+        Some(info) if info.size == local_size => {}
+        Some(info) => {
+            warn!(
+                "Keeping local copy of {file_name}, {} holds {} of its {local_size} bytes",
+                backend.name(),
+                info.size
+            );
+            return Ok(false);
+        }
+        None => {
+            warn!(
+                "Keeping local copy of {file_name}, it is missing from {}",
+                backend.name()
+            );
+            return Ok(false);
+        }
+    }
+    fs::remove_file(path).await?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{
+        backend::{LocalBackend, S3Backend},
+        catalog::CatalogEntry,
+    };
+    use futures_util::TryStreamExt;
+    use tempfile::TempDir;
+
+    const FILE_NAME: &str = "motion_2025-06-01_14-03-00_UTC0100.mp4";
+
+    fn catalog_entry(file_name: &str, size_bytes: u64) -> CatalogEntry {
+        CatalogEntry {
+            file_name: file_name.to_string(),
+            recorded: 1_748_783_000,
+            duration: 10.0,
+            width: 1920,
+            height: 1080,
+            size_bytes,
+            codec: "h264".to_string(),
+            trigger: None,
+            metadata: None,
+            tags: Vec::new(),
+            starred: false,
+            notes: None,
+            protected: false,
+            trashed_at: None,
+            offloaded_to: None,
+            offloaded_at: None,
+        }
+    }
+
+    /// A recordings directory holding one catalogued recording, and an empty backend directory.
+    fn setup() -> (TempDir, RecordingStore, Catalog, Arc<dyn StorageBackend>) {
+        let dir = TempDir::new().unwrap();
+        let recordings = dir.path().join("recordings");
+        std::fs::create_dir(&recordings).unwrap();
+        std::fs::write(recordings.join(FILE_NAME), b"recording bytes").unwrap();
+        let catalog = Catalog::open(&dir.path().join("catalog.db")).unwrap();
+        catalog.upsert(&catalog_entry(FILE_NAME, 15)).unwrap();
+        let backend: Arc<dyn StorageBackend> =
+            Arc::new(LocalBackend::new(dir.path().join("remote")));
+        (dir, RecordingStore::new(recordings), catalog, backend)
+    }
+
+    #[tokio::test]
+    async fn offloads_and_removes_local_copy() {
+        let (dir, store, catalog, backend) = setup();
+        assert_eq!(
+            offload_pending(&backend, &store, &catalog, true)
+                .await
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            std::fs::read(dir.path().join("remote").join(FILE_NAME)).unwrap(),
+            b"recording bytes"
+        );
+        assert!(!store.root().join(FILE_NAME).exists());
+        let entry = catalog.get(FILE_NAME).unwrap().unwrap();
+        assert_eq!(entry.offloaded_to.as_deref(), Some("local"));
+        assert!(catalog.pending_offload().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn keeps_local_copy_unless_asked_to_remove_it() {
+        let (_dir, store, catalog, backend) = setup();
+        offload_pending(&backend, &store, &catalog, false)
+            .await
+            .unwrap();
+        assert!(store.root().join(FILE_NAME).exists());
+        assert!(catalog.pending_offload().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn keeps_local_copy_when_backend_size_differs() {
+        let (dir, store, _catalog, backend) = setup();
+        let path = store.root().join(FILE_NAME);
+        std::fs::create_dir(dir.path().join("remote")).unwrap();
+        std::fs::write(dir.path().join("remote").join(FILE_NAME), b"recording").unwrap();
+        assert!(!remove_local_copy(backend.as_ref(), FILE_NAME, &path)
+            .await
+            .unwrap());
+        assert!(path.exists());
+
+        std::fs::remove_file(dir.path().join("remote").join(FILE_NAME)).unwrap();
+        assert!(!remove_local_copy(backend.as_ref(), FILE_NAME, &path)
+            .await
+            .unwrap());
+        assert!(path.exists());
+    }
+
+    #[tokio::test]
+    async fn skips_recordings_missing_locally() {
+        let (_dir, store, catalog, backend) = setup();
+        catalog
+            .upsert(&catalog_entry("motion_1748783000.mp4", 15))
+            .unwrap();
+        assert_eq!(
+            offload_pending(&backend, &store, &catalog, true)
+                .await
+                .unwrap(),
+            1
+        );
+        assert_eq!(catalog.pending_offload().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs an S3 server such as MinIO, set S3_ENDPOINT, S3_BUCKET, S3_ACCESS_KEY_ID, S3_SECRET_ACCESS_KEY and S3_ALLOW_HTTP"]
+    async fn offloads_to_s3_and_streams_back() {
+        let dir = TempDir::new().unwrap();
+        let recordings = dir.path().join("recordings");
+        std::fs::create_dir(&recordings).unwrap();
+        // named after the current time so runs don't collide in a shared bucket
+        let file_name = format!("motion_{}.mp4", Utc::now().timestamp_millis());
+        // just over one upload part, so the upload takes more than one
+        let contents: Vec<u8> = (0..9 * 1024 * 1024 + 123)
+            .map(|index| (index % 251) as u8)
+            .collect();
+        std::fs::write(recordings.join(&file_name), &contents).unwrap();
+        let catalog = Catalog::open(&dir.path().join("catalog.db")).unwrap();
+        catalog
+            .upsert(&catalog_entry(&file_name, contents.len() as u64))
+            .unwrap();
+        let backend: Arc<dyn StorageBackend> = Arc::new(S3Backend::from_env().unwrap());
+        let store = RecordingStore::new(recordings);
+
+        assert_eq!(
+            offload_pending(&backend, &store, &catalog, true)
+                .await
+                .unwrap(),
+            1
+        );
+        assert!(!store.root().join(&file_name).exists());
+        let entry = catalog.get(&file_name).unwrap().unwrap();
+        assert_eq!(entry.offloaded_to.as_deref(), Some("s3"));
+        let info = backend.info(&file_name).await.unwrap().unwrap();
+        assert_eq!(info.size, contents.len() as u64);
+
+        let read = |range: std::ops::Range<u64>| {
+            let backend = backend.clone();
+            let file_name = file_name.clone();
+            async move {
+                let chunks: Vec<_> = backend
+                    .read_range(&file_name, range)
+                    .await
+                    .unwrap()
+                    .try_collect()
+                    .await
+                    .unwrap();
+                chunks.concat()
+            }
+        };
+        assert_eq!(read(0..contents.len() as u64).await, contents);
+        assert_eq!(read(1000..2000).await, &contents[1000..2000]);
+        assert!(backend.info("motion_0.mp4").await.unwrap().is_none());
+    }
+}
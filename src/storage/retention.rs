@@ -0,0 +1,379 @@
+use super::{
+    audit, catalog::Catalog, disk::free_bytes, is_in_progress, recording_time,
+    remove_recording_files, video_save_path,
+};
+use glob::glob;
+use std::{
+    cmp::Reverse,
+    env::var,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tokio::{task::spawn_blocking, time::interval};
+use tracing::{error, info};
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Rules deciding which recordings are removed to free up space.
+///
+/// Read from the environment:
+/// - `RETENTION_MAX_BYTES`: maximum total size of all recordings.
+/// - `RETENTION_MAX_AGE_DAYS`: recordings older than this are removed.
+/// - `RETENTION_KEEP_NEWEST`: the newest N finished recordings are always kept, defaults to 1. The
+///   recording in progress is never removed, even when this is 0.
+/// - `RETENTION_INTERVAL_SECS`: how often the background task runs, defaults to an hour.
+#[derive(Debug, Clone)]
+pub struct RetentionPolicy {
+    pub max_total_bytes: Option<u64>,
+    pub max_age: Option<Duration>,
+    pub keep_newest: usize,
+}
+
+impl RetentionPolicy {
+    pub fn from_env() -> Self {
+        RetentionPolicy {
+            max_total_bytes: var("RETENTION_MAX_BYTES")
+                .ok()
+                .and_then(|bytes| bytes.parse().ok()),
+            max_age: var("RETENTION_MAX_AGE_DAYS")
+                .ok()
+                .and_then(|days| days.parse::<u64>().ok())
+                .map(|days| Duration::from_secs(days * SECONDS_PER_DAY)),
+            keep_newest: var("RETENTION_KEEP_NEWEST")
+                .ok()
+                .and_then(|count| count.parse().ok())
+                .unwrap_or(1),
+        }
+    }
+
+    pub fn is_unbounded(&self) -> bool {
+        self.max_total_bytes.is_none() && self.max_age.is_none()
+    }
+}
+
+struct RecordingFile {
+    path: PathBuf,
+    file_name: String,
+    size: u64,
+    recorded: SystemTime,
+}
+
+/// Whether cleanup must leave a recording alone, erring on the side of keeping it if the catalog can't be read.
+fn is_protected(catalog: &Catalog, recording: &RecordingFile) -> bool {
+    catalog
+        .is_protected(&recording.file_name)
+        .unwrap_or_else(|err| {
+            error!(
+                "Unable to check if {} is protected, keeping it, error: {err}",
+                recording.file_name
+            );
+            true
+        })
+}
+
+/// Deletes a recording on behalf of the retention policy, keeping the catalog and audit trail in step.
+fn delete_recording(catalog: &Catalog, recording: &RecordingFile, reason: &str) -> io::Result<()> {
+    remove_recording_files(&recording.path)?;
+    if let Err(err) = catalog.remove(&recording.file_name) {
+        error!(
+            "Unable to remove {} from catalog, error: {err}",
+            recording.file_name
+        );
+    }
+    audit::record("retention", "delete", &recording.path, reason);
+    Ok(())
+}
+
+/// Finished recordings in `dir`, newest first. The one being written is left out so it is never removed.
+fn list_recordings(dir: &Path) -> io::Result<Vec<RecordingFile>> {
+    let pattern = format!("{}/*.mp4", dir.display());
+    let paths = glob(&pattern).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+
+    let mut recordings = Vec::new();
+    for path in paths.filter_map(Result::ok) {
+        if is_in_progress(&path) {
+            continue;
+        }
+        let metadata = fs::metadata(&path)?;
+        let recorded = recording_time(&path).unwrap_or(metadata.modified()?);
+        let Some(file_name) = path.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        recordings.push(RecordingFile {
+            file_name: file_name.to_string(),
+            path,
+            size: metadata.len(),
+            recorded,
+        });
+    }
+    // newest first so the recordings that must be kept are at the front
+    recordings.sort_by_key(|recording| Reverse(recording.recorded));
+    Ok(recordings)
+}
+
+/// Deletes recordings breaking the policy, oldest first, and returns the paths removed.
+///
+/// A recording that can't be deleted is logged and skipped so it doesn't hold up the rest.
+pub fn enforce_retention(
+    policy: &RetentionPolicy,
+    dir: &Path,
+    catalog: &Catalog,
+) -> io::Result<Vec<PathBuf>> {
+    if policy.is_unbounded() {
+        return Ok(Vec::new());
+    }
+    let recordings = list_recordings(dir)?;
+    let mut total_bytes: u64 = recordings.iter().map(|recording| recording.size).sum();
+    let now = SystemTime::now();
+
+    let mut deleted = Vec::new();
+    for recording in recordings.iter().skip(policy.keep_newest).rev() {
+        let age = now.duration_since(recording.recorded).unwrap_or_default();
+        let too_old = policy.max_age.is_some_and(|max_age| age > max_age);
+        let over_quota = policy
+            .max_total_bytes
+            .is_some_and(|max_bytes| total_bytes > max_bytes);
+        if !too_old && !over_quota {
+            continue;
+        }
+        if is_protected(catalog, recording) {
+            continue;
+        }
+
+        let reason = if too_old {
+            format!("older than {} days", age.as_secs() / SECONDS_PER_DAY)
+        } else {
+            format!(
+                "total size over {} bytes",
+                policy.max_total_bytes.unwrap_or(0)
+            )
+        };
+        if let Err(err) = delete_recording(catalog, recording, &reason) {
+            error!(
+                "Unable to delete {}, error: {err}",
+                recording.path.display()
+            );
+            continue;
+        }
+        total_bytes -= recording.size;
+        deleted.push(recording.path.clone());
+    }
+    Ok(deleted)
+}
+
+/// Deletes the oldest unprotected recordings until at least `min_free_bytes` are free on the volume.
+///
+/// The newest recordings kept by the policy are never removed, even if space is still short afterwards.
+/// Recordings are deleted rather than trashed: the trash defaults to a directory on the same volume,
+/// so moving recordings there frees no space. Each deletion is still written to the audit log.
+pub fn free_space(
+    policy: &RetentionPolicy,
+    dir: &Path,
+    min_free_bytes: u64,
+    catalog: &Catalog,
+) -> io::Result<Vec<PathBuf>> {
+    let recordings = list_recordings(dir)?;
+    let mut deleted = Vec::new();
+    for recording in recordings.iter().skip(policy.keep_newest).rev() {
+        if free_bytes(dir)? >= min_free_bytes {
+            break;
+        }
+        if is_protected(catalog, recording) {
+            continue;
+        }
+        let reason = format!("emergency cleanup, less than {min_free_bytes} bytes free");
+        if let Err(err) = delete_recording(catalog, recording, &reason) {
+            error!(
+                "Unable to delete {}, error: {err}",
+                recording.path.display()
+            );
+            continue;
+        }
+        deleted.push(recording.path.clone());
+    }
+    Ok(deleted)
+}
+
+/// Runs the retention policy for the recordings directory, logging rather than returning errors.
+pub fn run_retention(catalog: &Catalog) -> Vec<PathBuf> {
+    let policy = RetentionPolicy::from_env();
+    match enforce_retention(&policy, &video_save_path(), catalog) {
+        Ok(deleted) => {
+            if !deleted.is_empty() {
+                info!("Retention removed {} recordings", deleted.len());
+            }
+            deleted
+        }
+        Err(err) => {
+            error!("Error applying retention policy, error: {err}");
+            Vec::new()
+        }
+    }
+}
+
+/// Periodically applies the retention policy in the background.
+pub async fn retention_task(catalog: Arc<Catalog>) {
+    let period = var("RETENTION_INTERVAL_SECS")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .filter(|secs: &u64| *secs > 0)
+        .unwrap_or(60 * 60);
+    let mut ticker = interval(Duration::from_secs(period));
+    loop {
+        ticker.tick().await;
+        let catalog = catalog.clone();
+        if let Err(err) = spawn_blocking(move || run_retention(&catalog)).await {
+            error!("Retention task panicked, error: {err}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::catalog::{Annotation, CatalogEntry};
+    use std::time::UNIX_EPOCH;
+    use tempfile::TempDir;
+
+    /// A recordings directory and its catalog.
+    fn setup() -> (TempDir, Catalog) {
+        let dir = TempDir::new().unwrap();
+        let catalog = Catalog::open(&dir.path().join("catalog.db")).unwrap();
+        (dir, catalog)
+    }
+
+    /// Adds a recording of `size` bytes started `days_ago`, returning its file name.
+    fn add_recording(dir: &TempDir, catalog: &Catalog, days_ago: u64, size: usize) -> String {
+        let recorded = SystemTime::now() - Duration::from_secs(days_ago * SECONDS_PER_DAY);
+        let secs = recorded.duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let file_name = format!("motion_{secs}.mp4");
+        fs::write(dir.path().join(&file_name), vec![0; size]).unwrap();
+        catalog
+            .upsert(&CatalogEntry {
+                file_name: file_name.clone(),
+                recorded: secs as i64,
+                duration: 10.0,
+                width: 1920,
+                height: 1080,
+                size_bytes: size as u64,
+                codec: "h264".to_string(),
+                trigger: None,
+                metadata: None,
+                tags: Vec::new(),
+                starred: false,
+                notes: None,
+                protected: false,
+                trashed_at: None,
+                offloaded_to: None,
+                offloaded_at: None,
+            })
+            .unwrap();
+        file_name
+    }
+
+    fn protect(catalog: &Catalog, file_name: &str) {
+        let annotation = Annotation {
+            tags: None,
+            starred: None,
+            notes: None,
+            protected: Some(true),
+        };
+        catalog.annotate(file_name, &annotation).unwrap();
+    }
+
+    fn policy(
+        max_total_bytes: Option<u64>,
+        max_age_days: Option<u64>,
+        keep_newest: usize,
+    ) -> RetentionPolicy {
+        RetentionPolicy {
+            max_total_bytes,
+            max_age: max_age_days.map(|days| Duration::from_secs(days * SECONDS_PER_DAY)),
+            keep_newest,
+        }
+    }
+
+    fn remaining(dir: &TempDir, file_names: &[String]) -> Vec<String> {
+        file_names
+            .iter()
+            .filter(|file_name| dir.path().join(file_name).exists())
+            .cloned()
+            .collect()
+    }
+
+    #[test]
+    fn oldest_are_deleted_until_under_max_bytes() {
+        let (dir, catalog) = setup();
+        let names: Vec<String> = (1..=4)
+            .rev()
+            .map(|days_ago| add_recording(&dir, &catalog, days_ago, 10))
+            .collect();
+
+        let deleted = enforce_retention(&policy(Some(25), None, 0), dir.path(), &catalog).unwrap();
+        assert_eq!(deleted.len(), 2);
+        assert_eq!(remaining(&dir, &names), &names[2..]);
+        assert!(catalog.get(&names[0]).unwrap().is_none());
+        assert!(catalog.get(&names[2]).unwrap().is_some());
+    }
+
+    #[test]
+    fn recordings_past_max_age_are_deleted() {
+        let (dir, catalog) = setup();
+        let names: Vec<String> = [10, 5, 1]
+            .into_iter()
+            .map(|days_ago| add_recording(&dir, &catalog, days_ago, 10))
+            .collect();
+
+        enforce_retention(&policy(None, Some(3), 0), dir.path(), &catalog).unwrap();
+        assert_eq!(remaining(&dir, &names), &names[2..]);
+    }
+
+    #[test]
+    fn newest_are_kept_whatever_the_limits() {
+        let (dir, catalog) = setup();
+        let names: Vec<String> = [10, 9, 8, 7]
+            .into_iter()
+            .map(|days_ago| add_recording(&dir, &catalog, days_ago, 10))
+            .collect();
+
+        enforce_retention(&policy(Some(0), Some(1), 2), dir.path(), &catalog).unwrap();
+        assert_eq!(remaining(&dir, &names), &names[2..]);
+    }
+
+    #[test]
+    fn protected_recordings_are_skipped() {
+        let (dir, catalog) = setup();
+        let names: Vec<String> = [3, 2, 1]
+            .into_iter()
+            .map(|days_ago| add_recording(&dir, &catalog, days_ago, 10))
+            .collect();
+        protect(&catalog, &names[0]);
+
+        // the protected oldest recording stays, so the next oldest goes to get under the limit
+        enforce_retention(&policy(Some(25), None, 0), dir.path(), &catalog).unwrap();
+        assert_eq!(
+            remaining(&dir, &names),
+            [names[0].clone(), names[2].clone()]
+        );
+    }
+
+    #[test]
+    fn undeletable_recording_does_not_stop_the_pass() {
+        let (dir, catalog) = setup();
+        let names: Vec<String> = [3, 2, 1]
+            .into_iter()
+            .map(|days_ago| add_recording(&dir, &catalog, days_ago, 10))
+            .collect();
+        // a directory with a recording's name can't be removed as a file
+        let stuck = add_recording(&dir, &catalog, 4, 0);
+        fs::remove_file(dir.path().join(&stuck)).unwrap();
+        fs::create_dir(dir.path().join(&stuck)).unwrap();
+
+        let deleted = enforce_retention(&policy(None, Some(0), 1), dir.path(), &catalog).unwrap();
+        assert_eq!(deleted.len(), 2);
+        assert_eq!(remaining(&dir, &names), &names[2..]);
+        assert!(dir.path().join(&stuck).is_dir());
+    }
+}
@@ -0,0 +1,251 @@
+use super::{catalog::Catalog, CLIP_MARKER};
+use crate::events::{Event, EventKind};
+use crate::media::summary::SUMMARY_PREFIX;
+use crate::timezone::{day_bounds, local_time};
+use anyhow::Result;
+use chrono::{Datelike, NaiveDate, Timelike, Utc};
+use serde::Serialize;
+use std::{env::var, sync::Arc, time::Duration};
+use tokio::{
+    sync::broadcast::{self, error::RecvError},
+    task::spawn_blocking,
+    time::interval,
+};
+use tracing::{error, info, warn};
+
+/// Keeps a little over a year of motion so a full year can always be charted.
+const DEFAULT_EVENT_RETENTION_DAYS: i64 = 400;
+const PRUNE_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Activity in one slot of a chart, such as an hour of the day.
+#[derive(Serialize, Clone, Copy, Debug, Default)]
+pub struct ActivityCount {
+    pub events: u64,
+    pub recordings: u64,
+}
+
+/// Activity on one local calendar day, a cell of a calendar heatmap.
+#[derive(Serialize, Clone, Debug)]
+pub struct DayActivity {
+    pub date: NaiveDate,
+    pub events: u64,
+    pub recordings: u64,
+    pub recorded_minutes: f64,
+    /// Size of the recordings started that day which are still kept.
+    pub size_bytes: u64,
+}
+
+/// Motion events and recordings over a range of local days, counted in local time.
+///
+/// Events are motion detections, recordings are those still in the catalog, so recordings removed
+/// by retention or deleted no longer count while the motion that caused them does.
+#[derive(Serialize, Clone, Debug)]
+pub struct ActivityStats {
+    pub from: NaiveDate,
+    pub to: NaiveDate,
+    /// Indexed by hour of the day, 0 to 23.
+    pub hourly: Vec<ActivityCount>,
+    /// Indexed by day of the week, Monday first.
+    pub weekdays: Vec<ActivityCount>,
+    /// Every day from `from` to `to`, including those without activity.
+    pub days: Vec<DayActivity>,
+    pub total_events: u64,
+    pub total_recordings: u64,
+    pub total_recorded_minutes: f64,
+    pub total_size_bytes: u64,
+}
+
+impl ActivityStats {
+    /// Gathers activity from the start of `from` to the end of `to`, both local dates.
+    pub fn collect(catalog: &Catalog, from: NaiveDate, to: NaiveDate) -> Result<Self> {
+        let (start, _) = day_bounds(from);
+        let (_, end) = day_bounds(to);
+        let mut stats = ActivityStats {
+            from,
+            to,
+            hourly: vec![ActivityCount::default(); 24],
+            weekdays: vec![ActivityCount::default(); 7],
+            days: from
+                .iter_days()
+                .take_while(|date| *date <= to)
+                .map(|date| DayActivity {
+                    date,
+                    events: 0,
+                    recordings: 0,
+                    recorded_minutes: 0.0,
+                    size_bytes: 0,
+                })
+                .collect(),
+            total_events: 0,
+            total_recordings: 0,
+            total_recorded_minutes: 0.0,
+            total_size_bytes: 0,
+        };
+
+        for timestamp in catalog.event_times(EventKind::MotionDetected.name(), start, end)? {
+            let (hour, weekday, day) = stats.slots(timestamp);
+            if let Some(day) = day {
+                day.events += 1;
+            }
+            stats.hourly[hour].events += 1;
+            stats.weekdays[weekday].events += 1;
+            stats.total_events += 1;
+        }
+        let recordings = catalog
+            .recorded_between(start, end)?
+            .into_iter()
+            // summaries and saved clips repeat recordings already counted
+            .filter(|entry| {
+                !entry.file_name.starts_with(SUMMARY_PREFIX)
+                    && !entry.file_name.contains(CLIP_MARKER)
+            });
+        for entry in recordings {
+            let minutes = entry.duration / 60.0;
+            let (hour, weekday, day) = stats.slots(entry.recorded);
+            if let Some(day) = day {
+                day.recordings += 1;
+                day.recorded_minutes += minutes;
+                day.size_bytes += entry.size_bytes;
+            }
+            stats.hourly[hour].recordings += 1;
+            stats.weekdays[weekday].recordings += 1;
+            stats.total_recordings += 1;
+            stats.total_recorded_minutes += minutes;
+            stats.total_size_bytes += entry.size_bytes;
+        }
+        Ok(stats)
+    }
+
+    /// Hour of the day, day of the week and calendar day a unix timestamp falls on locally.
+    fn slots(&mut self, timestamp: i64) -> (usize, usize, Option<&mut DayActivity>) {
+        let time = local_time(timestamp);
+        let index = (time.date_naive() - self.from).num_days();
+        let day = usize::try_from(index)
+            .ok()
+            .and_then(|index| self.days.get_mut(index));
+        (
+            time.hour() as usize,
+            time.weekday().num_days_from_monday() as usize,
+            day,
+        )
+    }
+}
+
+/// Keeps the motion detected by the motion detector in the catalog so activity can be charted.
+///
+/// Only motion is charted, so other events aren't stored. Motion older than `EVENT_RETENTION_DAYS`,
+/// 400 by default, is deleted once a day.
+pub async fn event_log_task(catalog: Arc<Catalog>, mut events: broadcast::Receiver<Event>) {
+    let retention_days = var("EVENT_RETENTION_DAYS")
+        .ok()
+        .and_then(|days| days.parse().ok())
+        .filter(|days: &i64| *days > 0)
+        .unwrap_or(DEFAULT_EVENT_RETENTION_DAYS);
+    let mut prune = interval(PRUNE_INTERVAL);
+    loop {
+        let timestamp = tokio::select! {
+            _ = prune.tick() => {
+                prune_events(&catalog, retention_days).await;
+                continue;
+            }
+            event = events.recv() => match event {
+                Ok(Event { kind: EventKind::MotionDetected, timestamp }) => timestamp,
+                Ok(_) => continue,
+                Err(RecvError::Lagged(missed)) => {
+                    warn!("Event log fell behind, {missed} events were not recorded");
+                    continue;
+                }
+                Err(RecvError::Closed) => return,
+            },
+        };
+        let catalog = catalog.clone();
+        let recorded = spawn_blocking(move || {
+            catalog.record_event(timestamp.timestamp(), EventKind::MotionDetected.name())
+        })
+        .await;
+        match recorded {
+            Ok(Ok(_)) => {}
+            Ok(Err(err)) => error!("Error recording event, error: {err}"),
+            Err(err) => error!("Event log task failed, error: {err}"),
+        }
+    }
+}
+
+async fn prune_events(catalog: &Arc<Catalog>, retention_days: i64) {
+    let before = Utc::now().timestamp() - retention_days * 24 * 60 * 60;
+    let catalog = catalog.clone();
+    match spawn_blocking(move || catalog.prune_events(before)).await {
+        Ok(Ok(0)) => {}
+        Ok(Ok(removed)) => info!("Removed {removed} events older than {retention_days} days"),
+        Ok(Err(err)) => error!("Error removing old events, error: {err}"),
+        Err(err) => error!("Event prune task failed, error: {err}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::catalog::CatalogEntry;
+    use tempfile::TempDir;
+
+    fn catalog_entry(file_name: &str, recorded: i64) -> CatalogEntry {
+        CatalogEntry {
+            file_name: file_name.to_string(),
+            recorded,
+            duration: 120.0,
+            width: 1920,
+            height: 1080,
+            size_bytes: 1000,
+            codec: "h264".to_string(),
+            trigger: None,
+            metadata: None,
+            tags: Vec::new(),
+            starred: false,
+            notes: None,
+            protected: false,
+            trashed_at: None,
+            offloaded_to: None,
+            offloaded_at: None,
+        }
+    }
+
+    #[test]
+    fn counts_motion_and_recordings_but_not_summaries_or_clips() {
+        let dir = TempDir::new().unwrap();
+        let catalog = Catalog::open(&dir.path().join("catalog.db")).unwrap();
+        let date = NaiveDate::from_ymd_opt(2025, 6, 2).unwrap();
+        let (day_start, _) = day_bounds(date);
+        let two_pm = day_start + 14 * 60 * 60;
+        for kind in ["MotionDetected", "MotionDetected", "RecordingStarted"] {
+            catalog.record_event(two_pm, kind).unwrap();
+        }
+        for file_name in [
+            "motion_a.mp4",
+            "motion_a_clip_0-5000.mp4",
+            "summary_2025-06-02.mp4",
+        ] {
+            catalog.upsert(&catalog_entry(file_name, two_pm)).unwrap();
+        }
+
+        let stats = ActivityStats::collect(&catalog, date, date).unwrap();
+        assert_eq!(stats.total_events, 2);
+        assert_eq!(stats.total_recordings, 1);
+        assert_eq!(stats.total_recorded_minutes, 2.0);
+        assert_eq!(stats.hourly[local_time(two_pm).hour() as usize].events, 2);
+        assert_eq!(stats.days.len(), 1);
+        assert_eq!(stats.days[0].recordings, 1);
+    }
+
+    #[test]
+    fn prunes_old_events() {
+        let dir = TempDir::new().unwrap();
+        let catalog = Catalog::open(&dir.path().join("catalog.db")).unwrap();
+        catalog.record_event(100, "MotionDetected").unwrap();
+        catalog.record_event(200, "MotionDetected").unwrap();
+        assert_eq!(catalog.prune_events(150).unwrap(), 1);
+        assert_eq!(
+            catalog.event_times("MotionDetected", 0, 1000).unwrap(),
+            vec![200]
+        );
+    }
+}
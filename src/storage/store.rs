@@ -0,0 +1,229 @@
+use super::{backend::StorageBackend, is_recording_name, video_save_path};
+use std::{
+    fmt::Display,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+#[derive(Debug)]
+pub enum StoreError {
+    /// The name isn't a plain recording file name.
+    InvalidName,
+    NotFound,
+    /// The name refers to something other than a regular file inside the root, such as a symlink.
+    NotARecording,
+    Io(io::Error),
+}
+
+impl Display for StoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StoreError::InvalidName => write!(f, "invalid recording name"),
+            StoreError::NotFound => write!(f, "recording not found"),
+            StoreError::NotARecording => write!(f, "not a recording"),
+            StoreError::Io(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+/// The recordings directory, the only way request supplied recording names are turned into paths.
+///
+/// Names are validated with [`is_recording_name`] and must resolve to a regular file directly
+/// inside the root, so neither `..` nor a symlink can reach files elsewhere.
+///
+/// Recordings offloaded to a remote [`StorageBackend`] and removed locally are read from `remote`.
+pub struct RecordingStore {
+    root: PathBuf,
+    remote: Option<Arc<dyn StorageBackend>>,
+}
+
+impl RecordingStore {
+    pub fn new(root: PathBuf) -> Self {
+        RecordingStore { root, remote: None }
+    }
+
+    pub fn with_remote(mut self, remote: Option<Arc<dyn StorageBackend>>) -> Self {
+        self.remote = remote;
+        self
+    }
+
+    pub fn from_env() -> Self {
+        Self::new(video_save_path())
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn remote(&self) -> Option<&Arc<dyn StorageBackend>> {
+        self.remote.as_ref()
+    }
+
+    /// Path of an existing recording.
+    pub fn resolve(&self, file_name: &str) -> Result<PathBuf, StoreError> {
+        if !is_recording_name(file_name) {
+            return Err(StoreError::InvalidName);
+        }
+        let path = self.root.join(file_name);
+        // symlink_metadata doesn't follow links, so a link is seen as a link rather than its target
+        let metadata = match fs::symlink_metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Err(StoreError::NotFound),
+            Err(err) => return Err(StoreError::Io(err)),
+        };
+        if !metadata.file_type().is_file() {
+            return Err(StoreError::NotARecording);
+        }
+
+        // the root itself may be a symlink, so compare canonical paths
+        let root = self.root.canonicalize().map_err(StoreError::Io)?;
+        let canonical = path.canonicalize().map_err(StoreError::Io)?;
+        if canonical.parent() != Some(root.as_path()) {
+            return Err(StoreError::NotARecording);
+        }
+        Ok(path)
+    }
+
+    /// Paths of several existing recordings, failing on the first that can't be resolved.
+    pub fn resolve_all(&self, file_names: &[String]) -> Result<Vec<PathBuf>, (String, StoreError)> {
+        file_names
+            .iter()
+            .map(|file_name| {
+                self.resolve(file_name)
+                    .map_err(|err| (file_name.clone(), err))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+    use tempfile::TempDir;
+
+    fn store_with_recording() -> (TempDir, RecordingStore) {
+        let root = TempDir::new().unwrap();
+        fs::write(root.path().join("motion_1.mp4"), b"recording").unwrap();
+        let store = RecordingStore::new(root.path().to_path_buf());
+        (root, store)
+    }
+
+    #[test]
+    fn resolves_recording_in_root() {
+        let (root, store) = store_with_recording();
+        assert_eq!(
+            store.resolve("motion_1.mp4").unwrap(),
+            root.path().join("motion_1.mp4")
+        );
+        assert!(matches!(
+            store.resolve("motion_2.mp4"),
+            Err(StoreError::NotFound)
+        ));
+    }
+
+    #[test]
+    fn rejects_traversal_and_absolute_names() {
+        let (_root, store) = store_with_recording();
+        for name in [
+            "../x.mp4",
+            "../../etc/x.mp4",
+            "sub/x.mp4",
+            "/etc/x.mp4",
+            "/tmp/motion_1.mp4",
+            "..\\x.mp4",
+            ".mp4",
+            "..mp4",
+            "",
+        ] {
+            assert!(
+                matches!(store.resolve(name), Err(StoreError::InvalidName)),
+                "{name} was accepted"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_names_that_decode_to_traversal() {
+        let (_root, store) = store_with_recording();
+        // names are never decoded again after the query string, so encoded forms stay invalid
+        for name in [
+            "%2e%2e%2fx.mp4",
+            "..%2fx.mp4",
+            "%2E%2E/x.mp4",
+            "..%5cx.mp4",
+            "%252e%252e%252fx.mp4",
+            "motion_1.mp4%00.txt",
+        ] {
+            assert!(
+                matches!(store.resolve(name), Err(StoreError::InvalidName)),
+                "{name} was accepted"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_symlink_pointing_outside_root() {
+        let (root, store) = store_with_recording();
+        let outside = TempDir::new().unwrap();
+        fs::write(outside.path().join("secret.mp4"), b"secret").unwrap();
+        symlink(
+            outside.path().join("secret.mp4"),
+            root.path().join("link.mp4"),
+        )
+        .unwrap();
+        assert!(matches!(
+            store.resolve("link.mp4"),
+            Err(StoreError::NotARecording)
+        ));
+    }
+
+    #[test]
+    fn rejects_symlink_to_recording_in_root() {
+        let (root, store) = store_with_recording();
+        symlink(
+            root.path().join("motion_1.mp4"),
+            root.path().join("alias.mp4"),
+        )
+        .unwrap();
+        assert!(matches!(
+            store.resolve("alias.mp4"),
+            Err(StoreError::NotARecording)
+        ));
+    }
+
+    #[test]
+    fn rejects_directory_named_like_recording() {
+        let (root, store) = store_with_recording();
+        fs::create_dir(root.path().join("folder.mp4")).unwrap();
+        assert!(matches!(
+            store.resolve("folder.mp4"),
+            Err(StoreError::NotARecording)
+        ));
+    }
+
+    #[test]
+    fn follows_symlinked_root() {
+        let (root, _) = store_with_recording();
+        let links = TempDir::new().unwrap();
+        let linked_root = links.path().join("recordings");
+        symlink(root.path(), &linked_root).unwrap();
+        let store = RecordingStore::new(linked_root.clone());
+        assert_eq!(
+            store.resolve("motion_1.mp4").unwrap(),
+            linked_root.join("motion_1.mp4")
+        );
+    }
+
+    #[test]
+    fn resolve_all_names_the_first_failure() {
+        let (_root, store) = store_with_recording();
+        let names = vec!["motion_1.mp4".to_string(), "../x.mp4".to_string()];
+        let (name, err) = store.resolve_all(&names).unwrap_err();
+        assert_eq!(name, "../x.mp4");
+        assert!(matches!(err, StoreError::InvalidName));
+    }
+}
@@ -0,0 +1,368 @@
+use super::{audit, catalog::Catalog, is_recording_name, metadata::sidecar_path, video_save_path};
+use anyhow::Result;
+use chrono::Utc;
+use std::{
+    env::var,
+    fs::{self, OpenOptions},
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{task::spawn_blocking, time::interval};
+use tracing::error;
+
+const DEFAULT_TRASH_RETENTION_SECS: i64 = 7 * 24 * 60 * 60;
+
+#[derive(Debug, PartialEq)]
+pub enum TrashOutcome {
+    Trashed,
+    Restored,
+    Protected,
+    /// The destination already has a recording of the same name, for example a clip saved under
+    /// the name while a trashed one was away, so nothing was moved.
+    NameTaken,
+    NotFound,
+    InvalidName,
+}
+
+/// Directory deleted recordings wait in before being purged, set with `TRASH_PATH`.
+pub fn trash_path() -> PathBuf {
+    match var("TRASH_PATH") {
+        Ok(path) => PathBuf::from(path),
+        Err(_) => video_save_path().join(".trash"),
+    }
+}
+
+/// How long recordings stay in the trash, set in seconds with `TRASH_RETENTION_SECS`.
+fn trash_retention_secs() -> i64 {
+    var("TRASH_RETENTION_SECS")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .unwrap_or(DEFAULT_TRASH_RETENTION_SECS)
+}
+
+/// Moves a recording and its sidecar between two directories, returning false without moving
+/// anything if `to` already has a recording of the same name.
+fn move_recording(file_name: &str, from: &Path, to: &Path) -> Result<bool> {
+    fs::create_dir_all(to)?;
+    let source = from.join(file_name);
+    let destination = to.join(file_name);
+    // a rename silently replaces what is there, so the name is claimed first as clips are saved
+    match OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&destination)
+    {
+        Ok(_) => {}
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => return Ok(false),
+        Err(err) => return Err(err.into()),
+    }
+    if let Err(err) = fs::rename(&source, &destination) {
+        let _ = fs::remove_file(&destination);
+        return Err(err.into());
+    }
+    let sidecar = sidecar_path(&source);
+    if let Some(sidecar_name) = sidecar.file_name() {
+        if sidecar.exists() {
+            fs::rename(&sidecar, to.join(sidecar_name))?;
+        }
+    }
+    Ok(true)
+}
+
+/// Moves a recording to the trash, refusing protected recordings.
+pub fn trash_recording(catalog: &Catalog, file_name: &str, actor: &str) -> Result<TrashOutcome> {
+    trash_recording_in(catalog, &video_save_path(), &trash_path(), file_name, actor)
+}
+
+fn trash_recording_in(
+    catalog: &Catalog,
+    recordings_dir: &Path,
+    trash_dir: &Path,
+    file_name: &str,
+    actor: &str,
+) -> Result<TrashOutcome> {
+    if !is_recording_name(file_name) {
+        return Ok(TrashOutcome::InvalidName);
+    }
+    let path = recordings_dir.join(file_name);
+    if !path.is_file() {
+        return Ok(TrashOutcome::NotFound);
+    }
+    // the purge works from the catalog, so make sure the recording is known before trashing it
+    if catalog.get(file_name)?.is_none() {
+        catalog.index_recording(&path, None)?;
+    }
+    if catalog.is_protected(file_name)? {
+        return Ok(TrashOutcome::Protected);
+    }
+
+    if !move_recording(file_name, recordings_dir, trash_dir)? {
+        return Ok(TrashOutcome::NameTaken);
+    }
+    catalog.set_trashed(file_name, Some(Utc::now().timestamp()))?;
+    audit::record(
+        actor,
+        "trash",
+        &path,
+        &format!("purged after {} seconds", trash_retention_secs()),
+    );
+    Ok(TrashOutcome::Trashed)
+}
+
+/// Moves a recording out of the trash back into the recordings directory, unless a recording of
+/// the same name has been saved there since.
+pub fn restore_recording(catalog: &Catalog, file_name: &str, actor: &str) -> Result<TrashOutcome> {
+    restore_recording_in(catalog, &video_save_path(), &trash_path(), file_name, actor)
+}
+
+fn restore_recording_in(
+    catalog: &Catalog,
+    recordings_dir: &Path,
+    trash_dir: &Path,
+    file_name: &str,
+    actor: &str,
+) -> Result<TrashOutcome> {
+    if !is_recording_name(file_name) {
+        return Ok(TrashOutcome::InvalidName);
+    }
+    if !trash_dir.join(file_name).is_file() {
+        return Ok(TrashOutcome::NotFound);
+    }
+
+    if !move_recording(file_name, trash_dir, recordings_dir)? {
+        return Ok(TrashOutcome::NameTaken);
+    }
+    catalog.set_trashed(file_name, None)?;
+    audit::record(
+        actor,
+        "restore",
+        &recordings_dir.join(file_name),
+        "restored from trash",
+    );
+    Ok(TrashOutcome::Restored)
+}
+
+/// Permanently deletes recordings that have been in the trash longer than the retention period.
+pub fn purge_trash(catalog: &Catalog) -> Result<usize> {
+    let cutoff = Utc::now().timestamp() - trash_retention_secs();
+    purge_trash_in(catalog, &trash_path(), cutoff)
+}
+
+/// Deletes recordings trashed at or before `cutoff`, a unix timestamp in seconds.
+fn purge_trash_in(catalog: &Catalog, trash_dir: &Path, cutoff: i64) -> Result<usize> {
+    let mut purged = 0;
+    for entry in catalog.trashed()? {
+        if entry
+            .trashed_at
+            .is_some_and(|trashed_at| trashed_at > cutoff)
+        {
+            continue;
+        }
+        let path = trash_dir.join(&entry.file_name);
+        if path.exists() {
+            fs::remove_file(&path)?;
+        }
+        let sidecar = sidecar_path(&path);
+        if sidecar.exists() {
+            fs::remove_file(&sidecar)?;
+        }
+        catalog.remove(&entry.file_name)?;
+        audit::record("trash", "purge", &path, "trash retention period passed");
+        purged += 1;
+    }
+    Ok(purged)
+}
+
+/// Periodically purges expired recordings from the trash.
+pub async fn trash_task(catalog: Arc<Catalog>) {
+    let mut ticker = interval(Duration::from_secs(60 * 60));
+    loop {
+        ticker.tick().await;
+        let catalog = catalog.clone();
+        match spawn_blocking(move || purge_trash(&catalog)).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(err)) => error!("Error purging trash, error: {err}"),
+            Err(err) => error!("Trash purge task panicked, error: {err}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::catalog::{Annotation, CatalogEntry};
+    use tempfile::TempDir;
+
+    const FILE_NAME: &str = "motion_2025-06-01_14-03-00_UTC0100.mp4";
+
+    fn catalog_entry(file_name: &str) -> CatalogEntry {
+        CatalogEntry {
+            file_name: file_name.to_string(),
+            recorded: 1_748_783_000,
+            duration: 10.0,
+            width: 1920,
+            height: 1080,
+            size_bytes: 15,
+            codec: "h264".to_string(),
+            trigger: None,
+            metadata: None,
+            tags: Vec::new(),
+            starred: false,
+            notes: None,
+            protected: false,
+            trashed_at: None,
+            offloaded_to: None,
+            offloaded_at: None,
+        }
+    }
+
+    /// A recordings directory holding one catalogued recording with a sidecar, and its trash.
+    fn setup() -> (TempDir, PathBuf, PathBuf, Catalog) {
+        let dir = TempDir::new().unwrap();
+        let recordings = dir.path().join("recordings");
+        fs::create_dir(&recordings).unwrap();
+        fs::write(recordings.join(FILE_NAME), b"recording bytes").unwrap();
+        fs::write(sidecar_path(&recordings.join(FILE_NAME)), b"{}").unwrap();
+        let catalog = Catalog::open(&dir.path().join("catalog.db")).unwrap();
+        catalog.upsert(&catalog_entry(FILE_NAME)).unwrap();
+        let trash = recordings.join(".trash");
+        (dir, recordings, trash, catalog)
+    }
+
+    #[test]
+    fn trashed_recording_can_be_restored() {
+        let (_dir, recordings, trash, catalog) = setup();
+        assert_eq!(
+            trash_recording_in(&catalog, &recordings, &trash, FILE_NAME, "test").unwrap(),
+            TrashOutcome::Trashed
+        );
+        assert!(!recordings.join(FILE_NAME).exists());
+        assert!(trash.join(FILE_NAME).is_file());
+        assert!(sidecar_path(&trash.join(FILE_NAME)).is_file());
+        assert!(catalog
+            .get(FILE_NAME)
+            .unwrap()
+            .unwrap()
+            .trashed_at
+            .is_some());
+        assert_eq!(catalog.trashed().unwrap().len(), 1);
+
+        assert_eq!(
+            restore_recording_in(&catalog, &recordings, &trash, FILE_NAME, "test").unwrap(),
+            TrashOutcome::Restored
+        );
+        assert_eq!(
+            fs::read(recordings.join(FILE_NAME)).unwrap(),
+            b"recording bytes"
+        );
+        assert!(sidecar_path(&recordings.join(FILE_NAME)).is_file());
+        assert!(!trash.join(FILE_NAME).exists());
+        assert!(catalog
+            .get(FILE_NAME)
+            .unwrap()
+            .unwrap()
+            .trashed_at
+            .is_none());
+    }
+
+    #[test]
+    fn protected_recording_is_not_trashed() {
+        let (_dir, recordings, trash, catalog) = setup();
+        let protect = Annotation {
+            tags: None,
+            starred: None,
+            notes: None,
+            protected: Some(true),
+        };
+        catalog.annotate(FILE_NAME, &protect).unwrap();
+        assert_eq!(
+            trash_recording_in(&catalog, &recordings, &trash, FILE_NAME, "test").unwrap(),
+            TrashOutcome::Protected
+        );
+        assert!(recordings.join(FILE_NAME).is_file());
+        assert!(catalog
+            .get(FILE_NAME)
+            .unwrap()
+            .unwrap()
+            .trashed_at
+            .is_none());
+    }
+
+    #[test]
+    fn restore_does_not_replace_recording_saved_under_same_name() {
+        let (_dir, recordings, trash, catalog) = setup();
+        trash_recording_in(&catalog, &recordings, &trash, FILE_NAME, "test").unwrap();
+        // such as a clip saved again while the earlier one was in the trash
+        fs::write(recordings.join(FILE_NAME), b"newer recording").unwrap();
+
+        assert_eq!(
+            restore_recording_in(&catalog, &recordings, &trash, FILE_NAME, "test").unwrap(),
+            TrashOutcome::NameTaken
+        );
+        assert_eq!(
+            fs::read(recordings.join(FILE_NAME)).unwrap(),
+            b"newer recording"
+        );
+        assert_eq!(fs::read(trash.join(FILE_NAME)).unwrap(), b"recording bytes");
+        assert!(sidecar_path(&trash.join(FILE_NAME)).is_file());
+
+        // nor does trashing the newer one replace the one already in the trash
+        assert_eq!(
+            trash_recording_in(&catalog, &recordings, &trash, FILE_NAME, "test").unwrap(),
+            TrashOutcome::NameTaken
+        );
+        assert_eq!(fs::read(trash.join(FILE_NAME)).unwrap(), b"recording bytes");
+    }
+
+    #[test]
+    fn purge_removes_only_recordings_past_retention() {
+        let (_dir, recordings, trash, catalog) = setup();
+        let newer = "motion_2025-06-02_09-00-00_UTC0100.mp4";
+        fs::write(recordings.join(newer), b"newer").unwrap();
+        catalog.upsert(&catalog_entry(newer)).unwrap();
+        trash_recording_in(&catalog, &recordings, &trash, FILE_NAME, "test").unwrap();
+        trash_recording_in(&catalog, &recordings, &trash, newer, "test").unwrap();
+        catalog.set_trashed(FILE_NAME, Some(1_000)).unwrap();
+        catalog.set_trashed(newer, Some(2_000)).unwrap();
+
+        assert_eq!(purge_trash_in(&catalog, &trash, 1_500).unwrap(), 1);
+        assert!(!trash.join(FILE_NAME).exists());
+        assert!(!sidecar_path(&trash.join(FILE_NAME)).exists());
+        assert!(catalog.get(FILE_NAME).unwrap().is_none());
+        assert!(trash.join(newer).is_file());
+        assert!(catalog.get(newer).unwrap().is_some());
+
+        assert_eq!(purge_trash_in(&catalog, &trash, 2_000).unwrap(), 1);
+        assert!(catalog.trashed().unwrap().is_empty());
+    }
+
+    #[test]
+    fn names_outside_the_recordings_directory_are_refused() {
+        let (_dir, recordings, trash, catalog) = setup();
+        for file_name in [
+            "../outside.mp4",
+            "nested/motion.mp4",
+            ".hidden.mp4",
+            "notes.txt",
+        ] {
+            assert_eq!(
+                trash_recording_in(&catalog, &recordings, &trash, file_name, "test").unwrap(),
+                TrashOutcome::InvalidName
+            );
+            assert_eq!(
+                restore_recording_in(&catalog, &recordings, &trash, file_name, "test").unwrap(),
+                TrashOutcome::InvalidName
+            );
+        }
+        assert_eq!(
+            trash_recording_in(&catalog, &recordings, &trash, "missing.mp4", "test").unwrap(),
+            TrashOutcome::NotFound
+        );
+        assert_eq!(
+            restore_recording_in(&catalog, &recordings, &trash, FILE_NAME, "test").unwrap(),
+            TrashOutcome::NotFound
+        );
+    }
+}
@@ -0,0 +1,184 @@
+use chrono::{
+    DateTime, FixedOffset, LocalResult, NaiveDate, NaiveDateTime, NaiveTime, TimeDelta, TimeZone,
+    Utc,
+};
+use chrono_tz::Tz;
+use serde::Serializer;
+use std::{env::var, sync::OnceLock};
+use tracing::error;
+
+/// Zone dates and times are shown, bucketed and scheduled in, the IANA name set with `TIMEZONE`
+/// such as `Europe/London`. Defaults to UTC.
+pub fn local_timezone() -> Tz {
+    static TIMEZONE: OnceLock<Tz> = OnceLock::new();
+    *TIMEZONE.get_or_init(|| match var("TIMEZONE") {
+        Ok(name) => name.parse().unwrap_or_else(|err| {
+            error!("Unknown TIMEZONE {name}, using UTC, error: {err}");
+            Tz::UTC
+        }),
+        Err(_) => Tz::UTC,
+    })
+}
+
+pub fn local_now() -> DateTime<Tz> {
+    Utc::now().with_timezone(&local_timezone())
+}
+
+/// A unix timestamp, in seconds, in the local zone with its offset at that moment.
+pub fn local_time(timestamp: i64) -> DateTime<FixedOffset> {
+    DateTime::from_timestamp(timestamp, 0)
+        .unwrap_or_default()
+        .with_timezone(&local_timezone())
+        .fixed_offset()
+}
+
+/// RFC 3339 form of a unix timestamp in the local zone, such as `2025-06-01T14:03:00+01:00`.
+pub fn local_rfc3339(timestamp: i64) -> String {
+    local_time(timestamp).to_rfc3339()
+}
+
+/// Serializes a UTC time as RFC 3339 in the local zone, for `#[serde(serialize_with)]`.
+pub fn serialize_local<S: Serializer>(
+    time: &DateTime<Utc>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&time.with_timezone(&local_timezone()).to_rfc3339())
+}
+
+/// Like [`serialize_local`] for optional times.
+pub fn serialize_local_option<S: Serializer>(
+    time: &Option<DateTime<Utc>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match time {
+        Some(time) => serialize_local(time, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// The moment a local wall clock time happens.
+///
+/// When clocks go back and the time happens twice the first is used, when clocks go forward and
+/// it is skipped the first time after the gap is used.
+pub fn resolve_local(local: NaiveDateTime) -> DateTime<Tz> {
+    resolve_local_in(local_timezone(), local)
+}
+
+fn resolve_local_in(timezone: Tz, local: NaiveDateTime) -> DateTime<Tz> {
+    let mut candidate = local;
+    // gaps are at most a couple of hours, the limit only guards against a broken zone database
+    for _ in 0..24 * 4 {
+        match timezone.from_local_datetime(&candidate) {
+            LocalResult::Single(time) | LocalResult::Ambiguous(time, _) => return time,
+            LocalResult::None => candidate += TimeDelta::minutes(15),
+        }
+    }
+    timezone.from_utc_datetime(&local)
+}
+
+/// Start and end of a local calendar day as unix timestamps in seconds.
+///
+/// Days around DST changes are 23 or 25 hours long.
+pub fn day_bounds(date: NaiveDate) -> (i64, i64) {
+    day_bounds_in(local_timezone(), date)
+}
+
+fn day_bounds_in(timezone: Tz, date: NaiveDate) -> (i64, i64) {
+    let start_of =
+        |date: NaiveDate| resolve_local_in(timezone, date.and_time(NaiveTime::MIN)).timestamp();
+    let next_day = date.succ_opt().unwrap_or(date);
+    (start_of(date), start_of(next_day))
+}
+
+/// Next time after now the local clock reads `hour`:00, keeping to the wall clock across DST changes.
+pub fn next_local_hour(hour: u32) -> DateTime<Tz> {
+    next_local_hour_after(local_now(), hour)
+}
+
+/// Next time after `now` the clock of its zone reads `hour`:00.
+fn next_local_hour_after(now: DateTime<Tz>, hour: u32) -> DateTime<Tz> {
+    let time = NaiveTime::from_hms_opt(hour.min(23), 0, 0).unwrap();
+    let mut date = now.date_naive();
+    loop {
+        let next = resolve_local_in(now.timezone(), date.and_time(time));
+        if next > now {
+            return next;
+        }
+        date = date.succ_opt().unwrap_or(date);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono_tz::Europe::London;
+
+    const HOUR: i64 = 60 * 60;
+
+    fn london(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> DateTime<Tz> {
+        London
+            .with_ymd_and_hms(year, month, day, hour, minute, 0)
+            .earliest()
+            .unwrap()
+    }
+
+    fn utc(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(year, month, day, hour, minute, 0)
+            .unwrap()
+    }
+
+    /// Local time on the day clocks go forward.
+    fn spring_time(hour: u32, minute: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2025, 3, 30)
+            .unwrap()
+            .and_hms_opt(hour, minute, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn days_around_dst_changes_are_23_and_25_hours() {
+        let spring = NaiveDate::from_ymd_opt(2025, 3, 30).unwrap();
+        let (start, end) = day_bounds_in(London, spring);
+        assert_eq!(start, utc(2025, 3, 30, 0, 0).timestamp());
+        assert_eq!(end - start, 23 * HOUR);
+
+        let autumn = NaiveDate::from_ymd_opt(2025, 10, 26).unwrap();
+        let (start, end) = day_bounds_in(London, autumn);
+        // midnight is still in summer time, an hour ahead of UTC
+        assert_eq!(start, utc(2025, 10, 25, 23, 0).timestamp());
+        assert_eq!(end - start, 25 * HOUR);
+
+        let ordinary = NaiveDate::from_ymd_opt(2025, 6, 1).unwrap();
+        let (start, end) = day_bounds_in(London, ordinary);
+        assert_eq!(end - start, 24 * HOUR);
+    }
+
+    #[test]
+    fn hour_skipped_by_spring_forward_runs_after_the_gap() {
+        let now = london(2025, 3, 29, 12, 0);
+        let next = next_local_hour_after(now, 1);
+        // 01:00 doesn't exist on the 30th, clocks jump from 01:00 GMT to 02:00 BST
+        assert_eq!(next.with_timezone(&Utc), utc(2025, 3, 30, 1, 0));
+        assert_eq!(next.naive_local(), spring_time(2, 0));
+
+        // the day after, the hour is back to normal
+        let following = next_local_hour_after(next, 1);
+        assert_eq!(following.with_timezone(&Utc), utc(2025, 3, 31, 0, 0));
+    }
+
+    #[test]
+    fn hour_repeated_by_fall_back_runs_once() {
+        let now = london(2025, 10, 25, 12, 0);
+        let next = next_local_hour_after(now, 1);
+        // the first 01:00, still in summer time
+        assert_eq!(next.with_timezone(&Utc), utc(2025, 10, 26, 0, 0));
+        let following = next_local_hour_after(next, 1);
+        assert_eq!(following.with_timezone(&Utc), utc(2025, 10, 27, 1, 0));
+    }
+
+    #[test]
+    fn resolves_times_in_the_gap_to_its_end() {
+        let resolved = resolve_local_in(London, spring_time(1, 30));
+        assert_eq!(resolved.with_timezone(&Utc), utc(2025, 3, 30, 1, 0));
+    }
+}